@@ -0,0 +1,99 @@
+//! `host:port` addresses of the network sinks, hostnames, IPv4 and bracketed IPv6 literals (`[::1]:2003`)
+
+use clap::ValueEnum;
+use std::fmt;
+use std::io;
+use std::net::{Ipv6Addr, SocketAddr, ToSocketAddrs};
+
+/// Address family to try first when a hostname resolves to both
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// In the order the resolver returns them
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+/// A validated, not yet resolved `host:port`
+#[derive(Clone, Debug)]
+pub struct Address {
+    /// Without brackets for IPv6 literals
+    host: String,
+    port: u16,
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+impl Address {
+    /// Resolve to every address of the host, the preferred family first
+    pub fn resolve(&self, family: AddressFamily) -> io::Result<Vec<SocketAddr>> {
+        let mut addresses = (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to resolve '{}': {}", self.host, e),
+                )
+            })?
+            .collect::<Vec<SocketAddr>>();
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' did not resolve to any address", self.host),
+            ));
+        }
+
+        // Stable, so the resolver order is kept within a family
+        match family {
+            AddressFamily::Any => {}
+            AddressFamily::Ipv4 => addresses.sort_by_key(|a| !a.is_ipv4()),
+            AddressFamily::Ipv6 => addresses.sort_by_key(|a| !a.is_ipv6()),
+        }
+
+        Ok(addresses)
+    }
+}
+
+/// Parse `host:port`, IPv6 literals have to be bracketed like `[::1]:2003`
+pub fn parse(value: &str) -> Result<Address, String> {
+    let (host, port) = match value.strip_prefix('[') {
+        Some(rest) => {
+            let (host, port) = rest
+                .split_once("]:")
+                .ok_or_else(|| format!("expected `[ipv6]:port`, got '{}'", value))?;
+            host.parse::<Ipv6Addr>()
+                .map_err(|_| format!("'{}' is not an IPv6 address", host))?;
+            (host, port)
+        }
+        None => {
+            let (host, port) = value
+                .rsplit_once(':')
+                .ok_or_else(|| format!("expected `host:port`, got '{}'", value))?;
+            if host.contains(':') {
+                return Err(format!(
+                    "IPv6 addresses have to be bracketed, e.g. `[{}]:{}`",
+                    host, port
+                ));
+            }
+            (host, port)
+        }
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in '{}'", value));
+    }
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port '{}'", port))?;
+
+    Ok(Address {
+        host: host.to_string(),
+        port,
+    })
+}
@@ -0,0 +1,301 @@
+//! Conditions over the metrics of a process like `cpu>50 and mem>500`, for `busy_when`
+
+/// Value of a process a condition can compare
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    /// CPU usage in percent
+    Cpu,
+    /// Resident memory in MB
+    Mem,
+    /// Percent of the total CPU usage
+    Share,
+    /// Mean CPU usage since the process was first measured in percent
+    Avg,
+    /// Seconds since the process was started
+    Runtime,
+}
+
+impl Metric {
+    const ALL: [(&'static str, Metric); 5] = [
+        ("cpu", Metric::Cpu),
+        ("mem", Metric::Mem),
+        ("share", Metric::Share),
+        ("avg", Metric::Avg),
+        ("runtime", Metric::Runtime),
+    ];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+#[derive(Clone, Debug)]
+enum Expression {
+    Compare(Metric, Comparison, f32),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+/// A parsed condition, `and` binds stronger than `or` and parentheses group
+#[derive(Clone, Debug)]
+pub struct Condition {
+    source: String,
+    expression: Expression,
+}
+
+impl Condition {
+    /// Whether the metrics given by `value` satisfy the condition
+    pub fn eval(&self, value: &dyn Fn(Metric) -> f32) -> bool {
+        eval(&self.expression, value)
+    }
+
+    /// The condition as it was given
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+fn eval(expression: &Expression, value: &dyn Fn(Metric) -> f32) -> bool {
+    match expression {
+        Expression::Compare(metric, comparison, limit) => {
+            let value = value(*metric);
+            match comparison {
+                Comparison::Greater => value > *limit,
+                Comparison::GreaterOrEqual => value >= *limit,
+                Comparison::Less => value < *limit,
+                Comparison::LessOrEqual => value <= *limit,
+            }
+        }
+        Expression::And(a, b) => eval(a, value) && eval(b, value),
+        Expression::Or(a, b) => eval(a, value) || eval(b, value),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Number(f32),
+    Comparison(Comparison),
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(match c {
+                    '(' => Token::Open,
+                    _ => Token::Close,
+                });
+            }
+            '<' | '>' => {
+                chars.next();
+                let or_equal = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Comparison(match (c, or_equal) {
+                    ('>', false) => Comparison::Greater,
+                    ('>', true) => Comparison::GreaterOrEqual,
+                    ('<', false) => Comparison::Less,
+                    _ => Comparison::LessOrEqual,
+                }));
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                }
+                let number = number
+                    .parse()
+                    .map_err(|_| format!("invalid number '{}'", number))?;
+                tokens.push(Token::Number(number));
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+                    word.push(c.to_ascii_lowercase());
+                }
+                tokens.push(Token::Word(word));
+            }
+            c => return Err(format!("unexpected '{}'", c)),
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent over `or := and ("or" and)*`, `and := term ("and" term)*`,
+/// `term := "(" or ")" | metric comparison number`
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_is_word(&mut self, word: &str) -> bool {
+        let is_word = matches!(self.peek(), Some(Token::Word(w)) if w == word);
+        if is_word {
+            self.position += 1;
+        }
+        is_word
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.and()?;
+        while self.next_is_word("or") {
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.term()?;
+        while self.next_is_word("and") {
+            expression = Expression::And(Box::new(expression), Box::new(self.term()?));
+        }
+        Ok(expression)
+    }
+
+    fn term(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Open) => {
+                let expression = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err(String::from("missing ')'")),
+                }
+            }
+            Some(Token::Word(name)) => {
+                let metric = Metric::ALL
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, metric)| *metric)
+                    .ok_or_else(|| {
+                        format!(
+                            "unknown metric '{}', expected one of: {}",
+                            name,
+                            Metric::ALL
+                                .iter()
+                                .map(|(n, _)| *n)
+                                .collect::<Vec<&str>>()
+                                .join(", ")
+                        )
+                    })?;
+                let Some(Token::Comparison(comparison)) = self.next() else {
+                    return Err(format!("expected '>', '>=', '<' or '<=' after '{}'", name));
+                };
+                let Some(Token::Number(limit)) = self.next() else {
+                    return Err(format!("expected a number to compare '{}' with", name));
+                };
+                Ok(Expression::Compare(metric, comparison, limit))
+            }
+            _ => Err(String::from("expected a metric or '('")),
+        }
+    }
+}
+
+/// Parse a condition like `cpu>50 and mem>500`
+pub fn parse(source: &str) -> Result<Condition, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let expression = parser.or()?;
+    if parser.peek().is_some() {
+        return Err(String::from("expected 'and' or 'or'"));
+    }
+
+    Ok(Condition {
+        source: source.trim().to_string(),
+        expression,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate a condition for CPU usage and memory
+    fn busy(source: &str, cpu: f32, mem: f32) -> bool {
+        parse(source).unwrap().eval(&|metric| match metric {
+            Metric::Cpu => cpu,
+            Metric::Mem => mem,
+            _ => 0.0,
+        })
+    }
+
+    #[test]
+    fn and_binds_stronger_than_or() {
+        // `cpu>50 or (cpu<10 and mem>500)`, not `(cpu>50 or cpu<10) and mem>500`
+        let condition = "cpu>50 or cpu<10 and mem>500";
+        assert!(busy(condition, 60.0, 0.0));
+        assert!(busy(condition, 5.0, 600.0));
+        assert!(!busy(condition, 5.0, 100.0));
+        assert!(!busy(condition, 20.0, 600.0));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        let condition = "(cpu>50 or cpu<10) and mem>500";
+        assert!(!busy(condition, 60.0, 0.0));
+        assert!(busy(condition, 60.0, 600.0));
+        assert!(busy(condition, 5.0, 600.0));
+        assert!(!busy(condition, 20.0, 600.0));
+    }
+
+    #[test]
+    fn or_equal_comparisons_include_the_limit() {
+        assert!(busy("cpu>=50", 50.0, 0.0));
+        assert!(!busy("cpu>50", 50.0, 0.0));
+        assert!(busy("cpu<=50", 50.0, 0.0));
+        assert!(!busy("cpu<50", 50.0, 0.0));
+    }
+
+    #[test]
+    fn unknown_metrics_are_rejected() {
+        let error = parse("disk>50").unwrap_err();
+        assert_eq!(
+            error,
+            "unknown metric 'disk', expected one of: cpu, mem, share, avg, runtime"
+        );
+    }
+
+    #[test]
+    fn incomplete_conditions_are_rejected() {
+        assert_eq!(
+            parse("cpu>").unwrap_err(),
+            "expected a number to compare 'cpu' with"
+        );
+        assert_eq!(parse("cpu>50 and").unwrap_err(), "expected a metric or '('");
+        assert_eq!(parse("(cpu>50").unwrap_err(), "missing ')'");
+    }
+
+    #[test]
+    fn trailing_tokens_are_rejected() {
+        assert_eq!(
+            parse("cpu>50 mem>500").unwrap_err(),
+            "expected 'and' or 'or'"
+        );
+        assert_eq!(parse("cpu>50)").unwrap_err(), "expected 'and' or 'or'");
+    }
+}
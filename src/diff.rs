@@ -0,0 +1,181 @@
+//! Offline comparison of two exported JSON snapshots, for `diff`
+
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fs;
+
+/// How the processes of two snapshots are matched
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffKey {
+    /// Processes with the same name are summed up and compared
+    Name,
+    /// The same process (PID and start time), a restarted process is removed and added
+    Pid,
+}
+
+/// Usage of a process (or of all processes with a name) in one snapshot
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    cpu: f64,
+    /// Bytes, [None] for snapshots written before memory was exported
+    memory: Option<u64>,
+}
+
+/// Processes of a snapshot by match key, with the label to show them by
+type Processes = HashMap<String, (String, Usage)>;
+
+/// Read a snapshot written by `export_on_exit` (the last one of several lines) or `baseline_file`
+fn read_snapshot(path: &str) -> Result<serde_json::Value, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("failed to read '{}': {}", path, e))?;
+    let last_line = content.lines().rev().find(|l| !l.trim().is_empty());
+
+    serde_json::from_str(&content)
+        .or_else(|e| match last_line {
+            Some(line) => serde_json::from_str(line),
+            None => Err(e),
+        })
+        .map_err(|e| format!("failed to parse '{}': {}", path, e))
+}
+
+fn processes(snapshot: &serde_json::Value, path: &str, key: DiffKey) -> Result<Processes, String> {
+    let mut processes = Processes::new();
+    for process in snapshot["processes"]
+        .as_array()
+        .ok_or_else(|| format!("'{}' has no 'processes' array", path))?
+    {
+        let name = process["name"].as_str().unwrap_or("-");
+        let usage = Usage {
+            cpu: process["got_cpu_usage"].as_f64().unwrap_or(0.0),
+            memory: process["memory"].as_u64(),
+        };
+        let (key, label) = match key {
+            DiffKey::Name => (name.to_string(), name.to_string()),
+            DiffKey::Pid => {
+                let pid = process["pid"].as_u64().unwrap_or(0);
+                // Older snapshots have no start time, PIDs alone may have been reused
+                let start_time = process["start_time"].as_u64().unwrap_or(0);
+                (
+                    format!("{}@{}", pid, start_time),
+                    format!("{} [{}]", name, pid),
+                )
+            }
+        };
+
+        // Memory is only summed up if it is known for every process
+        match processes.get_mut(&key) {
+            Some((_, merged)) => {
+                merged.cpu += usage.cpu;
+                merged.memory = merged.memory.zip(usage.memory).map(|(a, b)| a + b);
+            }
+            None => {
+                processes.insert(key, (label, usage));
+            }
+        }
+    }
+
+    Ok(processes)
+}
+
+fn format_cpu(usage: Option<Usage>) -> String {
+    usage.map_or(String::from("-"), |u| format!("{:.2} %", u.cpu))
+}
+
+/// Compare the snapshots in two files, processes sorted by the largest change first
+pub fn diff_files(before_path: &str, after_path: &str, key: DiffKey) -> Result<String, String> {
+    let before_snapshot = read_snapshot(before_path)?;
+    let after_snapshot = read_snapshot(after_path)?;
+    let before = processes(&before_snapshot, before_path, key)?;
+    let mut after = processes(&after_snapshot, after_path, key)?;
+
+    let mut rows = before
+        .into_iter()
+        .map(|(key, (label, usage))| {
+            let after_usage = after.remove(&key).map(|(_, usage)| usage);
+            (label, Some(usage), after_usage)
+        })
+        .collect::<Vec<(String, Option<Usage>, Option<Usage>)>>();
+    rows.extend(
+        after
+            .into_values()
+            .map(|(label, usage)| (label, None, Some(usage))),
+    );
+
+    // A process missing on one side counts as not using anything there
+    let cpu_delta = |before: &Option<Usage>, after: &Option<Usage>| {
+        after.map_or(0.0, |u| u.cpu) - before.map_or(0.0, |u| u.cpu)
+    };
+    let memory_delta = |before: &Option<Usage>, after: &Option<Usage>| -> Option<i64> {
+        let memory = |usage: &Option<Usage>| match usage {
+            Some(usage) => usage.memory.map(|m| m as i64),
+            None => Some(0),
+        };
+        Some(memory(after)? - memory(before)?)
+    };
+    rows.sort_by(
+        |(a_label, a_before, a_after), (b_label, b_before, b_after)| {
+            cpu_delta(b_before, b_after)
+                .abs()
+                .total_cmp(&cpu_delta(a_before, a_after).abs())
+                .then_with(|| {
+                    memory_delta(b_before, b_after)
+                        .map(i64::abs)
+                        .cmp(&memory_delta(a_before, a_after).map(i64::abs))
+                })
+                .then_with(|| a_label.cmp(b_label))
+        },
+    );
+
+    let added = rows
+        .iter()
+        .filter(|(_, before, _)| before.is_none())
+        .count();
+    let removed = rows.iter().filter(|(_, _, after)| after.is_none()).count();
+    let total = |snapshot: &serde_json::Value| snapshot["total_cpu_usage"].as_f64().unwrap_or(0.0);
+    let (total_before, total_after) = (total(&before_snapshot), total(&after_snapshot));
+
+    let stats = rows
+        .iter()
+        .map(|(label, before, after)| {
+            format!(
+                "| {: <27} | {: >9} | {: >9} | {: >9} | {: >10} |",
+                label.chars().take(27).collect::<String>(),
+                format_cpu(*before),
+                format_cpu(*after),
+                format!("{:+.2} %", cpu_delta(before, after)),
+                memory_delta(before, after).map_or(String::from("-"), |delta| format!(
+                    "{:+.1} MB",
+                    delta as f64 / 1_000_000.0
+                )),
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    Ok(format!(
+        "{header}\n{total}\n{changes}\n{divider}\n{column_names}\n{column_names_divider}\n{stats}\n{divider}",
+        header = format_args!("{:-^80}", "Snapshot diff"),
+        total = format_args!(
+            "|{: ^78}|",
+            format!(
+                "{:.2} % -> {:.2} % ({:+.2} %)",
+                total_before,
+                total_after,
+                total_after - total_before
+            )
+        ),
+        changes = format_args!(
+            "|{: ^78}|",
+            format!("Processes added: {}, removed: {}", added, removed)
+        ),
+        divider = format_args!("{:-^80}", ""),
+        column_names = format_args!(
+            "| {: <27} | {: >9} | {: >9} | {: >9} | {: >10} |",
+            "Process", "Before", "After", "CPU", "Memory"
+        ),
+        column_names_divider = format_args!(
+            "|{:-<29}|{:-<11}|{:-<11}|{:-<11}|{:-<12}|",
+            "", "", "", "", ""
+        ),
+    ))
+}
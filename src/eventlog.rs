@@ -0,0 +1,83 @@
+//! Windows Event Log sink reporting threshold events under the `cpu-usage-logger` source
+
+use std::ptr;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE,
+    EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+};
+
+const SOURCE_NAME: &str = "cpu-usage-logger";
+
+/// Severity of a reported event
+pub enum EventKind {
+    Information,
+    Warning,
+    Error,
+}
+
+/// Handle to the event source in the Application log
+pub struct EventLog {
+    handle: isize,
+}
+
+impl EventLog {
+    /// Register the event source, [None] if the Event Log refused it
+    pub fn open() -> Option<Self> {
+        let source = to_wide(SOURCE_NAME);
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        match handle {
+            0 => {
+                eprintln!(
+                    "Failed to register Event Log source: {}",
+                    std::io::Error::last_os_error()
+                );
+                None
+            }
+            handle => Some(EventLog { handle }),
+        }
+    }
+
+    pub fn report(&self, kind: EventKind, message: &str) {
+        let event_type = match kind {
+            EventKind::Information => EVENTLOG_INFORMATION_TYPE,
+            EventKind::Warning => EVENTLOG_WARNING_TYPE,
+            EventKind::Error => EVENTLOG_ERROR_TYPE,
+        };
+        let message = to_wide(message);
+        let strings = [message.as_ptr()];
+
+        let reported = unsafe {
+            ReportEventW(
+                self.handle,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            )
+        };
+        if reported == 0 {
+            crate::warn(
+                "eventlog",
+                &format!(
+                    "failed to write to the Event Log: {}",
+                    std::io::Error::last_os_error()
+                ),
+            );
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe { DeregisterEventSource(self.handle) };
+    }
+}
+
+/// Encode a string as NUL terminated UTF-16
+fn to_wide(value: &str) -> Vec<u16> {
+    value.encode_utf16().chain([0]).collect()
+}
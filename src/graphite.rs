@@ -0,0 +1,87 @@
+//! Graphite sink sending metrics in the plaintext protocol over a persistent TCP connection
+
+use std::io::Write;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use crate::address::{Address, AddressFamily};
+use crate::warn;
+
+/// How long connecting and writing may take before the connection is considered broken
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait before the first reconnect, doubled after every failed attempt
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Connection to a Graphite server that is re-established with backoff
+pub struct GraphiteSink {
+    address: Address,
+    family: AddressFamily,
+    stream: Option<TcpStream>,
+    backoff: Duration,
+    /// No reconnect is attempted before this time
+    retry_at: Option<Instant>,
+}
+
+impl GraphiteSink {
+    pub fn new(address: &Address, family: AddressFamily) -> Self {
+        GraphiteSink {
+            address: address.clone(),
+            family,
+            stream: None,
+            backoff: MIN_BACKOFF,
+            retry_at: None,
+        }
+    }
+
+    /// Send `path value timestamp` lines, metrics are dropped while disconnected
+    pub fn send(&mut self, lines: &str, now: Instant) {
+        if self.stream.is_none() && self.retry_at.is_none_or(|t| now >= t) {
+            match self.connect() {
+                Ok(stream) => {
+                    self.stream = Some(stream);
+                    self.backoff = MIN_BACKOFF;
+                    self.retry_at = None;
+                }
+                Err(e) => {
+                    warn(
+                        "graphite-connect",
+                        &format!("failed to connect to Graphite at {}: {}", self.address, e),
+                    );
+                    self.retry_at = Some(now + self.backoff);
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    return;
+                }
+            }
+        }
+
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+        if let Err(e) = stream.write_all(lines.as_bytes()) {
+            warn(
+                "graphite-send",
+                &format!("failed to send metrics to Graphite: {}", e),
+            );
+            // Reconnect on the next send
+            self.stream = None;
+        }
+    }
+
+    /// Try every resolved address in order, the error of the last one is returned if none connects
+    fn connect(&self) -> std::io::Result<TcpStream> {
+        let mut error = None;
+        for address in self.address.resolve(self.family)? {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(TIMEOUT))?;
+                    return Ok(stream);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        // `resolve` never returns an empty list
+        Err(error.unwrap())
+    }
+}
@@ -0,0 +1,377 @@
+//! gzip (RFC 1952) compression of finished log files, DEFLATE with fixed Huffman codes and LZ77 matching,
+//! small enough to not pull in a compression crate
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+
+/// Size of the DEFLATE window
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Number of earlier positions with the same hash tried per match, trading ratio for speed
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+/// Uncompressed bytes per gzip member of a file, so only this much of it is held in memory at once
+const CHUNK: usize = 1024 * 1024;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Bits packed least significant first, as DEFLATE expects
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    count: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, bits: u32) {
+        self.buffer |= value << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed most significant bit first
+    fn write_code(&mut self, code: u32, bits: u32) {
+        let reversed = code.reverse_bits() >> (32 - bits);
+        self.write(reversed, bits);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+/// Write a literal/length symbol with its fixed Huffman code
+fn write_symbol(writer: &mut BitWriter, symbol: u16) {
+    let symbol = symbol as u32;
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= length)
+        .unwrap();
+    write_symbol(writer, 257 + code as u16);
+    writer.write(
+        (length - LENGTH_BASE[code] as usize) as u32,
+        LENGTH_EXTRA[code] as u32,
+    );
+
+    let code = DISTANCE_BASE
+        .iter()
+        .rposition(|&base| base as usize <= distance)
+        .unwrap();
+    writer.write_code(code as u32, 5);
+    writer.write(
+        (distance - DISTANCE_BASE[code] as usize) as u32,
+        DISTANCE_EXTRA[code] as u32,
+    );
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Compress into a single DEFLATE block with the fixed Huffman codes
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter {
+        bytes: Vec::with_capacity(data.len() / 2),
+        buffer: 0,
+        count: 0,
+    };
+    // BFINAL and BTYPE 01 (fixed codes)
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    // Most recent position + 1 per hash and the previous position with the same hash per window slot
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut previous = vec![0usize; WINDOW];
+    let insert = |position: usize, head: &mut [usize], previous: &mut [usize]| {
+        if position + MIN_MATCH <= data.len() {
+            let h = hash(&data[position..]);
+            previous[position % WINDOW] = head[h];
+            head[h] = position + 1;
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let mut best = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - position);
+            let mut candidate = head[hash(&data[position..])];
+            let mut chain = 0;
+            while candidate > 0 && chain < MAX_CHAIN {
+                let start = candidate - 1;
+                if position - start > WINDOW - 1 {
+                    break;
+                }
+                let length = data[start..]
+                    .iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, position - start);
+                    if length == max_length {
+                        break;
+                    }
+                }
+                let next = previous[start % WINDOW];
+                // Older entries of the slot were overwritten by a newer position
+                if next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        match best {
+            (length, distance) if length >= MIN_MATCH => {
+                write_match(&mut writer, length, distance);
+                for p in position..position + length {
+                    insert(p, &mut head, &mut previous);
+                }
+                position += length;
+            }
+            _ => {
+                write_symbol(&mut writer, data[position] as u16);
+                insert(position, &mut head, &mut previous);
+                position += 1;
+            }
+        }
+    }
+    write_symbol(&mut writer, 256);
+
+    writer.finish()
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(i as u32, |c, _| match c & 1 {
+            1 => 0xedb88320 ^ (c >> 1),
+            _ => c >> 1,
+        });
+    }
+
+    !data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// gzip member of `data` without a file name or modification time
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    gzip.extend(deflate(data));
+    gzip.extend(crc32(data).to_le_bytes());
+    gzip.extend((data.len() as u32).to_le_bytes());
+    gzip
+}
+
+/// Replace a file by a gzip compressed `<path>.gz`, the original is only removed once that was written.
+/// The file is read in chunks that each become their own gzip member, which `gzip -d` joins back together
+pub fn compress_file(path: &str) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut writer = BufWriter::new(File::create(format!("{}.gz", path))?);
+    let mut chunk = Vec::with_capacity(CHUNK);
+    loop {
+        chunk.clear();
+        (&mut file).take(CHUNK as u64).read_to_end(&mut chunk)?;
+        writer.write_all(&compress(&chunk))?;
+        if chunk.len() < CHUNK {
+            break;
+        }
+    }
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())?
+        .sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads bits least significant first like [BitWriter] writes them
+    struct BitReader<'a> {
+        data: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bit(&mut self) -> u32 {
+            let bit = (self.data[self.position / 8] >> (self.position % 8)) & 1;
+            self.position += 1;
+            bit as u32
+        }
+
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |value, i| value | self.bit() << i)
+        }
+
+        /// Huffman codes are packed most significant bit first
+        fn code(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |code, _| code << 1 | self.bit())
+        }
+
+        /// Literal/length symbol of the fixed Huffman codes
+        fn symbol(&mut self) -> usize {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code as usize;
+            }
+            let code = code << 1 | self.bit();
+            match code {
+                0x30..=0xbf => return (code - 0x30) as usize,
+                0xc0..=0xc7 => return (280 + code - 0xc0) as usize,
+                _ => {}
+            }
+            let code = code << 1 | self.bit();
+            (144 + code - 0x190) as usize
+        }
+    }
+
+    /// Inflate the fixed Huffman blocks [deflate] writes, returning the data and the bytes consumed
+    fn inflate(data: &[u8]) -> (Vec<u8>, usize) {
+        let mut reader = BitReader { data, position: 0 };
+        assert_eq!(reader.bits(1), 1, "single final block");
+        assert_eq!(reader.bits(2), 1, "fixed Huffman codes");
+
+        let mut output = Vec::new();
+        loop {
+            match reader.symbol() {
+                literal @ 0..=255 => output.push(literal as u8),
+                256 => break,
+                symbol => {
+                    let code = symbol - 257;
+                    let length = LENGTH_BASE[code] as usize
+                        + reader.bits(LENGTH_EXTRA[code] as u32) as usize;
+                    let code = reader.code(5) as usize;
+                    let distance = DISTANCE_BASE[code] as usize
+                        + reader.bits(DISTANCE_EXTRA[code] as u32) as usize;
+                    let start = output.len() - distance;
+                    for i in 0..length {
+                        output.push(output[start + i]);
+                    }
+                }
+            }
+        }
+
+        (output, reader.position.div_ceil(8))
+    }
+
+    /// Decompress every gzip member and check their trailers
+    fn gunzip(mut data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        while !data.is_empty() {
+            assert_eq!(&data[..10], &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255]);
+            let (member, consumed) = inflate(&data[10..]);
+            let trailer = &data[10 + consumed..18 + consumed];
+            assert_eq!(trailer[..4], crc32(&member).to_le_bytes());
+            assert_eq!(trailer[4..], (member.len() as u32).to_le_bytes());
+            output.extend(member);
+            data = &data[18 + consumed..];
+        }
+        output
+    }
+
+    /// Deterministic bytes that hardly compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f491u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn compress_matches_known_output() {
+        assert_eq!(
+            compress(b""),
+            [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255, 0x03, 0x00, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        // zlib's output for the same input
+        assert_eq!(
+            compress(b"a"),
+            [
+                0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255, 0x4b, 0x04, 0x00, 0x43, 0xbe, 0xb7, 0xe8, 1,
+                0, 0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn compress_round_trips() {
+        let line = b"2024-05-06T07:08:09.123+02:00 | [PROCESS] Usage: 91.25%\n";
+        let inputs = [
+            b"a".to_vec(),
+            b"abcabcabcabcabcabc".to_vec(),
+            line.repeat(5_000),
+            vec![0; 100_000],
+            noise(3 * WINDOW),
+            [noise(WINDOW), noise(WINDOW)].concat().repeat(2),
+        ];
+        for input in inputs {
+            let compressed = compress(&input);
+            assert_eq!(gunzip(&compressed), input);
+        }
+        assert!(compress(&line.repeat(5_000)).len() < line.len() * 50);
+    }
+
+    #[test]
+    fn compress_file_writes_a_member_per_chunk_and_removes_the_original() {
+        let path =
+            std::env::temp_dir().join(format!("cpu-usage-logger-gzip-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let data = [noise(1000), b"measured\n".repeat(300_000)].concat();
+        assert!(data.len() > 2 * CHUNK);
+        fs::write(path, &data).unwrap();
+
+        compress_file(path).unwrap();
+
+        let compressed = fs::read(format!("{}.gz", path)).unwrap();
+        let _ = fs::remove_file(format!("{}.gz", path));
+        assert!(!std::path::Path::new(path).exists());
+        assert_eq!(gunzip(&compressed), data);
+    }
+}
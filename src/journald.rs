@@ -0,0 +1,69 @@
+//! journald sink sending threshold events with structured fields over the native protocol
+
+use std::os::unix::net::UnixDatagram;
+
+use crate::warn;
+
+const SOCKET_PATH: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "cpu-usage-logger";
+
+/// syslog priority of a sent event
+pub enum Priority {
+    Error = 3,
+    Warning = 4,
+    Info = 6,
+}
+
+/// Datagram socket connected to journald
+pub struct Journal {
+    socket: UnixDatagram,
+}
+
+impl Journal {
+    /// Connect to the journal, [None] if journald isn't running
+    pub fn open() -> Option<Self> {
+        let socket = UnixDatagram::unbound().and_then(|socket| {
+            socket.connect(SOCKET_PATH)?;
+            Ok(socket)
+        });
+        match socket {
+            Ok(socket) => Some(Journal { socket }),
+            Err(e) => {
+                eprintln!("Failed to connect to journald at {}: {}", SOCKET_PATH, e);
+                None
+            }
+        }
+    }
+
+    /// Send a message with additional fields (upper case names, e.g. `CPU_TOTAL`)
+    pub fn send(&self, priority: Priority, message: &str, fields: &[(&str, String)]) {
+        let mut datagram = Vec::new();
+        append_field(&mut datagram, "MESSAGE", message);
+        append_field(&mut datagram, "PRIORITY", &(priority as u8).to_string());
+        append_field(&mut datagram, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        for (name, value) in fields {
+            append_field(&mut datagram, name, value);
+        }
+
+        if let Err(e) = self.socket.send(&datagram) {
+            warn(
+                "journald-send",
+                &format!("failed to send event to journald: {}", e),
+            );
+        }
+    }
+}
+
+/// Append `NAME=value`, values with new lines are length prefixed instead
+fn append_field(datagram: &mut Vec<u8>, name: &str, value: &str) {
+    datagram.extend_from_slice(name.as_bytes());
+    match value.contains('\n') {
+        true => {
+            datagram.push(b'\n');
+            datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        }
+        false => datagram.push(b'='),
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
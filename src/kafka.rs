@@ -0,0 +1,77 @@
+//! Kafka sink publishing every measurement snapshot as a JSON message
+
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use rdkafka::ClientContext;
+use std::time::Duration;
+
+use crate::warn;
+
+/// How long to wait for outstanding messages on shutdown
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports failed deliveries instead of silently dropping them
+struct DeliveryReporter;
+
+impl ClientContext for DeliveryReporter {}
+
+impl ProducerContext for DeliveryReporter {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        if let Err((e, _)) = delivery_result {
+            warn(
+                "kafka-delivery",
+                &format!("failed to deliver Kafka message: {}", e),
+            );
+        }
+    }
+}
+
+/// Producer for a single topic, keying messages by hostname so they are partitioned per machine
+pub struct KafkaSink {
+    producer: BaseProducer<DeliveryReporter>,
+    topic: String,
+    key: String,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, topic: &str, key: &str) -> Self {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create_with_context(DeliveryReporter)
+            .expect("failed to create Kafka producer");
+
+        KafkaSink {
+            producer,
+            topic: topic.to_string(),
+            key: key.to_string(),
+        }
+    }
+
+    /// Queue a message for delivery, errors are reported but never fatal
+    pub fn send(&self, payload: &serde_json::Value) {
+        let payload = payload.to_string();
+        let record = BaseRecord::to(&self.topic).key(&self.key).payload(&payload);
+
+        if let Err((e, _)) = self.producer.send(record) {
+            warn(
+                "kafka-send",
+                &format!("failed to queue Kafka message: {}", e),
+            );
+        }
+
+        // Serve delivery callbacks
+        self.producer.poll(Duration::ZERO);
+    }
+
+    /// Wait for all queued messages to be delivered
+    pub fn flush(&self) {
+        if let Err(e) = self.producer.flush(FLUSH_TIMEOUT) {
+            warn(
+                "kafka-flush",
+                &format!("failed to flush Kafka messages: {}", e),
+            );
+        }
+    }
+}
@@ -1,19 +1,93 @@
-use chrono::Local;
-use clap::Parser;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::time::Duration;
-use std::{collections::HashMap, thread};
-use sysinfo::{Pid, Process, ProcessExt, ProcessRefreshKind, System, SystemExt};
+// The JSON schema is a single large `serde_json::json!` invocation
+#![recursion_limit = "256"]
+
+use chrono::{DateTime, Local};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, PidExt, Signal};
+
+use source::{Clock, ProcessInfo, ProcessSource, SysinfoSource, SystemClock};
+
+mod address;
+mod condition;
+mod diff;
+#[cfg(all(windows, feature = "eventlog"))]
+mod eventlog;
+mod graphite;
+mod gzip;
+#[cfg(all(target_os = "linux", feature = "journald"))]
+mod journald;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(all(target_os = "linux", feature = "procfs"))]
+mod procfs;
+mod rules;
+#[cfg(windows)]
+mod service;
+mod sha256;
+mod source;
 
 /// Simple utility to log high CPU usage, created by Rubin Raithel (@Coronon) https://rubinraithel.de
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// How long to wait between measurements in seconds
     #[arg(short = 'b', long, default_value_t = 5)]
     time_between_measurements: u64,
 
+    /// Whether `time_between_measurements` is the wait after measuring or the whole period including it
+    #[arg(long, value_enum, default_value_t = PeriodSemantics::Gap)]
+    period_semantics: PeriodSemantics,
+
+    /// Exit after this many measurements
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "duration")]
+    iterations: Option<u64>,
+
+    /// Exit once monitoring ran for this many seconds, cutting a measurement or wait short
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    duration: Option<u64>,
+
+    /// Report a measurement cycle that takes longer than this many seconds (e.g. a hanging refresh)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    cycle_timeout: Option<u64>,
+
+    /// What to do once a measurement cycle exceeded `cycle_timeout`
+    #[arg(long, value_enum, default_value_t = CycleTimeoutAction::Abort, requires = "cycle_timeout")]
+    on_cycle_timeout: CycleTimeoutAction,
+
+    /// How the time between measurements is chosen
+    #[arg(long, value_enum, default_value_t = IntervalProfile::Fixed)]
+    interval_profile: IntervalProfile,
+
+    /// Total CPU usage in percent below which the adaptive interval is lengthened
+    #[arg(long, default_value_t = 10.0)]
+    interval_low_water: f32,
+
+    /// Total CPU usage in percent above which the adaptive interval is shortened
+    #[arg(long, default_value_t = 50.0)]
+    interval_high_water: f32,
+
+    /// Shortest adaptive time between measurements in seconds
+    #[arg(long, default_value_t = 1)]
+    min_interval: u64,
+
+    /// Longest adaptive time between measurements in seconds
+    #[arg(long, default_value_t = 60)]
+    max_interval: u64,
+
     /// How long to measure for in seconds (CPU usage is an average over this time)
     #[arg(short, long, default_value_t = 1)]
     measurement_time: u64,
@@ -26,227 +100,7590 @@ struct Args {
     #[arg(short, long, default_value_t = 15.0)]
     process_log_threshold: f32,
 
-    /// Number of top CPU consuming processes to log when `total_log_threshold` is exceeded and to show in the CLI
-    #[arg(short, long, default_value_t = 5)]
-    number_of_processes_to_show: usize,
+    /// Also log processes using at least this much resident memory in MB, whatever their CPU usage
+    #[arg(long, conflicts_with_all = ["busy_when", "rules_file"])]
+    memory_log_threshold: Option<f32>,
+
+    /// Show a memory column in the table and the resident memory of logged processes
+    #[arg(long, default_value_t = false)]
+    show_memory: bool,
+
+    /// Log processes whose CPU usage exceeds their own usual usage (learned per process) by this factor, even below `process_log_threshold`
+    #[arg(long)]
+    process_anomaly_multiplier: Option<f32>,
+
+    /// Log processes matching a condition like `cpu>50 and mem>500` instead of `process_log_threshold`
+    /// (metrics: cpu, mem in MB, share, avg, runtime in seconds; `and` binds stronger than `or`)
+    #[arg(long, value_parser = condition::parse)]
+    busy_when: Option<condition::Condition>,
+
+    /// TOML file of `[[rule]]`s with a name `pattern` (`*`, `?` globs) and `cpu`/`mem` thresholds and an optional
+    /// `action`, the first matching rule replaces `process_log_threshold`, `busy_when` and `on_process_breach`
+    #[arg(long, value_parser = rules::load)]
+    rules_file: Option<rules::Rules>,
+
+    /// Also show the CPU usage summed up per user in a table below the processes
+    #[arg(long, default_value_t = false)]
+    group_by_user: bool,
+
+    /// Threshold of the summed up CPU usage of a single user to start logging at in percent
+    #[arg(long, requires = "group_by_user")]
+    user_log_threshold: Option<f32>,
+
+    /// Log when a single logical core stays at or above this usage in percent for `core_saturation_duration`
+    #[arg(long)]
+    core_saturation_threshold: Option<f32>,
+
+    /// Seconds a core has to stay above `core_saturation_threshold` for to be logged (a single-threaded bottleneck)
+    #[arg(long, default_value_t = 60, requires = "core_saturation_threshold")]
+    core_saturation_duration: u64,
+
+    /// Source of the total CPU usage used for `total_log_threshold` and the table header
+    #[arg(long, value_enum, default_value_t = TotalSource::Processes)]
+    total_source: TotalSource,
+
+    /// Only consider these logical cores (e.g. `0,2,4-7`) for the global total, their usage is shown in the header
+    #[arg(long, value_delimiter = ',', value_parser = parse_core_range)]
+    cores: Vec<(usize, usize)>,
+
+    /// Scale the total CPU usage is reported and compared against `total_log_threshold` in
+    #[arg(long, value_enum, default_value_t = TotalScale::Normalized)]
+    total_scale: TotalScale,
+
+    /// Append the top memory consuming processes to the logged message when `total_log_threshold` is exceeded
+    #[arg(long, default_value_t = false)]
+    include_memory_on_breach: bool,
+
+    /// Merge processes with the same name in the list logged when `total_log_threshold` is exceeded (the CLI table is unchanged)
+    #[arg(long, default_value_t = false)]
+    collapse_breach_list: bool,
+
+    /// Merge all processes with the same name into one row with their summed up usage, shown, logged and checked
+    /// against the thresholds as one process
+    #[arg(long, default_value_t = false)]
+    aggregate_by_name: bool,
+
+    /// Number of initial measurements that are shown but not logged or alerted on (while CPU counters stabilize)
+    #[arg(long, default_value_t = 1)]
+    warmup: u64,
+
+    /// Seconds after starting in which measurements are recorded (summaries, histogram, sinks) but nothing is alerted on
+    #[arg(long)]
+    alert_grace: Option<u64>,
+
+    /// Mute alerts and logging while this file exists (e.g. during maintenance), measuring continues
+    #[arg(long)]
+    pause_file: Option<String>,
+
+    /// Learn the average total CPU usage for this many seconds (without alerting) and then use it instead of `total_log_threshold`
+    #[arg(long)]
+    baseline_window: Option<u64>,
+
+    /// Alert when the total CPU usage exceeds the learned baseline by this factor (at a threshold of at least 5%)
+    #[arg(long, default_value_t = 2.0)]
+    baseline_multiplier: f32,
+
+    /// Smooth the per-process CPU usage with an EWMA of this weight (0 < alpha <= 1) to stabilize the ordering
+    #[arg(long, value_parser = parse_smoothing_alpha)]
+    process_smoothing: Option<f32>,
+
+    /// Average the per-process CPU usage over all measurements of the last N seconds
+    #[arg(long, conflicts_with = "process_smoothing")]
+    average_over: Option<u64>,
+
+    /// Where process names are taken from (used for display, logging and `baseline_file` matching)
+    #[arg(long, value_enum, default_value_t = NameSource::Comm)]
+    name_source: NameSource,
+
+    /// Replace process names and executable paths in all output with a stable short hash, for command lines that must not be logged
+    #[arg(long, default_value_t = false)]
+    hash_names: bool,
+
+    /// How PIDs are shown in the table and logs
+    #[arg(long, value_enum, default_value_t = PidFormat::Dec)]
+    pid_format: PidFormat,
+
+    /// Zero-pad PIDs to this many digits
+    #[arg(long, default_value_t = 0)]
+    pid_width: u64,
+
+    /// Columns to show in the table (comma separated)
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [Column::Pid, Column::Name, Column::Cpu])]
+    columns: Vec<Column>,
+
+    /// Title of the table, `{host}` and `{time}` are replaced by the hostname and current time
+    #[arg(long, default_value = "CPU usage")]
+    title: String,
+
+    /// Footer of the table, supports the same placeholders as `title`
+    #[arg(long)]
+    footer: Option<String>,
+
+    /// Path to a JSON snapshot (`{"processes": [{"name": ..., "got_cpu_usage": ...}]}`) to compare CPU usage against by process name
+    #[arg(long)]
+    baseline_file: Option<String>,
+
+    /// Log processes whose CPU usage grew by more than this many percent compared to `baseline_file`
+    #[arg(long, requires = "baseline_file")]
+    baseline_alert_delta: Option<f32>,
+
+    /// Ignore processes running for less than this many seconds for `process_log_threshold` (shown dimmed in the CLI)
+    #[arg(long)]
+    settle_delay: Option<u64>,
+
+    /// Number of top CPU consuming processes to log when `total_log_threshold` is exceeded and to show in the CLI,
+    /// or a percentage of all processes like `10%`
+    #[arg(short, long, default_value = "5", value_parser = parse_process_count)]
+    number_of_processes_to_show: ProcessCount,
+
+    /// Only keep the top N processes each measurement instead of sorting all of them (for systems with many processes),
+    /// processes above `memory_log_threshold` are kept too
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_processes: Option<u64>,
+
+    /// Order of processes with the same usage, so they don't swap places between measurements
+    #[arg(long, value_enum, default_value_t = TieBreak::Pid)]
+    tie_break: TieBreak,
+
+    /// Keep these PIDs at the top of the table, marked with `*`, for as long as the process runs
+    #[arg(long, value_delimiter = ',', conflicts_with = "max_processes")]
+    pin: Vec<usize>,
+
+    /// Flag processes whose stats couldn't be read (e.g. access denied) instead of showing them as idle
+    #[arg(long)]
+    mark_inaccessible: bool,
+
+    /// Action to take against a process exceeding `process_log_threshold` for `breach_persist` consecutive measurements
+    #[arg(long, value_enum, default_value_t = BreachAction::None)]
+    on_process_breach: BreachAction,
+
+    /// Number of consecutive measurements a process has to exceed `process_log_threshold` for before `on_process_breach` is taken
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    breach_persist: u32,
+
+    /// Confirm that `--on-process-breach kill` will kill processes
+    #[arg(
+        long,
+        default_value_t = false,
+        required_if_eq("on_process_breach", "kill")
+    )]
+    i_understand_this_kills_processes: bool,
+
+    /// Hide processes below this CPU usage in percent from the table and single process logging (still counted in the total),
+    /// processes above `memory_log_threshold` are kept
+    #[arg(long, default_value_t = 0.0)]
+    min_display_usage: f32,
+
+    /// Print a histogram of the total CPU usage on shutdown
+    #[arg(long, default_value_t = false)]
+    histogram: bool,
+
+    /// Also write the histogram to the log file on shutdown
+    #[arg(long, default_value_t = false, requires = "histogram")]
+    log_histogram: bool,
+
+    /// Log a single line with the total and the top process every measurement, regardless of thresholds
+    #[arg(long, default_value_t = false, requires = "log_file")]
+    always_log_top: bool,
+
+    /// Only log a line when the top process changes to another one, a minimal record of what dominated the CPU over time
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "log_file",
+        conflicts_with = "always_log_top"
+    )]
+    log_on_change: bool,
+
+    /// Log an "alive" line with the total if nothing was logged for this many seconds, so a quiet log isn't mistaken for a dead monitor
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), requires = "log_file")]
+    heartbeat: Option<u64>,
+
+    /// Log the average, peak and p95 total CPU usage and the busiest process of every hour
+    #[arg(long, default_value_t = false, requires = "log_file")]
+    hourly_summary: bool,
+
+    /// Print a JSON summary of the run (measurements, breaches, peaks, duration) on shutdown, to this path or `-` for stdout
+    #[arg(long, num_args = 0..=1, default_missing_value = "-")]
+    summary_json: Option<String>,
+
+    /// Write all measurements kept in memory to this file on shutdown (JSON lines, or logfmt with `--format logfmt`),
+    /// a `:json` or `:logfmt` suffix overrides the format
+    #[arg(long, value_parser = parse_export_target)]
+    export_on_exit: Option<SinkTarget>,
+
+    /// Number of most recent measurements kept in memory for `export_on_exit`
+    #[arg(long, default_value_t = 10_000, value_parser = clap::value_parser!(u64).range(1..))]
+    history_size: u64,
+
+    /// How much of every measurement the JSON snapshots (Kafka, `export_on_exit`) contain
+    #[arg(long, value_enum, default_value_t = JsonDetail::Full)]
+    json_detail: JsonDetail,
+
+    /// Write the `timestamp` of JSON records (snapshots, events, summary) as a Unix epoch in this unit instead of ISO 8601
+    #[arg(long, value_enum)]
+    json_timestamp_epoch: Option<EpochUnit>,
+
+    /// Keep the ISO 8601 timestamp of JSON records in `timestamp_iso` next to the epoch one
+    #[arg(long, default_value_t = false, requires = "json_timestamp_epoch")]
+    json_iso_timestamp: bool,
 
     /// CLI mode -> periodically write stats to stdout
     #[arg(short, long, default_value_t = false)]
     cli: bool,
 
-    /// Path to log file
-    #[arg(short, long)]
-    log_file: Option<String>,
+    /// Only start printing in CLI mode once a threshold was exceeded, then keep printing
+    #[arg(long, default_value_t = false, requires = "cli")]
+    cli_on_breach: bool,
+
+    /// Only redraw the CLI table while the total CPU usage is at least this many percent, keep the last frame otherwise
+    #[arg(long, requires = "cli")]
+    min_total_to_display: Option<f32>,
+
+    /// Show the share of CPU time spent in iowait and steal in the table header (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_cpu_breakdown: bool,
+
+    /// Show the estimated CPU package power draw in the header and a power column attributing it by CPU share (Linux with RAPL only)
+    #[arg(long, default_value_t = false)]
+    show_power: bool,
+
+    /// Show the system wide swap-in and swap-out rates during the measurement in the header (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_swap_activity: bool,
+
+    /// Scale usage by the current / maximum CPU frequency to approximate the work actually done (Linux only)
+    #[arg(long, default_value_t = false)]
+    frequency_aware: bool,
+
+    /// Show voluntary and involuntary context switches per process during the measurement (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_ctxsw: bool,
+
+    /// Show minor and major page faults per process during the measurement (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_faults: bool,
+
+    /// Log processes with at least this many major page faults during a measurement, a sign of memory pressure (Linux only)
+    #[arg(long, requires = "show_faults")]
+    major_fault_threshold: Option<u64>,
+
+    /// Show the disk read and write rate per process during the measurement (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_disk: bool,
+
+    /// Smooth the disk rates with an EWMA of this weight (0 < alpha <= 1)
+    #[arg(long, value_parser = parse_smoothing_alpha, requires = "show_disk")]
+    io_smoothing: Option<f32>,
+
+    /// Unit of the disk rates
+    #[arg(long, value_enum, default_value_t = IoUnit::Auto)]
+    io_unit: IoUnit,
+
+    /// Show the nice value and real-time priority of each process (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_priority: bool,
+
+    /// Show the number of open file descriptors of each process and of the whole system (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_fds: bool,
+
+    /// Log processes once they have this many open file descriptors, a climbing count hints at a leak (Linux only)
+    #[arg(long, requires = "show_fds")]
+    fd_threshold: Option<usize>,
+
+    /// Show the CPUs each process is allowed to run on (Linux only)
+    #[arg(long, default_value_t = false)]
+    show_affinity: bool,
+
+    /// Show the executable path of each process
+    #[arg(long, default_value_t = false)]
+    show_exe_path: bool,
+
+    /// Add the executable path and its SHA-256 to process threshold breaches (hashed once per path)
+    #[arg(long, default_value_t = false)]
+    hash_exe: bool,
+
+    /// Sum the usage of child processes into a parent exceeding `process_log_threshold` and hide them
+    #[arg(long, default_value_t = false)]
+    processes_only_above_parent: bool,
+
+    /// Still list the children summed into their parent by `processes_only_above_parent`
+    #[arg(
+        long,
+        default_value_t = false,
+        requires = "processes_only_above_parent"
+    )]
+    expand: bool,
+
+    /// Only refresh CPU usage and skip all optional enrichment (user names, CPU breakdown, ...) to minimize overhead
+    #[arg(long, default_value_t = false)]
+    minimal: bool,
+
+    /// Print diagnostic information in CLI mode
+    #[arg(long, default_value_t = false)]
+    debug: bool,
+
+    /// Show an "all quiet" message in the CLI header while no threshold is close to being exceeded
+    #[arg(long, default_value_t = false)]
+    idle_message: bool,
+
+    /// Show the CPU usage and memory of this logger itself in the CLI header, to confirm it isn't a load of its own
+    #[arg(long, default_value_t = false)]
+    report_self: bool,
+
+    /// How to color the process rows in CLI mode by their usage
+    #[arg(long, value_enum, default_value_t = ColorScheme::None)]
+    color_scheme: ColorScheme,
+
+    /// Up to three ascending usages in percent (e.g. `50,75,90`) from which the total and the process rows are colored
+    /// yellow, orange and red in CLI mode (with fewer, the last colors are used)
+    #[arg(long, value_delimiter = ',', conflicts_with = "color_scheme")]
+    color_thresholds: Vec<f32>,
+
+    /// Show the total CPU usage as a bar below it in the table header, colored by `total_log_threshold` in CLI mode
+    #[arg(long, default_value_t = false)]
+    gauge: bool,
+
+    /// Alignment of the values in the usage column of the table
+    #[arg(long, value_enum, default_value_t = Alignment::Left)]
+    usage_align: Alignment,
+
+    /// Format of the CLI output (logfmt is also used for the log file)
+    #[arg(short, long, value_enum, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+
+    /// Manage the Windows service (install and uninstall need an elevated prompt)
+    #[cfg(windows)]
+    #[arg(long, value_enum)]
+    service: Option<service::ServiceAction>,
+
+    /// Path to log file, a `:text`, `:compact`, `:logfmt`, `:json` or `:csv` suffix overrides the format set by `format`
+    /// and `compact_log`
+    #[arg(short, long, value_parser = parse_log_file_target)]
+    log_file: Option<SinkTarget>,
+
+    /// Only log a process exceeding `process_log_threshold` on the measurement it crossed it, not while it stays above
+    #[arg(long, default_value_t = false)]
+    alert_new_only: bool,
+
+    /// Suppress identical threshold log messages (same process and rounded usage) within this many seconds
+    #[arg(long)]
+    dedup_window: Option<u64>,
+
+    /// Number of fractional second digits in timestamps
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u64).range(0..=9))]
+    timestamp_precision: u64,
+
+    /// Decimal separator of the numbers in the table, text log messages and CSV, which is then delimited by `;` (logfmt, JSON and templates always use `.`)
+    #[arg(long, value_enum, default_value_t = DecimalSeparator::Point)]
+    decimal_separator: DecimalSeparator,
+
+    /// Log one event per line, total breaches as a single line with the top processes instead of the full table
+    #[arg(long, default_value_t = false)]
+    compact_log: bool,
+
+    /// Log threshold events as single lines from this template, e.g. `{ts} {reason} {pid} {name} {cpu}`
+    #[arg(long, value_parser = parse_log_template, requires = "log_file", conflicts_with_all = ["format", "compact_log"])]
+    log_template: Option<LogTemplate>,
+
+    /// Tag every log line from a total breach through its recovery with a shared incident number
+    #[arg(long, default_value_t = false)]
+    incident_ids: bool,
+
+    /// Write a JSON report of every incident (total breach through recovery) into this directory: duration, peak,
+    /// the processes most responsible and a sampled timeline
+    #[arg(long)]
+    incident_dir: Option<String>,
+
+    /// Sync the log file to disk after every write (survives power loss, slower)
+    #[arg(long, default_value_t = false)]
+    log_sync: bool,
+
+    /// Path to switch logging to after repeated failed writes (e.g. a full disk), `-` for stdout
+    #[arg(long, requires = "log_file")]
+    log_fallback: Option<String>,
+
+    /// Cycle logging through this many files (`cpu.0.log` to `cpu.N-1.log` for `cpu.log`), truncating each when switching to it
+    #[arg(long, requires = "log_file", group = "log_rotation", value_parser = clap::value_parser!(u64).range(2..))]
+    log_files: Option<u64>,
+
+    /// Seconds to write to one of `log_files` before switching to the next
+    #[arg(long, default_value_t = 3600, requires = "log_files", value_parser = clap::value_parser!(u64).range(1..))]
+    log_switch_interval: u64,
+
+    /// Rotate the log file before it would grow beyond this many MB (`cpu.log` -> `cpu.log.1` -> `cpu.log.2` ...),
+    /// only ever between messages
+    #[arg(long, requires = "log_file", conflicts_with = "log_files", group = "log_rotation", value_parser = clap::value_parser!(u64).range(1..))]
+    max_log_size: Option<u64>,
+
+    /// Number of rotated log files kept by `max_log_size`, older ones are deleted
+    #[arg(long, default_value_t = 5, requires = "max_log_size", value_parser = clap::value_parser!(u64).range(1..))]
+    max_log_files: u64,
+
+    /// gzip each of `log_files` once logging switched away from it (`cpu.0.log` -> `cpu.0.log.gz`) or each file rotated
+    /// by `max_log_size` (`cpu.log.1` -> `cpu.log.1.gz`), in the background
+    #[arg(long, default_value_t = false, requires = "log_rotation")]
+    compress_rotated: bool,
+
+    /// Hide the values of options that may contain hosts or credentials in the command line written to the log file
+    #[arg(long, default_value_t = false)]
+    redact: bool,
+
+    /// Print each kind of warning to stderr at most once per this many seconds
+    #[arg(long, default_value_t = 60)]
+    warn_interval: u64,
+
+    /// Page the CLI output through `$PAGER` (`less -R` by default) instead of redrawing it, quitting the pager stops monitoring
+    #[cfg(unix)]
+    #[arg(long, default_value_t = false, requires = "cli")]
+    pager: bool,
+
+    /// Show fewer processes in the CLI table when needed to fit the terminal height, so the header never scrolls off
+    #[arg(long, default_value_t = false, requires = "cli")]
+    compact_cli: bool,
+
+    /// Redraw the CLI table at most once per this many seconds, showing the latest measurement (threshold messages are
+    /// shown right away)
+    #[arg(long, requires = "cli", value_parser = clap::value_parser!(u64).range(1..))]
+    redraw_interval: Option<u64>,
+
+    /// Path to a FIFO (created if missing) to write a JSON line to on every threshold breach
+    #[cfg(unix)]
+    #[arg(long)]
+    event_fifo: Option<String>,
+
+    /// Exclude kernel threads (kworker, ksoftirqd, ...) from measurement (Linux only)
+    #[arg(long, default_value_t = false)]
+    exclude_kernel: bool,
+
+    /// Only measure processes whose name matches one of these patterns (comma separated or repeated), `*` and `?`
+    /// globs match the whole name, other patterns any part of it, ignoring case
+    #[arg(long, value_delimiter = ',')]
+    include: Vec<String>,
+
+    /// Never measure processes whose name matches one of these patterns (like `include`), wins over `include`
+    #[arg(long, value_delimiter = ',')]
+    exclude: Vec<String>,
+
+    /// Report threshold events to the Windows Event Log (Application log, source `cpu-usage-logger`)
+    #[cfg(all(windows, feature = "eventlog"))]
+    #[arg(long, default_value_t = false)]
+    eventlog: bool,
+
+    /// Send threshold events to journald with structured fields (`CPU_TOTAL`, `PID`, `PROCESS_NAME`, ...)
+    #[cfg(all(target_os = "linux", feature = "journald"))]
+    #[arg(long, default_value_t = false)]
+    journald: bool,
+
+    /// Where processes and CPU usage are read from
+    #[cfg(all(target_os = "linux", feature = "procfs"))]
+    #[arg(long, value_enum, default_value_t = Backend::Sysinfo)]
+    backend: Backend,
+
+    /// Comma separated list of Kafka brokers to publish every measurement to
+    #[cfg(feature = "kafka")]
+    #[arg(long, requires = "kafka_topic")]
+    kafka_brokers: Option<String>,
+
+    /// Kafka topic to publish measurements to
+    #[cfg(feature = "kafka")]
+    #[arg(long, requires = "kafka_brokers")]
+    kafka_topic: Option<String>,
+
+    /// Name of this machine in every JSON record and network sink, for telling machines apart centrally [default: hostname]
+    #[arg(long, value_parser = parse_instance_tag)]
+    instance_tag: Option<String>,
+
+    /// Start every line of the log file with the `instance_tag`
+    #[arg(long, default_value_t = false, requires = "log_file")]
+    tag_log_lines: bool,
+
+    /// Graphite server (`host:port`, `[ipv6]:port`) to send the total and top process CPU usage to every measurement over TCP
+    #[arg(long, value_parser = address::parse)]
+    graphite: Option<address::Address>,
+
+    /// Prefix of the Graphite metric paths
+    #[arg(long, default_value = "cpu_usage_logger")]
+    graphite_prefix: String,
+
+    /// Address family to try first when the host of a network sink resolves to both
+    #[arg(long, value_enum, default_value_t = address::AddressFamily::Any)]
+    address_family: address::AddressFamily,
+
+    /// Path to a file listing PIDs to watch (one per line), re-read every measurement
+    #[arg(long)]
+    pid_file: Option<String>,
+
+    /// Log when a watched process is replaced by a new one with the same name (e.g. restarted by a supervisor)
+    #[arg(long, default_value_t = false, requires = "pid_file")]
+    log_restarts: bool,
+
+    /// Only watch the processes listening on these TCP/UDP ports (e.g. `8080,8443`), looked up every measurement (Linux only)
+    #[arg(long, value_delimiter = ',', conflicts_with = "pid_file")]
+    watch_port: Vec<u16>,
+
+    /// Wait up to this many seconds for a process of `pid_file` or `watch_port` to run before measuring, exit if none did
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    wait_for_process: Option<u64>,
+
+    /// Print the JSON Schema of the JSON output (events and snapshots) and exit
+    #[arg(long, default_value_t = false)]
+    print_schema: bool,
+
+    /// Print every process (PID, names, user, executable and command line) once and exit, to help writing filters
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "table")]
+    list_processes: Option<ListFormat>,
+
+    /// Generate CPU load on a background thread for a few seconds, check that it is measured and exit
+    #[arg(long, default_value_t = false)]
+    self_test: bool,
 }
 
-/// CPU usage stats for a process
-struct ProcessStats<'a> {
-    got_cpu_usage: f32,
-    process: &'a Process,
+/// Tools run instead of monitoring
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare two JSON snapshots written by `export_on_exit` (the last line of each) and exit
+    Diff {
+        /// Snapshot taken before the change
+        before: String,
+        /// Snapshot taken after the change
+        after: String,
+
+        /// How the processes of the two snapshots are matched
+        #[arg(long, value_enum, default_value_t = diff::DiffKey::Name)]
+        by: diff::DiffKey,
+    },
 }
 
-impl<'a> From<&'a Process> for ProcessStats<'a> {
-    fn from(prcs: &'a Process) -> Self {
-        ProcessStats {
-            got_cpu_usage: 0.0,
-            process: prcs,
+/// Columns that can be shown in the table
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Column {
+    /// Process ID
+    Pid,
+    /// Process name
+    Name,
+    /// CPU usage
+    Cpu,
+    /// Resident memory
+    Mem,
+    /// Name of the user running the process
+    User,
+    /// CPU usage compared to `baseline_file`
+    Delta,
+    /// CPU usage before `process_smoothing` or `average_over` was applied
+    Raw,
+    /// Share of the total CPU usage
+    Share,
+    /// Mean CPU usage since the process was first measured
+    Avg,
+    /// Share of the package power estimated from the share of the total CPU usage
+    Power,
+    /// Voluntary context switches during the measurement (waiting for I/O, locks, ...)
+    Vcsw,
+    /// Involuntary context switches during the measurement (preempted, a sign of CPU contention)
+    Ivcsw,
+    /// Minor page faults during the measurement (served without disk I/O)
+    MinorFaults,
+    /// Major page faults during the measurement (pages read from disk or swap)
+    MajorFaults,
+    /// Bytes read from disk per second during the measurement
+    DiskRead,
+    /// Bytes written to disk per second during the measurement
+    DiskWrite,
+    /// Nice value, or the real-time priority for processes with a real-time scheduling policy
+    Nice,
+    /// Open file descriptors
+    Fds,
+    /// CPUs the process is allowed to run on
+    Affinity,
+    /// Path of the executable
+    Exe,
+}
+
+impl Column {
+    /// Title shown in the table header
+    fn title(&self) -> &'static str {
+        match self {
+            Column::Pid => "PID",
+            Column::Name => "Name",
+            Column::Cpu => "Usage",
+            Column::Mem => "Memory",
+            Column::User => "User",
+            Column::Delta => "Delta",
+            Column::Raw => "Raw",
+            Column::Share => "Share",
+            Column::Avg => "Avg",
+            Column::Power => "Power",
+            Column::Vcsw => "Vol. CS",
+            Column::Ivcsw => "Invol. CS",
+            Column::MinorFaults => "Minor PF",
+            Column::MajorFaults => "Major PF",
+            Column::DiskRead => "Disk read",
+            Column::DiskWrite => "Disk write",
+            Column::Nice => "Nice",
+            Column::Fds => "FDs",
+            Column::Affinity => "Affinity",
+            Column::Exe => "Executable",
         }
     }
-}
 
-/// CPU usage stats for the whole system (including processes)
-struct CPUStats<'a> {
-    processes: Vec<ProcessStats<'a>>,
-}
+    /// Fixed width of the column, [None] if it takes up the remaining space
+    fn width(&self) -> Option<usize> {
+        match self {
+            Column::Name => None,
+            Column::User | Column::Affinity | Column::DiskRead | Column::DiskWrite => Some(12),
+            Column::Exe => Some(30),
+            _ => Some(10),
+        }
+    }
 
-/// Convert `sys.processes()` to [CPUStats]
-impl<'a> From<&'a HashMap<Pid, Process>> for CPUStats<'a> {
-    fn from(value: &'a HashMap<Pid, Process>) -> Self {
-        CPUStats {
-            processes: value
-                .values()
-                .map(|v| ProcessStats {
-                    got_cpu_usage: 0.0,
-                    process: v,
-                })
-                .collect::<Vec<ProcessStats>>(),
+    /// Formatted value of the column for a process
+    fn value(&self, p: &ProcessStats) -> String {
+        match self {
+            Column::Pid => format_pid(p.process.pid()),
+            Column::Name => {
+                let name = match p.pinned {
+                    true => format!("* {}", p.name),
+                    false => p.name.to_string(),
+                };
+                match p.merged {
+                    1 => name,
+                    merged => format!("{} ({} processes)", name, merged),
+                }
+            }
+            Column::Cpu | Column::Mem if p.inaccessible => String::from("n/a"),
+            Column::Cpu => format!("{:.2} %", Decimal(p.got_cpu_usage)),
+            Column::Mem => format_memory(p.memory),
+            Column::User => p.user_name.unwrap_or("-").to_string(),
+            Column::Delta => match p.baseline_delta {
+                Some(delta) => format!("{:+.2} %", Decimal(delta)),
+                None => String::from("-"),
+            },
+            Column::Raw => match p.raw_cpu_usage {
+                Some(raw) => format!("({:.2} %)", Decimal(raw)),
+                None => String::from("-"),
+            },
+            Column::Share => format!("{:.2} %", Decimal(p.share_of_total)),
+            Column::Avg => format!("{:.2} %", Decimal(p.avg_cpu_usage)),
+            Column::Power => match p.power_estimate {
+                Some(power) => format!("~{:.2} W", Decimal(power)),
+                None => String::from("-"),
+            },
+            Column::Vcsw => p
+                .context_switches
+                .map_or(String::from("-"), |(voluntary, _)| voluntary.to_string()),
+            Column::Ivcsw => p
+                .context_switches
+                .map_or(String::from("-"), |(_, involuntary)| {
+                    involuntary.to_string()
+                }),
+            Column::MinorFaults => p
+                .page_faults
+                .map_or(String::from("-"), |(minor, _)| minor.to_string()),
+            Column::MajorFaults => p
+                .page_faults
+                .map_or(String::from("-"), |(_, major)| major.to_string()),
+            Column::DiskRead => p
+                .disk_rates
+                .map_or(String::from("-"), |(read, _)| format_rate(read)),
+            Column::DiskWrite => p
+                .disk_rates
+                .map_or(String::from("-"), |(_, written)| format_rate(written)),
+            Column::Nice => match p.priority {
+                Some((_, Some(rt_priority))) => format!("RT {}", rt_priority),
+                Some((nice, None)) => nice.to_string(),
+                None => String::from("-"),
+            },
+            Column::Fds => p
+                .fd_count
+                .map_or(String::from("-"), |count| count.to_string()),
+            // Only looked up for the shown processes
+            Column::Affinity => read_affinity(p.process.pid()).unwrap_or(String::from("-")),
+            Column::Exe => {
+                // Keep the end of long paths, the file name matters most
+                let exe = format_exe(p.process.exe());
+                match exe.chars().count() {
+                    0 => String::from("-"),
+                    length if length > 30 => {
+                        format!("...{}", exe.chars().skip(length - 27).collect::<String>())
+                    }
+                    _ => exe,
+                }
+            }
         }
     }
 }
 
-fn main() {
-    //* Parse args
-    let args = Args::parse();
+/// Minimum time between two stderr warnings of the same kind
+static WARN_INTERVAL_SECS: AtomicU64 = AtomicU64::new(60);
 
-    //* Process
-    // Init process tracking
-    let proc_refresh_kind = ProcessRefreshKind::new().with_cpu();
-    let mut sys = System::new_all();
-    let cpu_count = sys.physical_core_count().unwrap() as f32;
+/// Number of fractional second digits in timestamps
+static TIMESTAMP_PRECISION: AtomicU64 = AtomicU64::new(3);
 
-    loop {
-        // Refresh CPU
-        sys.refresh_processes_specifics(proc_refresh_kind);
+/// Show PIDs in hexadecimal and zero-pad them to this many digits
+static PID_HEX: AtomicBool = AtomicBool::new(false);
+static PID_WIDTH: AtomicU64 = AtomicU64::new(0);
 
-        // Get currently running processes
-        let mut cpu_stats: CPUStats = CPUStats::from(sys.processes());
+/// Show hashes instead of executable paths, see [hash_name]
+static HASH_NAMES: AtomicBool = AtomicBool::new(false);
 
-        // Start CPU calculation
-        for p_info in &cpu_stats.processes {
-            p_info.process.cpu_usage();
-        }
+/// Write human readable numbers with a decimal comma, see [Decimal]
+static DECIMAL_COMMA: AtomicBool = AtomicBool::new(false);
 
-        // Wait to collect data between time points
-        thread::sleep(Duration::from_secs(args.measurement_time));
+/// Unit of the epoch timestamps in JSON records, ISO 8601 strings if [None], see [json_timestamp]
+static JSON_TIMESTAMP_EPOCH: Mutex<Option<EpochUnit>> = Mutex::new(None);
+static JSON_ISO_TIMESTAMP: AtomicBool = AtomicBool::new(false);
 
-        // Update CPU usage
-        cpu_stats
-            .processes
-            .iter_mut()
-            .for_each(|p| p.got_cpu_usage = p.process.cpu_usage() / cpu_count);
-
-        // Sort by usage
-        cpu_stats.processes.sort_by(|a, b| {
-            a.got_cpu_usage
-                .partial_cmp(&b.got_cpu_usage)
-                .unwrap()
-                .reverse()
-        });
+/// Unit disk rates are shown in
+static IO_UNIT: Mutex<IoUnit> = Mutex::new(IoUnit::Auto);
 
-        // Calculate total usage by all processes
-        let total_cpu_usage: f32 = cpu_stats.processes.iter().map(|v| v.got_cpu_usage).sum();
-        let mut formatted_stats: Option<String> = None;
+/// Start of the running measurement cycle in milliseconds since the epoch, 0 while waiting between measurements
+static CYCLE_STARTED_MS: AtomicU64 = AtomicU64::new(0);
 
-        //* Handle thresholds
-        let mut total_cpu_usage_message: Option<String> = None;
-        if total_cpu_usage >= args.total_log_threshold {
-            // We always have to format the stats here
-            formatted_stats = Some(format_stats(
-                &cpu_stats,
-                total_cpu_usage,
-                args.number_of_processes_to_show,
-            ));
+/// Kind of warning -> (last time it was printed, number of times it was suppressed since)
+static WARNINGS: Mutex<BTreeMap<&'static str, (Instant, u32)>> = Mutex::new(BTreeMap::new());
 
-            total_cpu_usage_message = Some(format!(
-                "Total CPU usage threshold of {:.2}% exceeded -> {:.2}%",
-                args.total_log_threshold, total_cpu_usage,
-            ));
+/// Options whose values are hidden by `redact`
+const REDACTED_OPTIONS: [&str; 2] = ["--kafka-brokers", "--graphite"];
 
-            // If we would push the whole logged message into total_cpu_usage_message the
-            // CLI would display the usage table twice
-            let logged_message = format!(
-                "{}\n{}",
-                total_cpu_usage_message.as_ref().unwrap(),
-                formatted_stats.as_ref().unwrap(),
-            );
+/// Version of the JSON output, bumped on every incompatible change
+const JSON_SCHEMA_VERSION: u32 = 1;
 
-            log_to_file(&args.log_file, &logged_message);
-        }
+/// Fraction of a threshold above which it is considered close to being exceeded
+const NEAR_BREACH_RATIO: f32 = 0.8;
 
-        let mut process_cpu_usage_message: Option<String> = None;
-        cpu_stats
-            .processes
-            .iter()
-            .take_while(|p| p.got_cpu_usage >= args.process_log_threshold)
-            .for_each(|p| {
-                let existing_string = match process_cpu_usage_message.as_ref() {
-                    Some(s) => format!("{}\n", s),
-                    None => String::new(),
-                };
+/// Core count from which busy cores are counted in the header and per-core usage is shown as a heatmap
+const MANY_CORES: usize = 32;
 
-                process_cpu_usage_message = Some(format!(
-                    "{}Single process CPU usage threshold of {:.2}% exceeded -> [Pid: {}] Name: '{}' Usage: {:.2}%",
-                    existing_string,
-                    args.process_log_threshold,
-                    p.process.pid(),
-                    p.process.name(),
-                    p.got_cpu_usage,
-                ));
-            });
-        if process_cpu_usage_message.is_some() {
-            log_to_file(&args.log_file, process_cpu_usage_message.as_ref().unwrap());
-        }
+/// EWMA weight of the usual usage of a process for `process_anomaly_multiplier`
+const ANOMALY_ALPHA: f32 = 0.1;
 
-        //* Print results
-        if args.cli {
-            // Ensure we only format stats if needed
-            formatted_stats = formatted_stats.or_else(|| {
-                Some(format_stats(
-                    &cpu_stats,
-                    total_cpu_usage,
-                    args.number_of_processes_to_show,
-                ))
-            });
+/// CPU usage below which a process is never anomalous, idle processes would multiply their norm all the time
+const ANOMALY_MIN_USAGE: f32 = 5.0;
 
-            // Clear old output (we use a library because different consoles /os's support different ways of clearing)
-            clearscreen::clear().expect("failed to clear screen");
+/// Lowest total threshold learned by `baseline_window`, an idle baseline would otherwise alert on every measurement
+const BASELINE_MIN_THRESHOLD: f32 = 5.0;
 
-            // Write new output
-            println!("{}", formatted_stats.as_ref().unwrap());
+/// Formats the CLI output can be written in
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Table of the top processes, redrawn every measurement
+    Table,
+    /// Single status line per measurement (e.g. for status bars), no clearing
+    Oneline,
+    /// key=value lines per top process, for log shippers like Loki or Splunk
+    Logfmt,
+    /// One JSON object per measurement with the top processes (JSON lines)
+    Json,
+    /// One CSV row per top process after a header row
+    Csv,
+}
 
-            if total_cpu_usage_message.is_some() {
-                println!("\n{}", total_cpu_usage_message.as_ref().unwrap());
-            }
+/// Amount of detail in the JSON snapshots
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum JsonDetail {
+    /// The total and the top process with its required fields only
+    Summary,
+    /// Every top process with every enabled field
+    Full,
+}
 
-            if process_cpu_usage_message.is_some() {
-                println!("\n{}", process_cpu_usage_message.as_ref().unwrap());
-            }
-        }
+/// Formats a sink can be written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SinkFormat {
+    /// Full tables with blank lines around every event
+    Text,
+    /// One event per line like `compact_log`
+    Compact,
+    Logfmt,
+    /// One snapshot per line, or one event per line in the log file
+    Json,
+    /// One row per process and event after a `CSV_HEADER` row
+    Csv,
+}
 
-        // Wait for next iteration
-        thread::sleep(Duration::from_secs(args.time_between_measurements));
+impl SinkFormat {
+    const ALL: [(&'static str, SinkFormat); 5] = [
+        ("text", SinkFormat::Text),
+        ("compact", SinkFormat::Compact),
+        ("logfmt", SinkFormat::Logfmt),
+        ("json", SinkFormat::Json),
+        ("csv", SinkFormat::Csv),
+    ];
+}
+
+/// Columns of the CSV output, free text log lines only fill in `timestamp`, `event` and `message`
+const CSV_HEADER: &str = "timestamp,event,total_cpu_usage,pid,name,got_cpu_usage,message";
+
+/// Delimiter of CSV fields, `;` with a decimal comma so numbers don't need quoting
+fn csv_delimiter() -> &'static str {
+    match DECIMAL_COMMA.load(Ordering::Relaxed) {
+        true => ";",
+        false => ",",
     }
 }
 
-/// Formats stats into a nice looking table
-fn format_stats(cpu_stats: &CPUStats, total_cpu_usage: f32, num_processes: usize) -> String {
-    format!(
-        "{header}\n{total_cpu_usage}\n{timestamp}\n{divider}\n{column_names}\n{column_names_divider}\n{stats}\n{divider}",
-        header = format_args!("{:-^80}", "CPU usage"),
-        total_cpu_usage = format_args!("|{: ^78}|", format!("{:.2} %", total_cpu_usage)),
-        timestamp = format_args!("|{: ^78}|", get_iso_time()),
-        divider = format_args!("{:-^80}", ""),
-        column_names = format_args!("| {0: <10} | {1: <50} | {2: <10} |", "PID", "Name", "Usage"),
-        column_names_divider = format_args!("|{0:-<12}|{1:-<52}|{2:-<12}|", "", "", ""),
-        stats = cpu_stats.processes.iter().take(num_processes).map(|p| {
-            format!(
-                "| {0: <10} | {1: <50} | {2: <10} |",
-                p.process.pid().to_string(),
-                p.process.name().to_string(),
-                format!("{:.2} %", p.got_cpu_usage),
-            )
-        }).fold(String::new(), |ret, new| format!("{}\n{}", ret, new)).trim()
-    )
+/// `CSV_HEADER` with the current [csv_delimiter]
+fn csv_header() -> String {
+    CSV_HEADER.replace(',', csv_delimiter())
 }
 
-/// Log a message to a file with timestamp, ending in a new line
-fn log_to_file(file_path: &Option<String>, message: &str) {
-    // Don't log anything if no path specified
-    if file_path.is_none() {
-        return;
+/// Path of a sink with an optional format overriding the global one
+#[derive(Clone, Debug)]
+struct SinkTarget {
+    path: String,
+    format: Option<SinkFormat>,
+}
+
+/// Ways to color the process rows of the CLI table
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ColorScheme {
+    /// No colors
+    None,
+    /// Green, yellow from 80% of `process_log_threshold` and red from the threshold on
+    Threshold,
+    /// Continuous green -> yellow -> red up to `process_log_threshold`
+    Gradient,
+}
+
+/// Ways the time between measurements is chosen
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum IntervalProfile {
+    /// Always `time_between_measurements`
+    Fixed,
+    /// Start at `time_between_measurements`, doubled while quiet and halved while busy
+    Adaptive,
+}
+
+/// Sources processes and CPU usage can be read from
+#[cfg(all(target_os = "linux", feature = "procfs"))]
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Backend {
+    /// sysinfo, the same as on every other platform
+    Sysinfo,
+    /// Read `/proc` directly, less overhead at sub-second intervals
+    Procfs,
+}
+
+/// Meanings of `time_between_measurements`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PeriodSemantics {
+    /// The wait after every measurement, a measurement starts every `measurement_time` + `time_between_measurements`
+    Gap,
+    /// The whole period, the time spent measuring is subtracted from the wait
+    Total,
+}
+
+/// Actions that can be taken once a measurement cycle hangs
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CycleTimeoutAction {
+    /// Only log an error once per hanging cycle
+    Log,
+    /// Log an error and exit with status 1, so a supervisor can restart the logger
+    Abort,
+}
+
+/// Secondary sort keys for processes with the same usage
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TieBreak {
+    /// Lowest PID first
+    Pid,
+    /// Alphabetically by name, then by PID
+    Name,
+}
+
+/// Number of processes to show, fixed or scaling with the number of processes
+#[derive(Clone, Copy, Debug)]
+enum ProcessCount {
+    Count(usize),
+    /// Percent of all processes, rounded up
+    Percent(f32),
+}
+
+impl ProcessCount {
+    /// Resolve against the number of processes measured
+    fn of(&self, process_count: usize) -> usize {
+        match self {
+            ProcessCount::Count(count) => *count,
+            ProcessCount::Percent(percent) => {
+                (process_count as f32 * percent / 100.0).ceil() as usize
+            }
+        }
     }
+}
 
-    // Prepend ISO timestamp to every line
-    let pre_text = format!("{} | ", get_iso_time());
-    let processed_message = format!("\n{}\n", message)
-        .split('\n')
-        .map(|m| format!("{}{}", pre_text, m))
-        .collect::<Vec<String>>()
-        .join("\n");
+/// Separators between the integer and fractional digits of human readable numbers
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum DecimalSeparator {
+    #[value(name = ".")]
+    Point,
+    /// For spreadsheets and readers expecting European number formats
+    #[value(name = ",")]
+    Comma,
+}
 
-    // Open file in append mode
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(file_path.as_ref().unwrap())
-        .unwrap();
+/// Units of epoch timestamps
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum EpochUnit {
+    Seconds,
+    Milliseconds,
+}
+
+/// Units of disk rates
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum IoUnit {
+    /// The largest unit keeping the value at 1 or above
+    Auto,
+    Kib,
+    Mib,
+}
+
+/// Alignments of a numeric table column
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Alignment {
+    Left,
+    Right,
+    /// Line up the decimal points
+    Decimal,
+}
+
+/// Formats `list_processes` can print in
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ListFormat {
+    Table,
+    Json,
+}
 
-    writeln!(file, "{}", processed_message).unwrap();
+/// Sources the total CPU usage can be calculated from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TotalSource {
+    /// Sum of the usage of all measured processes
+    Processes,
+    /// Global CPU usage reported by the system
+    Global,
 }
 
-/// Get current DateTime as an ISO 8601 formatted string
-fn get_iso_time() -> String {
-    // 2023-03-08T21:19:47.101382300+01:00
-    format!("{}", Local::now().format("%Y-%m-%dT%H:%M:%S%.9f%:z"))
+/// Number systems PIDs can be shown in
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PidFormat {
+    /// Decimal
+    Dec,
+    /// Hexadecimal with a `0x` prefix (as in some kernel and driver logs)
+    Hex,
+}
+
+/// Where the shown name of a process comes from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum NameSource {
+    /// Name reported by the system (truncated to 15 characters on Linux)
+    Comm,
+    /// File name of the executable, falling back to the first command line argument and the reported name
+    Full,
+}
+
+/// Scales the total CPU usage can be expressed in
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum TotalScale {
+    /// 0-100% of the total capacity of all cores
+    Normalized,
+    /// 0-(100 * cores)%, 100% per fully used core
+    Absolute,
+}
+
+/// Actions that can be taken against a process exceeding `process_log_threshold`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum BreachAction {
+    /// Only log the breach
+    None,
+    /// Send SIGSTOP
+    Stop,
+    /// Send SIGCONT
+    Cont,
+    /// Send SIGKILL
+    Kill,
+}
+
+impl BreachAction {
+    /// Signal to send for this action (if any)
+    fn signal(&self) -> Option<Signal> {
+        match self {
+            BreachAction::None => None,
+            BreachAction::Stop => Some(Signal::Stop),
+            BreachAction::Cont => Some(Signal::Continue),
+            BreachAction::Kill => Some(Signal::Kill),
+        }
+    }
+}
+
+/// Average total CPU usage learned during `baseline_window`
+struct Baseline {
+    window: Duration,
+    started: Instant,
+    /// The first measurement is skipped, the counters before it weren't refreshed by the monitor yet
+    primed: bool,
+    samples: u32,
+    sum: f32,
+    learned: Option<f32>,
+}
+
+impl Baseline {
+    fn new(window: Duration, now: Instant) -> Self {
+        Baseline {
+            window,
+            started: now,
+            primed: false,
+            samples: 0,
+            sum: 0.0,
+            learned: None,
+        }
+    }
+
+    /// Record a measurement while still learning, returns true if learning finished with it
+    fn update(&mut self, total_cpu_usage: f32, now: Instant) -> bool {
+        if self.learned.is_some() {
+            return false;
+        }
+        if !self.primed {
+            self.primed = true;
+            return false;
+        }
+
+        self.samples += 1;
+        self.sum += total_cpu_usage;
+
+        if now.duration_since(self.started) < self.window {
+            return false;
+        }
+
+        self.learned = Some(self.sum / self.samples as f32);
+        true
+    }
+
+    /// Total threshold once learned, at least [BASELINE_MIN_THRESHOLD]
+    fn threshold(&self, multiplier: f32) -> Option<f32> {
+        self.learned
+            .map(|learned| (learned * multiplier).max(BASELINE_MIN_THRESHOLD))
+    }
+}
+
+/// Total CPU usage of the current hour for `hourly_summary`
+struct HourlySummary {
+    /// Local hour being summarized (e.g. `2023-03-08T21:00`)
+    hour: String,
+    totals: Vec<f32>,
+    /// Summed up usage per process name
+    process_usage: HashMap<String, f32>,
+}
+
+impl HourlySummary {
+    fn new(hour: String) -> Self {
+        HourlySummary {
+            hour,
+            totals: Vec::new(),
+            process_usage: HashMap::new(),
+        }
+    }
+
+    /// Local hour a summary of measurements taken at `time` is kept for
+    fn hour_of(time: &DateTime<Local>) -> String {
+        time.format("%Y-%m-%dT%H:00").to_string()
+    }
+
+    fn record(&mut self, total_cpu_usage: f32, cpu_stats: &CPUStats) {
+        self.totals.push(total_cpu_usage);
+        for p in &cpu_stats.processes {
+            *self.process_usage.entry(p.name.to_string()).or_default() += p.got_cpu_usage;
+        }
+    }
+
+    /// Formats the summary as a single line, [None] if nothing was recorded
+    fn format(&self) -> Option<String> {
+        if self.totals.is_empty() {
+            return None;
+        }
+
+        let mut totals = self.totals.clone();
+        totals.sort_by(f32::total_cmp);
+        let average = totals.iter().sum::<f32>() / totals.len() as f32;
+        let peak = totals[totals.len() - 1];
+        let p95 = totals[((totals.len() as f32 * 0.95).ceil() as usize).max(1) - 1];
+
+        // The busiest process used the most CPU over the whole hour
+        let busiest = self
+            .process_usage
+            .iter()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map_or(String::from("-"), |(name, usage)| {
+                format!(
+                    "'{}' ({:.2}% avg)",
+                    name,
+                    Decimal(usage / totals.len() as f32)
+                )
+            });
+
+        Some(format!(
+            "Hourly summary {} ({} measurements) -> avg: {:.2}% peak: {:.2}% p95: {:.2}% busiest: {}",
+            self.hour,
+            totals.len(),
+            Decimal(average),
+            Decimal(peak),
+            Decimal(p95),
+            busiest,
+        ))
+    }
+}
+
+/// Totals over the whole run, written by `summary_json`
+struct RunSummary {
+    started: Instant,
+    total_breaches: u64,
+    process_breaches: u64,
+    peak_total: Option<f32>,
+    /// Name, PID and usage of the highest single process measurement
+    peak_process: Option<(String, Pid, f32)>,
+}
+
+impl RunSummary {
+    fn new(started: Instant) -> Self {
+        RunSummary {
+            started,
+            total_breaches: 0,
+            process_breaches: 0,
+            peak_total: None,
+            peak_process: None,
+        }
+    }
+
+    /// Track the peaks of a measurement with processes sorted by usage
+    fn record(&mut self, total_cpu_usage: f32, cpu_stats: &CPUStats) {
+        if self.peak_total.is_none_or(|peak| total_cpu_usage > peak) {
+            self.peak_total = Some(total_cpu_usage);
+        }
+        if let Some(top) = cpu_stats.processes.first() {
+            if self
+                .peak_process
+                .as_ref()
+                .is_none_or(|(_, _, usage)| top.got_cpu_usage > *usage)
+            {
+                self.peak_process =
+                    Some((top.name.to_string(), top.process.pid(), top.got_cpu_usage));
+            }
+        }
+    }
+
+    fn to_json(
+        &self,
+        measurements: u64,
+        now: Instant,
+        ended_at: &DateTime<Local>,
+        instance: &str,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "schema_version": JSON_SCHEMA_VERSION,
+            "timestamp": json_timestamp(ended_at),
+            "timestamp_iso": json_iso_timestamp(ended_at),
+            "instance": instance,
+            "measurements": measurements,
+            "total_breaches": self.total_breaches,
+            "process_breaches": self.process_breaches,
+            "peak_total_cpu_usage": self.peak_total,
+            "peak_process": self.peak_process.as_ref().map(|(name, pid, usage)| {
+                serde_json::json!({ "name": name, "pid": pid.as_u32(), "cpu_usage": usage })
+            }),
+            "duration_secs": now.duration_since(self.started).as_secs_f64(),
+        })
+    }
+}
+
+/// Number of processes listed in an incident report
+const INCIDENT_TOP_PROCESSES: usize = 10;
+/// Most points in the timeline of an incident report, longer incidents are sampled evenly
+const INCIDENT_TIMELINE_POINTS: usize = 120;
+
+/// Name, PID and usage of the busiest process of a measurement
+type TopProcess = (String, Pid, f32);
+
+/// A total breach through its recovery, written to `incident_dir` once it recovered
+struct IncidentReport {
+    started_at: DateTime<Local>,
+    threshold: f32,
+    peak_total: f32,
+    /// Summed up intervals of the measurements during the incident
+    measured_secs: f64,
+    /// Name and CPU usage times interval seconds of every process seen during the incident
+    process_usage: HashMap<ProcessKey, (String, f64)>,
+    /// Time, total and top process of every measurement
+    timeline: Vec<(DateTime<Local>, f32, Option<TopProcess>)>,
+}
+
+impl IncidentReport {
+    fn new(started_at: DateTime<Local>, threshold: f32) -> Self {
+        IncidentReport {
+            started_at,
+            threshold,
+            peak_total: 0.0,
+            measured_secs: 0.0,
+            process_usage: HashMap::new(),
+            timeline: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, total_cpu_usage: f32, cpu_stats: &CPUStats, measured_at: DateTime<Local>) {
+        let interval = cpu_stats.interval.as_secs_f64();
+        self.peak_total = self.peak_total.max(total_cpu_usage);
+        self.measured_secs += interval;
+        for p in &cpu_stats.processes {
+            self.process_usage
+                .entry(p.key())
+                .or_insert_with(|| (p.name.to_string(), 0.0))
+                .1 += p.got_cpu_usage as f64 * interval;
+        }
+
+        let top = cpu_stats
+            .processes
+            .iter()
+            .max_by(|a, b| a.got_cpu_usage.total_cmp(&b.got_cpu_usage))
+            .map(|p| (p.name.to_string(), p.process.pid(), p.got_cpu_usage));
+        self.timeline.push((measured_at, total_cpu_usage, top));
+    }
+
+    /// File name unique per incident, sorting by start time
+    fn file_name(&self, incident: Option<u64>) -> String {
+        let started = self.started_at.format("%Y%m%d-%H%M%S-%3f");
+        match incident {
+            Some(id) => format!("incident-{}-{}.json", started, id),
+            None => format!("incident-{}.json", started),
+        }
+    }
+
+    fn to_json(
+        &self,
+        incident: Option<u64>,
+        ended_at: &DateTime<Local>,
+        instance: &str,
+    ) -> serde_json::Value {
+        // Processes most responsible by their CPU time during the incident
+        let mut processes = self
+            .process_usage
+            .iter()
+            .collect::<Vec<(&ProcessKey, &(String, f64))>>();
+        processes.sort_by(|a, b| b.1 .1.total_cmp(&a.1 .1));
+        let processes = processes
+            .into_iter()
+            .filter(|(_, (_, usage_secs))| *usage_secs > 0.0)
+            .take(INCIDENT_TOP_PROCESSES)
+            .map(|((pid, _), (name, usage_secs))| {
+                serde_json::json!({
+                    "name": name,
+                    "pid": pid.as_u32(),
+                    "cpu_percent_secs": usage_secs,
+                    "avg_cpu_usage": usage_secs / self.measured_secs.max(f64::EPSILON),
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+
+        // Every nth measurement, always including the last one
+        let step = self
+            .timeline
+            .len()
+            .div_ceil(INCIDENT_TIMELINE_POINTS)
+            .max(1);
+        let timeline = self
+            .timeline
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| i % step == 0 || *i == self.timeline.len() - 1)
+            .map(|(_, (time, total, top))| {
+                serde_json::json!({
+                    "timestamp": json_timestamp(time),
+                    "total_cpu_usage": total,
+                    "top_process": top.as_ref().map(|(name, pid, usage)| {
+                        serde_json::json!({ "name": name, "pid": pid.as_u32(), "cpu_usage": usage })
+                    }),
+                })
+            })
+            .collect::<Vec<serde_json::Value>>();
+
+        serde_json::json!({
+            "schema_version": JSON_SCHEMA_VERSION,
+            "timestamp": json_timestamp(ended_at),
+            "timestamp_iso": json_iso_timestamp(ended_at),
+            "instance": instance,
+            "incident": incident,
+            "started_at": json_timestamp(&self.started_at),
+            "ended_at": json_timestamp(ended_at),
+            "duration_secs": (*ended_at - self.started_at).num_milliseconds() as f64 / 1000.0,
+            "threshold": self.threshold,
+            "peak_total_cpu_usage": self.peak_total,
+            "measurements": self.timeline.len(),
+            "processes": processes,
+            "timeline": timeline,
+        })
+    }
+
+    /// Write the report into `dir`, creating it if missing
+    fn write(
+        &self,
+        dir: &str,
+        incident: Option<u64>,
+        ended_at: &DateTime<Local>,
+        instance: &str,
+    ) -> std::io::Result<()> {
+        fs::create_dir_all(dir)?;
+        write_atomic(
+            &Path::new(dir).join(self.file_name(incident)),
+            &format!("{:#}\n", self.to_json(incident, ended_at, instance)),
+        )
+    }
+}
+
+/// Distribution of total CPU usage over the run in 10% wide buckets
+struct Histogram {
+    buckets: [u32; 10],
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram { buckets: [0; 10] }
+    }
+
+    /// Add a measurement to its bucket (100% and above go into the last bucket)
+    fn record(&mut self, total_cpu_usage: f32) {
+        let bucket = ((total_cpu_usage / 10.0) as usize).min(self.buckets.len() - 1);
+        self.buckets[bucket] += 1;
+    }
+
+    /// Formats the histogram as one bar per bucket with the share of time spent in it
+    fn format(&self) -> String {
+        let measurements: u32 = self.buckets.iter().sum();
+
+        let bars = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                let share = match measurements {
+                    0 => 0.0,
+                    _ => *count as f32 / measurements as f32,
+                };
+
+                format!(
+                    "| {: >8} | {: <50} | {: >7} |",
+                    format!("{}-{}%", i * 10, (i + 1) * 10),
+                    "#".repeat((share * 50.0).round() as usize),
+                    format!("{:.2} %", Decimal(share * 100.0)),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        format!(
+            "{header}\n{bars}\n{divider}",
+            header = format_args!(
+                "{:-^74}",
+                format!("Total CPU usage ({} measurements)", measurements)
+            ),
+            divider = format_args!("{:-^74}", ""),
+        )
+    }
+}
+
+/// Suppresses identical log messages within a window
+struct Deduplicator {
+    window: Duration,
+    /// Hash of the normalized message -> last logged occurrence
+    seen: HashMap<u64, DedupEntry>,
+}
+
+/// Last logged occurrence of a deduplicated message
+struct DedupEntry {
+    logged_at: Instant,
+    message: String,
+    suppressed: u32,
+}
+
+impl Deduplicator {
+    fn new(window: Duration) -> Self {
+        Deduplicator {
+            window,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Check whether a message should be logged, `key` is the normalized message text
+    fn should_log(&mut self, key: &str, message: &str, now: Instant) -> bool {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        match self.seen.get_mut(&hash) {
+            Some(entry) if now.duration_since(entry.logged_at) < self.window => {
+                entry.suppressed += 1;
+                false
+            }
+            _ => {
+                self.seen.insert(
+                    hash,
+                    DedupEntry {
+                        logged_at: now,
+                        message: message.to_string(),
+                        suppressed: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Remove messages whose window passed, returning a summary for each one that was suppressed
+    fn expire(&mut self, now: Instant) -> Vec<String> {
+        let mut summaries = Vec::new();
+
+        self.seen.retain(|_, entry| {
+            if now.duration_since(entry.logged_at) < self.window {
+                return true;
+            }
+
+            if entry.suppressed > 0 {
+                summaries.push(format!(
+                    "Repeated {} times in the last {}s -> {}",
+                    entry.suppressed,
+                    self.window.as_secs(),
+                    entry.message,
+                ));
+            }
+            false
+        });
+
+        summaries
+    }
+}
+
+/// Log file that is kept open for the whole run
+struct LogFile {
+    target: RefCell<LogTarget>,
+    sync: bool,
+    format: SinkFormat,
+    /// Taken once logging switched to it
+    fallback: RefCell<Option<String>>,
+    failed_writes: Cell<u32>,
+    /// Only set if `log_files` is used
+    slots: Option<LogSlots>,
+    /// Only set if `max_log_size` is used, taken once logging switched to the fallback
+    rotation: RefCell<Option<LogRotation>>,
+    /// Put before every line, only set if `tag_log_lines` is used
+    line_prefix: Option<String>,
+    /// Time of the last successful write, for `heartbeat`
+    last_written: Cell<Instant>,
+}
+
+/// Fixed set of log files written to in turn, for a bounded disk usage without renaming files
+struct LogSlots {
+    /// Slot paths in writing order
+    paths: Vec<String>,
+    interval: Duration,
+    /// Slot currently written to
+    index: Cell<usize>,
+    switched_at: Cell<Instant>,
+    /// Replace finished slots by `<slot>.gz`
+    compress: bool,
+}
+
+impl LogSlots {
+    /// Slots for `file_path`, the slot number is put before the extension
+    fn new(
+        file_path: &str,
+        count: usize,
+        interval: Duration,
+        compress: bool,
+        now: Instant,
+    ) -> Self {
+        let path = Path::new(file_path);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let paths = (0..count)
+            .map(|i| {
+                let name = match path.extension() {
+                    Some(extension) => format!("{}.{}.{}", stem, i, extension.to_string_lossy()),
+                    None => format!("{}.{}", stem, i),
+                };
+                path.with_file_name(name).to_string_lossy().into_owned()
+            })
+            .collect::<Vec<String>>();
+        // Continue after the slot written last instead of overwriting it on a restart
+        let index = paths
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| Some((i, fs::metadata(p).ok()?.modified().ok()?)))
+            .max_by_key(|(_, modified)| *modified)
+            .map_or(0, |(i, _)| (i + 1) % count);
+
+        LogSlots {
+            paths,
+            interval,
+            index: Cell::new(index),
+            switched_at: Cell::new(now),
+            compress,
+        }
+    }
+
+    /// Truncate and open the current slot
+    fn open_current(&self) -> std::io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.paths[self.index.get()])
+    }
+}
+
+/// Size based rotation of the log file by renaming, the numbered files are shifted up
+struct LogRotation {
+    path: String,
+    max_bytes: u64,
+    /// Number of rotated files kept (`<path>.1` to `<path>.N`)
+    max_files: u64,
+    /// Replace `<path>.1` by `<path>.1.gz` after rotating
+    compress: bool,
+    /// Compression of the previously rotated file
+    compressing: RefCell<Option<std::thread::JoinHandle<()>>>,
+}
+
+impl LogRotation {
+    /// Shift `<path>.N-1` to `<path>.N` and so on and move the log file to `<path>.1`, then open a new log file
+    fn rotate(&self) -> std::io::Result<File> {
+        // The file being compressed is about to be renamed
+        if let Some(compressing) = self.compressing.borrow_mut().take() {
+            let _ = compressing.join();
+        }
+
+        let rotated = |i: u64| format!("{}.{}", self.path, i);
+        for i in (1..=self.max_files).rev() {
+            let from = match i {
+                1 => self.path.clone(),
+                _ => rotated(i - 1),
+            };
+            // Rotated files are shifted whether they were compressed or not, the log file never is
+            let extensions: &[&str] = match i {
+                1 => &[""],
+                _ => &["", ".gz"],
+            };
+            for extension in extensions {
+                let (from, to) = (from.clone() + extension, rotated(i) + extension);
+                if Path::new(&from).exists() {
+                    // Renaming doesn't replace an existing file on every platform
+                    let _ = fs::remove_file(&to);
+                    fs::rename(&from, to)?;
+                }
+            }
+        }
+
+        // Compressing a large file would stall measuring
+        if self.compress {
+            let rotated = rotated(1);
+            *self.compressing.borrow_mut() = Some(std::thread::spawn(move || {
+                if let Err(e) = gzip::compress_file(&rotated) {
+                    warn(
+                        "log-compress",
+                        &format!("failed to compress log file {}: {}", rotated, e),
+                    );
+                }
+            }));
+        }
+
+        open_append(&self.path)
+    }
+}
+
+impl Drop for LogRotation {
+    /// Don't leave a half written archive behind when stopping
+    fn drop(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+    }
+}
+
+/// Where log lines are written to
+enum LogTarget {
+    File(File),
+    Stdout,
+}
+
+/// Consecutive failed writes after which logging switches to `log_fallback`
+const LOG_FAILURES_BEFORE_FALLBACK: u32 = 3;
+
+impl LogFile {
+    /// Open a log file in append mode, creating it if missing
+    fn open(
+        file_path: &str,
+        sync: bool,
+        format: SinkFormat,
+        fallback: Option<String>,
+        slots: Option<LogSlots>,
+        now: Instant,
+    ) -> Self {
+        let file = match &slots {
+            Some(slots) => slots.open_current(),
+            None => open_append(file_path),
+        }
+        .expect("failed to open log file");
+
+        LogFile {
+            target: RefCell::new(LogTarget::File(file)),
+            sync,
+            format,
+            fallback: RefCell::new(fallback),
+            failed_writes: Cell::new(0),
+            slots,
+            rotation: RefCell::new(None),
+            line_prefix: None,
+            last_written: Cell::new(now),
+        }
+    }
+
+    /// Switch to the next of `log_files` once `log_switch_interval` passed
+    fn switch_slot(&self, now: Instant) {
+        let Some(slots) = &self.slots else {
+            return;
+        };
+        if now.duration_since(slots.switched_at.get()) < slots.interval {
+            return;
+        }
+
+        let previous = slots.paths[slots.index.get()].clone();
+        slots.index.set((slots.index.get() + 1) % slots.paths.len());
+        slots.switched_at.set(now);
+        match slots.open_current() {
+            Ok(file) => {
+                *self.target.borrow_mut() = LogTarget::File(file);
+                // Compressing a large slot would stall measuring
+                if slots.compress {
+                    std::thread::spawn(move || {
+                        if let Err(e) = gzip::compress_file(&previous) {
+                            warn(
+                                "log-compress",
+                                &format!("failed to compress log file {}: {}", previous, e),
+                            );
+                        }
+                    });
+                }
+            }
+            // Keep writing to the previous slot
+            Err(e) => warn(
+                "log-switch",
+                &format!(
+                    "failed to switch to log file {}: {}",
+                    slots.paths[slots.index.get()],
+                    e
+                ),
+            ),
+        }
+    }
+
+    /// Rotate the log file if writing `incoming` bytes would grow it beyond `max_log_size`
+    fn rotate_if_full(&self, incoming: usize) {
+        let rotation = self.rotation.borrow();
+        let Some(rotation) = rotation.as_ref() else {
+            return;
+        };
+        let mut target = self.target.borrow_mut();
+        let LogTarget::File(file) = &*target else {
+            return;
+        };
+        // A message larger than the limit still gets a file of its own
+        let size = file.metadata().map_or(0, |m| m.len());
+        if size == 0 || size + incoming as u64 <= rotation.max_bytes {
+            return;
+        }
+
+        // Open files can't be renamed on Windows
+        *target = LogTarget::Stdout;
+        match rotation.rotate() {
+            Ok(file) => *target = LogTarget::File(file),
+            Err(e) => {
+                warn(
+                    "log-rotate",
+                    &format!("failed to rotate log file {}: {}", rotation.path, e),
+                );
+                match open_append(&rotation.path) {
+                    Ok(file) => *target = LogTarget::File(file),
+                    Err(e) => eprintln!("Failed to reopen log file {}: {}", rotation.path, e),
+                }
+            }
+        }
+    }
+
+    /// Count a failed write, switching to the fallback once writes failed repeatedly.
+    /// Returns whether logging switched
+    fn write_failed(&self) -> bool {
+        self.failed_writes.set(self.failed_writes.get() + 1);
+        if self.failed_writes.get() < LOG_FAILURES_BEFORE_FALLBACK {
+            return false;
+        }
+        let Some(fallback) = self.fallback.borrow_mut().take() else {
+            return false;
+        };
+
+        let target = match fallback.as_str() {
+            "-" => LogTarget::Stdout,
+            path => match open_append(path) {
+                Ok(file) => LogTarget::File(file),
+                Err(e) => {
+                    eprintln!("Failed to open fallback log file {}: {}", path, e);
+                    return false;
+                }
+            },
+        };
+        eprintln!(
+            "Log file writes failed {} times in a row, switching to {}",
+            self.failed_writes.get(),
+            match fallback.as_str() {
+                "-" => "stdout",
+                path => path,
+            }
+        );
+        *self.target.borrow_mut() = target;
+        self.failed_writes.set(0);
+        // The fallback is never rotated
+        self.rotation.borrow_mut().take();
+
+        true
+    }
+}
+
+/// Open a file in append mode, creating it if missing
+fn open_append(file_path: &str) -> std::io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(file_path)
+}
+
+/// Replace a file in one step by writing `<name>.tmp` next to it and renaming that over it,
+/// so a reader (or a crash) never sees a partially written file
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Identifies a process across measurements (PIDs get recycled, the start time disambiguates them)
+type ProcessKey = (Pid, u64);
+
+/// Consecutive measurements a process exceeded the threshold for including this one, a recycled PID starts anew
+fn count_breach(breach_counts: &HashMap<ProcessKey, u32>, key: ProcessKey) -> u32 {
+    breach_counts.get(&key).unwrap_or(&0) + 1
+}
+
+/// CPU usage stats for a process
+#[derive(Clone)]
+struct ProcessStats<'a> {
+    got_cpu_usage: f32,
+    process: &'a dyn ProcessInfo,
+    /// Name according to `name_source`
+    name: &'a str,
+    /// Only resolved if the user column is shown
+    user_name: Option<&'a str>,
+    /// CPU usage minus the usage of processes with the same name in `baseline_file`
+    baseline_delta: Option<f32>,
+    /// Unsmoothed CPU usage, only set if `process_smoothing` or `average_over` is used
+    raw_cpu_usage: Option<f32>,
+    /// Percent of the total CPU usage caused by this process
+    share_of_total: f32,
+    /// Mean unsmoothed CPU usage since the process was first measured
+    avg_cpu_usage: f32,
+    /// Rough share of the package power in watts, only set if `show_power` is used
+    power_estimate: Option<f32>,
+    /// Started less than `settle_delay` ago
+    settling: bool,
+    /// Voluntary and involuntary context switches during the measurement, only set if `show_ctxsw` is used
+    context_switches: Option<(u64, u64)>,
+    /// Minor and major page faults during the measurement, only set if `show_faults` is used
+    page_faults: Option<(u64, u64)>,
+    /// Bytes read and written per second during the measurement, only set if `show_disk` is used
+    disk_rates: Option<(f32, f32)>,
+    /// Nice value and real-time priority (if scheduled real-time), only set if `show_priority` is used
+    priority: Option<(i32, Option<u32>)>,
+    /// Open file descriptors, only set if `show_fds` is used and the process is accessible
+    fd_count: Option<usize>,
+    /// Listed in `pin`, shown above all other processes
+    pinned: bool,
+    /// Stats couldn't be read, only set if `mark_inaccessible` is used
+    inaccessible: bool,
+    /// Number of processes with this name merged into this one by `collapse_breach_list` or `aggregate_by_name`
+    merged: usize,
+    /// Resident memory in bytes, summed up over the merged processes
+    memory: u64,
+}
+
+impl ProcessStats<'_> {
+    /// Key to track this process across measurements with
+    fn key(&self) -> ProcessKey {
+        (self.process.pid(), self.process.start_time())
+    }
+
+    /// Value of a metric `busy_when` compares
+    fn metric(&self, metric: condition::Metric) -> f32 {
+        match metric {
+            condition::Metric::Cpu => self.got_cpu_usage,
+            condition::Metric::Mem => self.memory as f32 / 1_000_000.0,
+            condition::Metric::Share => self.share_of_total,
+            condition::Metric::Avg => self.avg_cpu_usage,
+            condition::Metric::Runtime => self.process.run_time() as f32,
+        }
+    }
+}
+
+impl<'a> From<&'a dyn ProcessInfo> for ProcessStats<'a> {
+    fn from(prcs: &'a dyn ProcessInfo) -> Self {
+        ProcessStats {
+            got_cpu_usage: 0.0,
+            process: prcs,
+            name: prcs.name(),
+            user_name: None,
+            baseline_delta: None,
+            raw_cpu_usage: None,
+            share_of_total: 0.0,
+            avg_cpu_usage: 0.0,
+            power_estimate: None,
+            settling: false,
+            context_switches: None,
+            page_faults: None,
+            disk_rates: None,
+            priority: None,
+            fd_count: None,
+            pinned: false,
+            inaccessible: false,
+            merged: 1,
+            memory: prcs.memory(),
+        }
+    }
+}
+
+/// CPU usage stats for the whole system (including processes)
+struct CPUStats<'a> {
+    processes: Vec<ProcessStats<'a>>,
+    /// Actual time spent measuring (may overrun `measurement_time` under load)
+    interval: Duration,
+    /// Only available on some platforms
+    breakdown: Option<CpuBreakdown>,
+    /// Average CPU package power during the measurement in watts, only available with RAPL
+    power: Option<f32>,
+    /// Bytes swapped in and out per second during the measurement, only set if `show_swap_activity` is used
+    swap_rates: Option<(f32, f32)>,
+    /// File descriptors allocated system wide, only set if `show_fds` is used
+    fd_total: Option<u64>,
+}
+
+/// Share of global CPU time spent waiting instead of working in percent
+#[derive(Clone, Copy)]
+struct CpuBreakdown {
+    /// Idle while waiting for I/O (disk bottleneck)
+    iowait: f32,
+    /// Taken by the hypervisor for other VMs (noisy neighbor)
+    steal: f32,
+}
+
+/// Cumulative global CPU time counters from `/proc/stat`
+#[cfg(target_os = "linux")]
+struct CpuTimes {
+    total: u64,
+    iowait: u64,
+    steal: u64,
+}
+
+/// Cumulative energy counters of all CPU packages from RAPL (`/sys/class/powercap`)
+#[cfg(target_os = "linux")]
+struct EnergyCounters {
+    /// Energy in microjoules and the value it wraps around at, per package
+    packages: Vec<(u64, u64)>,
+}
+
+#[cfg(target_os = "linux")]
+impl EnergyCounters {
+    /// Average power in watts drawn since an earlier reading
+    fn power_since(&self, start: &EnergyCounters, interval: Duration) -> Option<f32> {
+        if self.packages.len() != start.packages.len() || interval.is_zero() {
+            return None;
+        }
+
+        let microjoules: u64 = self
+            .packages
+            .iter()
+            .zip(&start.packages)
+            .map(|((end, range), (start, _))| match end >= start {
+                true => end - start,
+                // The counter wrapped around
+                false => range - start + end,
+            })
+            .sum();
+
+        Some(microjoules as f32 / 1_000_000.0 / interval.as_secs_f32())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl CpuTimes {
+    /// Breakdown of the CPU time spent since an earlier reading
+    fn breakdown_since(&self, start: &CpuTimes) -> Option<CpuBreakdown> {
+        let total = self.total.checked_sub(start.total).filter(|t| *t > 0)? as f32;
+
+        Some(CpuBreakdown {
+            iowait: self.iowait.saturating_sub(start.iowait) as f32 / total * 100.0,
+            steal: self.steal.saturating_sub(start.steal) as f32 / total * 100.0,
+        })
+    }
+}
+
+/// Convert [ProcessSource::processes] to [CPUStats]
+impl<'a> From<Vec<&'a dyn ProcessInfo>> for CPUStats<'a> {
+    fn from(value: Vec<&'a dyn ProcessInfo>) -> Self {
+        CPUStats {
+            processes: value
+                .into_iter()
+                .map(ProcessStats::from)
+                .collect::<Vec<ProcessStats>>(),
+            interval: Duration::ZERO,
+            breakdown: None,
+            power: None,
+            swap_rates: None,
+            fd_total: None,
+        }
+    }
+}
+
+fn main() {
+    //* Parse args
+    let args = Args::parse();
+
+    if args.print_schema {
+        println!("{:#}", json_schema());
+        return;
+    }
+
+    if let Some(format) = args.list_processes {
+        list_processes(&mut SysinfoSource::new(false), format);
+        return;
+    }
+
+    if let Some(Command::Diff { before, after, by }) = &args.command {
+        match diff::diff_files(before, after, *by) {
+            Ok(diff) => println!("{}", diff),
+            Err(e) => {
+                eprintln!("Failed to compare snapshots: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if args.self_test {
+        let passed = self_test(&mut SysinfoSource::new(true), &SystemClock);
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
+    #[cfg(windows)]
+    if let Some(action) = args.service {
+        service::handle(action).expect("failed to handle service action");
+        return;
+    }
+
+    // Stop gracefully on Ctrl+C / SIGTERM
+    let running = Arc::new(AtomicBool::new(true));
+    {
+        let running = running.clone();
+        ctrlc::set_handler(move || running.store(false, Ordering::SeqCst))
+            .expect("failed to set signal handler");
+    }
+
+    #[cfg(unix)]
+    let pager = args.pager.then(|| start_pager(running.clone())).flatten();
+
+    monitor(&args, &running);
+
+    // Let the pager show the end of the output until it is quit
+    #[cfg(unix)]
+    if let Some(pager) = pager {
+        close_stdout();
+        let _ = pager.join();
+    }
+}
+
+/// Pipe stdout through `$PAGER` on a background thread that stops monitoring once the pager is quit,
+/// [None] if stdout isn't a terminal or the pager couldn't be started
+#[cfg(unix)]
+fn start_pager(running: Arc<AtomicBool>) -> Option<std::thread::JoinHandle<()>> {
+    use std::os::unix::io::AsRawFd;
+    use std::process::{Command, Stdio};
+
+    // Nothing to page interactively when writing to a file or pipe
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|p| !p.trim().is_empty())
+        .unwrap_or(String::from("less -R"));
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(&pager)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("Failed to start pager '{}': {}", pager, e);
+            return None;
+        }
+    };
+
+    // Everything printed from now on goes to the pager
+    let stdin = child.stdin.take().expect("pager stdin is piped");
+    unsafe { libc::dup2(stdin.as_raw_fd(), libc::STDOUT_FILENO) };
+    drop(stdin);
+
+    Some(std::thread::spawn(move || {
+        let _ = child.wait();
+        // Printing to the closed pipe would fail
+        close_stdout();
+        running.store(false, Ordering::SeqCst);
+    }))
+}
+
+/// Ways of clearing the screen between CLI tables, in the order they are tried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ClearMethod {
+    /// The `clearscreen` crate, which knows the quirks of many terminals
+    Library,
+    /// Raw `ESC[2J ESC[H`, understood by most terminals
+    Ansi,
+    /// Don't clear, only separate the tables by a blank line
+    Scroll,
+}
+
+/// Clear the screen, falling back to the next [ClearMethod] (with a warning) whenever one fails
+fn clear_screen(method: &mut ClearMethod) {
+    loop {
+        let result = match method {
+            ClearMethod::Library => clearscreen::clear().map_err(|e| e.to_string()),
+            // Escapes would only end up as garbage in a file or pipe
+            ClearMethod::Ansi if !std::io::stdout().is_terminal() => {
+                Err(String::from("stdout is not a terminal"))
+            }
+            ClearMethod::Ansi => {
+                let mut stdout = std::io::stdout();
+                write!(stdout, "\x1b[2J\x1b[H")
+                    .and_then(|_| stdout.flush())
+                    .map_err(|e| e.to_string())
+            }
+            ClearMethod::Scroll => {
+                println!();
+                return;
+            }
+        };
+        let Err(e) = result else {
+            return;
+        };
+
+        let (kind, next, description) = match method {
+            ClearMethod::Library => ("clear-library", ClearMethod::Ansi, "ANSI escapes"),
+            _ => ("clear-ansi", ClearMethod::Scroll, "scrolling"),
+        };
+        warn(
+            kind,
+            &format!(
+                "failed to clear the screen ({}), falling back to {}",
+                e, description
+            ),
+        );
+        *method = next;
+    }
+}
+
+/// Rows of the terminal stdout is attached to, [None] if it isn't a terminal
+#[cfg(unix)]
+fn terminal_height() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    (result == 0 && size.ws_row > 0).then_some(size.ws_row as usize)
+}
+
+/// Rows of the terminal as exported in `LINES`, [None] if it isn't set
+#[cfg(not(unix))]
+fn terminal_height() -> Option<usize> {
+    std::env::var("LINES").ok()?.parse().ok()
+}
+
+/// Point stdout at `/dev/null`, which ends the input of the pager
+#[cfg(unix)]
+fn close_stdout() {
+    use std::os::unix::io::AsRawFd;
+
+    let _ = std::io::stdout().flush();
+    if let Ok(null) = OpenOptions::new().write(true).open("/dev/null") {
+        unsafe { libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO) };
+    }
+}
+
+/// Measure and log CPU usage of the real system until `running` is cleared
+fn monitor(args: &Args, running: &AtomicBool) {
+    if args.wait_for_process.is_some() && args.pid_file.is_none() && args.watch_port.is_empty() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--wait-for-process needs processes to wait for, given by --pid-file or --watch-port",
+            )
+            .exit();
+    }
+    // Same confirmation as for `--on-process-breach kill`
+    if args.rules_file.as_ref().is_some_and(|rules| rules.kills())
+        && !args.i_understand_this_kills_processes
+    {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "--rules-file has rules with `action = \"kill\"`, confirm with --i-understand-this-kills-processes",
+            )
+            .exit();
+    }
+    if let Some(timeout) = args.cycle_timeout {
+        // Measuring alone takes `measurement_time`, the watchdog would always fire
+        if timeout <= args.measurement_time {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ValueValidation,
+                    format!(
+                        "--cycle-timeout ({}s) must be longer than --measurement-time ({}s)",
+                        timeout, args.measurement_time
+                    ),
+                )
+                .exit();
+        }
+        spawn_cycle_watchdog(
+            Duration::from_secs(timeout),
+            args.on_cycle_timeout,
+            args.log_file.as_ref().map(|target| target.path.clone()),
+        );
+    }
+
+    #[cfg(all(target_os = "linux", feature = "procfs"))]
+    if args.backend == Backend::Procfs {
+        return monitor_with(
+            args,
+            running,
+            &mut procfs::ProcfsSource::new(),
+            &SystemClock,
+        );
+    }
+
+    monitor_with(
+        args,
+        running,
+        &mut SysinfoSource::new(args.minimal),
+        &SystemClock,
+    );
+}
+
+/// Measure and log CPU usage of the processes of `source` until `running` is cleared
+fn monitor_with(
+    args: &Args,
+    running: &AtomicBool,
+    source: &mut dyn ProcessSource,
+    clock: &dyn Clock,
+) {
+    WARN_INTERVAL_SECS.store(args.warn_interval, Ordering::Relaxed);
+    TIMESTAMP_PRECISION.store(args.timestamp_precision, Ordering::Relaxed);
+    PID_HEX.store(args.pid_format == PidFormat::Hex, Ordering::Relaxed);
+    PID_WIDTH.store(args.pid_width, Ordering::Relaxed);
+    HASH_NAMES.store(args.hash_names, Ordering::Relaxed);
+    *IO_UNIT.lock().unwrap() = args.io_unit;
+    *JSON_TIMESTAMP_EPOCH.lock().unwrap() = args.json_timestamp_epoch;
+    JSON_ISO_TIMESTAMP.store(args.json_iso_timestamp, Ordering::Relaxed);
+    DECIMAL_COMMA.store(
+        args.decimal_separator == DecimalSeparator::Comma,
+        Ordering::Relaxed,
+    );
+
+    // A suffix on the log file overrides the global format
+    let log_format = match args.log_file.as_ref().and_then(|target| target.format) {
+        Some(format) => format,
+        None if args.format == OutputFormat::Logfmt => SinkFormat::Logfmt,
+        None if args.format == OutputFormat::Json => SinkFormat::Json,
+        None if args.format == OutputFormat::Csv => SinkFormat::Csv,
+        None if args.compact_log => SinkFormat::Compact,
+        None => SinkFormat::Text,
+    };
+    let export_format = match args
+        .export_on_exit
+        .as_ref()
+        .and_then(|target| target.format)
+    {
+        Some(format) => format,
+        None if args.format == OutputFormat::Logfmt => SinkFormat::Logfmt,
+        None => SinkFormat::Json,
+    };
+
+    let instance = args
+        .instance_tag
+        .clone()
+        .unwrap_or_else(|| source.host_name());
+    let log_file = args.log_file.as_ref().map(|target| {
+        let path = &target.path;
+        LogFile::open(
+            path,
+            args.log_sync,
+            log_format,
+            args.log_fallback.clone(),
+            args.log_files.map(|count| {
+                LogSlots::new(
+                    path,
+                    count as usize,
+                    Duration::from_secs(args.log_switch_interval),
+                    args.compress_rotated,
+                    clock.now(),
+                )
+            }),
+            clock.now(),
+        )
+    });
+    let log_file = log_file.map(|log_file| LogFile {
+        rotation: RefCell::new(args.max_log_size.map(|size| LogRotation {
+            path: args.log_file.as_ref().unwrap().path.clone(),
+            max_bytes: size * 1_000_000,
+            max_files: args.max_log_files,
+            compress: args.compress_rotated,
+            compressing: RefCell::new(None),
+        })),
+        line_prefix: match log_format {
+            _ if !args.tag_log_lines => None,
+            SinkFormat::Logfmt => Some(format!(
+                "{} ",
+                format_logfmt(&[("instance", instance.clone())])
+            )),
+            // A prefix would break JSON and CSV lines
+            SinkFormat::Json | SinkFormat::Csv => None,
+            _ => Some(format!("[{}] ", instance)),
+        },
+        ..log_file
+    });
+    // Record the invocation that produced the log
+    log_to_file(
+        &log_file,
+        &format!("Started as: {}", format_command_line(args.redact)),
+        &clock.local_now(),
+        clock.now(),
+    );
+
+    let baseline_snapshot = args.baseline_file.as_deref().map(read_baseline_file);
+    let mut columns = args.columns.clone();
+    if baseline_snapshot.is_some() && !columns.contains(&Column::Delta) {
+        columns.push(Column::Delta);
+    }
+    if args.show_memory && !columns.contains(&Column::Mem) {
+        columns.push(Column::Mem);
+    }
+    if args.show_power && cfg!(target_os = "linux") && !columns.contains(&Column::Power) {
+        columns.push(Column::Power);
+    }
+    if args.show_priority && cfg!(target_os = "linux") && !columns.contains(&Column::Nice) {
+        columns.push(Column::Nice);
+    }
+    if args.show_fds && cfg!(target_os = "linux") && !columns.contains(&Column::Fds) {
+        columns.push(Column::Fds);
+    }
+    if args.show_affinity && !columns.contains(&Column::Affinity) {
+        columns.push(Column::Affinity);
+    }
+    if args.show_exe_path && !columns.contains(&Column::Exe) {
+        columns.push(Column::Exe);
+    }
+    if args.show_ctxsw && cfg!(target_os = "linux") {
+        for column in [Column::Vcsw, Column::Ivcsw] {
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+    }
+    if args.show_faults && cfg!(target_os = "linux") {
+        for column in [Column::MinorFaults, Column::MajorFaults] {
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+    }
+    if args.show_disk && cfg!(target_os = "linux") {
+        for column in [Column::DiskRead, Column::DiskWrite] {
+            if !columns.contains(&column) {
+                columns.push(column);
+            }
+        }
+    }
+
+    // Rather hide columns than show misleading placeholders if the platform has no data for them
+    let unsupported = unsupported_columns(source, &columns);
+    if !args.minimal && !unsupported.is_empty() {
+        let names = unsupported
+            .iter()
+            .filter_map(|c| c.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        let message = format!("Disabled columns unsupported on this platform: {}", names);
+        eprintln!("Notice: {}", message);
+        log_to_file(&log_file, &message, &clock.local_now(), clock.now());
+        columns.retain(|c| !unsupported.contains(c));
+    }
+
+    // The two intervals add up by default, which is surprising once measuring takes longer than waiting
+    if args.measurement_time > args.time_between_measurements {
+        let message = match args.period_semantics {
+            PeriodSemantics::Gap => format!(
+                "Measurement time ({}s) exceeds the time between measurements ({}s), a measurement starts every {}s (every {}s with --period-semantics total)",
+                args.measurement_time,
+                args.time_between_measurements,
+                args.measurement_time + args.time_between_measurements,
+                args.measurement_time,
+            ),
+            PeriodSemantics::Total => format!(
+                "Measurement time ({}s) exceeds the period ({}s), measuring back to back every {}s",
+                args.measurement_time, args.time_between_measurements, args.measurement_time,
+            ),
+        };
+        eprintln!("Notice: {}", message);
+        log_to_file(&log_file, &message, &clock.local_now(), clock.now());
+    }
+
+    let resolve_users = (!args.minimal && columns.contains(&Column::User)) || args.group_by_user;
+    if resolve_users {
+        source.resolve_users();
+    }
+    let layout = TableLayout {
+        columns,
+        title: args.title.clone(),
+        footer: args.footer.clone(),
+        host: source.host_name(),
+        color_scheme: args.color_scheme,
+        color_threshold: args.process_log_threshold,
+        color_bands: args.color_thresholds.clone(),
+        usage_align: args.usage_align,
+        gauge: args.gauge.then(|| Gauge {
+            max: match args.total_scale {
+                TotalScale::Normalized => 100.0,
+                TotalScale::Absolute => 100.0 * source.core_count() as f32,
+            },
+            threshold: args.total_log_threshold,
+            colored: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }),
+    };
+
+    // Usage is normalized so that 100% means all logical cores are busy, like sysinfo reports it per logical core
+    let core_count = source.core_count();
+    let core_kind = "logical";
+    let cpu_count = core_count as f32;
+
+    #[cfg(unix)]
+    if let Some(fifo_path) = &args.event_fifo {
+        create_event_fifo(fifo_path);
+    }
+
+    #[cfg(feature = "kafka")]
+    let kafka_sink = args.kafka_brokers.as_ref().map(|brokers| {
+        kafka::KafkaSink::new(brokers, args.kafka_topic.as_ref().unwrap(), &instance)
+    });
+
+    let mut graphite_sink = args
+        .graphite
+        .as_ref()
+        .map(|address| graphite::GraphiteSink::new(address, args.address_family));
+
+    #[cfg(all(windows, feature = "eventlog"))]
+    let event_log = args.eventlog.then(eventlog::EventLog::open).flatten();
+    #[cfg(all(target_os = "linux", feature = "journald"))]
+    let journal = args.journald.then(journald::Journal::open).flatten();
+
+    // The monitor may have been started together with the process it watches
+    if let Some(timeout) = args.wait_for_process {
+        log_to_file(
+            &log_file,
+            &format!("Waiting up to {}s for a watched process to start", timeout),
+            &clock.local_now(),
+            clock.now(),
+        );
+        let appeared =
+            wait_for_watched_process(args, source, clock, running, Duration::from_secs(timeout));
+        if !running.load(Ordering::SeqCst) {
+            return;
+        }
+        if !appeared {
+            let message = format!("No watched process started within {}s, exiting", timeout);
+            eprintln!("{}", message);
+            log_to_file(&log_file, &message, &clock.local_now(), clock.now());
+            std::process::exit(1);
+        }
+    }
+
+    // Learn the total threshold first if requested
+    let mut baseline = args
+        .baseline_window
+        .map(|window| Baseline::new(Duration::from_secs(window), clock.now()));
+    if let Some(window) = args.baseline_window {
+        log_to_file(
+            &log_file,
+            &format!("Learning baseline total CPU usage for {}s", window),
+            &clock.local_now(),
+            clock.now(),
+        );
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if args.show_power {
+        warn("power", "power counters are not available on this platform");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.show_swap_activity {
+        warn("swap", "swap activity is not available on this platform");
+    }
+    if args.color_thresholds.len() > 3
+        || args
+            .color_thresholds
+            .windows(2)
+            .any(|pair| pair[0] >= pair[1])
+    {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "--color-thresholds have to be at most three ascending usages, got {}",
+                    args.color_thresholds
+                        .iter()
+                        .map(|t| t.to_string())
+                        .collect::<Vec<String>>()
+                        .join(",")
+                ),
+            )
+            .exit();
+    }
+    // Expand the selected core ranges, checking them against the cores there are
+    let mut cores = args
+        .cores
+        .iter()
+        .flat_map(|&(first, last)| first..=last)
+        .collect::<Vec<usize>>();
+    cores.sort_unstable();
+    cores.dedup();
+    let available_cores = source.cpu_usages().len();
+    if let Some(core) = cores.iter().find(|&&core| core >= available_cores) {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!(
+                    "core {} selected by --cores doesn't exist, there are {} logical cores (0-{})",
+                    core,
+                    available_cores,
+                    available_cores.saturating_sub(1)
+                ),
+            )
+            .exit();
+    }
+
+    // The maximum doesn't change, only the current frequency is read per measurement
+    #[cfg(target_os = "linux")]
+    let max_frequency = args.frequency_aware.then(read_max_frequency).flatten();
+    #[cfg(not(target_os = "linux"))]
+    let max_frequency: Option<u64> = None;
+    if max_frequency.is_some() {
+        source.track_frequency();
+    } else if args.frequency_aware {
+        warn(
+            "frequency",
+            "maximum CPU frequency is not available, usage is not frequency scaled",
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.show_ctxsw {
+        warn(
+            "ctxsw",
+            "context switches are not available on this platform",
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.show_faults {
+        warn("faults", "page faults are not available on this platform");
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.show_priority {
+        warn(
+            "priority",
+            "process priorities are not available on this platform",
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    if args.show_fds {
+        warn(
+            "fds",
+            "file descriptor counts are not available on this platform",
+        );
+    }
+    #[cfg(not(target_os = "linux"))]
+    if !args.watch_port.is_empty() {
+        warn(
+            "watch-port",
+            "listening ports can't be mapped to processes on this platform, watching all processes",
+        );
+    }
+
+    let mut histogram = Histogram::new();
+    let mut hourly_summary = args
+        .hourly_summary
+        .then(|| HourlySummary::new(HourlySummary::hour_of(&clock.local_now())));
+    let mut run_summary = args
+        .summary_json
+        .is_some()
+        .then(|| RunSummary::new(clock.now()));
+    let mut deduplicator = args
+        .dedup_window
+        .map(|window| Deduplicator::new(Duration::from_secs(window)));
+
+    #[cfg(all(feature = "sd-notify", target_os = "linux"))]
+    let (mut notified_ready, watchdog_enabled) =
+        (false, sd_notify::watchdog_enabled(false, &mut 0));
+
+    let mut measurement_count: u64 = 0;
+
+    // Consecutive measurements each process exceeded `process_log_threshold` for
+    let mut breach_counts: HashMap<ProcessKey, u32> = HashMap::new();
+    let mut smoothed_usage: HashMap<ProcessKey, f32> = HashMap::new();
+    #[cfg(target_os = "linux")]
+    let mut smoothed_disk_rates: HashMap<ProcessKey, (f32, f32)> = HashMap::new();
+    let mut usage_windows: HashMap<ProcessKey, VecDeque<(Instant, f32)>> = HashMap::new();
+    // Measurement count and sum of the usage of every process since it was first measured
+    let mut usage_sums: HashMap<ProcessKey, (u32, f32)> = HashMap::new();
+    let mut cli_latched = !args.cli_on_breach;
+    // The CLI table is kept because the total is below `min_total_to_display`
+    let mut display_gated = false;
+    // Downgraded whenever clearing the screen fails
+    let mut clear_method = ClearMethod::Library;
+    // For `redraw_interval`
+    let mut last_redraw: Option<Instant> = None;
+    let mut csv_header_printed = false;
+    let mut total_breached = false;
+    // Processes above `process_log_threshold` in the last measurement
+    let mut alerted: HashSet<ProcessKey> = HashSet::new();
+    // Processes at or above `fd_threshold` in the last measurement
+    let mut fd_alerted: HashSet<ProcessKey> = HashSet::new();
+    // Processes at or above `major_fault_threshold` in the last measurement
+    let mut fault_alerted: HashSet<ProcessKey> = HashSet::new();
+    // Usual usage per process and processes above it by `process_anomaly_multiplier` in the last measurement
+    let mut usage_norms: HashMap<ProcessKey, f32> = HashMap::new();
+    let mut anomalous: HashSet<ProcessKey> = HashSet::new();
+    // Cores above `core_saturation_threshold` -> (since, whether it was logged)
+    let mut saturated_cores: HashMap<usize, (Instant, bool)> = HashMap::new();
+    let alert_grace_until = args
+        .alert_grace
+        .map(|grace| clock.now() + Duration::from_secs(grace));
+    // Whether `pause_file` existed in the last measurement
+    let mut paused = false;
+    // Top process of the last measurement for `log_on_change`, [None] before the first one or without processes
+    let mut top_process: Option<(ProcessKey, String)> = None;
+    let own_pid = sysinfo::get_current_pid().ok().filter(|_| args.report_self);
+    // Last identity of every watched process name, kept while the process is gone
+    let mut watched_identities: HashMap<String, ProcessKey> = HashMap::new();
+    // SHA-256 per executable path, [None] if it couldn't be hashed
+    let mut exe_digests: HashMap<PathBuf, Option<String>> = HashMap::new();
+    // Hash of every process name currently running, only used with `hash_names`
+    let mut name_hashes: HashMap<String, String> = HashMap::new();
+    let mut time_between_measurements = args.time_between_measurements;
+    // Rendered measurements for `export_on_exit`, oldest first
+    let mut history: VecDeque<String> = VecDeque::new();
+    // PIDs of `pin` not yet resolved to a process, and the name of every pinned process
+    let mut pending_pins: HashSet<Pid> = args.pin.iter().map(|&pid| Pid::from(pid)).collect();
+    let mut pinned: HashMap<ProcessKey, String> = HashMap::new();
+    // Number of the total breach currently going on, only with `incident_ids`
+    let mut incident: Option<u64> = None;
+    let mut incident_count: u64 = 0;
+    // Accumulated from the first breaching measurement, only with `incident_dir`
+    let mut incident_report: Option<IncidentReport> = None;
+
+    // Sleeps end early at the deadline of `duration`
+    let deadline = args
+        .duration
+        .map(|duration| clock.now() + Duration::from_secs(duration));
+    let past_deadline = || deadline.is_some_and(|deadline| clock.now() >= deadline);
+    let until_deadline = |wait: Duration| match deadline {
+        Some(deadline) => wait.min(deadline.saturating_duration_since(clock.now())),
+        None => wait,
+    };
+
+    while running.load(Ordering::SeqCst) && !past_deadline() {
+        let period_start = clock.now();
+        CYCLE_STARTED_MS.store(epoch_millis(), Ordering::SeqCst);
+
+        // Refresh CPU
+        source.refresh(
+            args.total_source == TotalSource::Global
+                || !cores.is_empty()
+                || (args.debug && !args.minimal),
+        );
+
+        // Get currently running processes
+        let mut cpu_stats: CPUStats = CPUStats::from(source.processes());
+        // Before any filter could drop this process, covers everything done since the last refresh
+        let own_usage = own_pid.and_then(|pid| {
+            cpu_stats
+                .processes
+                .iter()
+                .find(|p| p.process.pid() == pid)
+                .map(|p| (p.process.cpu_usage() / cpu_count, p.process.memory()))
+        });
+
+        if args.name_source == NameSource::Full {
+            for p in cpu_stats.processes.iter_mut() {
+                p.name = full_name(p.process);
+            }
+        }
+        // Before anything matches or keeps names, so hashes are used throughout
+        if args.hash_names {
+            let names = cpu_stats
+                .processes
+                .iter()
+                .map(|p| p.name)
+                .collect::<HashSet<&str>>();
+            name_hashes.retain(|name, _| names.contains(name.as_str()));
+            for name in names {
+                if !name_hashes.contains_key(name) {
+                    name_hashes.insert(name.to_string(), hash_name(name));
+                }
+            }
+            for p in cpu_stats.processes.iter_mut() {
+                p.name = &name_hashes[p.name];
+            }
+        }
+
+        // Only keep watched processes if a PID file was specified
+        if let Some(pid_file) = &args.pid_file {
+            let watched_pids = match read_pid_file(pid_file) {
+                Ok(watched_pids) => watched_pids,
+                Err(e) => {
+                    warn(
+                        "pid-file",
+                        &format!("failed to read PID file '{}': {}", pid_file, e),
+                    );
+                    HashSet::new()
+                }
+            };
+            cpu_stats
+                .processes
+                .retain(|p| watched_pids.contains(&p.process.pid()));
+        }
+
+        // Only keep the processes listening on a watched port, which may change between measurements
+        if !args.watch_port.is_empty() {
+            let pids = cpu_stats.processes.iter().map(|p| p.process.pid());
+            if let Some(owners) = read_port_owners(&args.watch_port, pids) {
+                if owners.is_empty() {
+                    warn(
+                        "watch-port",
+                        &format!(
+                            "no process found listening on port {} (sockets of other users' processes are only visible to root)",
+                            args.watch_port
+                                .iter()
+                                .map(|port| port.to_string())
+                                .collect::<Vec<String>>()
+                                .join(", ")
+                        ),
+                    );
+                }
+                cpu_stats
+                    .processes
+                    .retain(|p| owners.contains(&p.process.pid()));
+            }
+        }
+
+        // Compare the identities of the watched processes by name
+        let mut restart_message: Option<String> = None;
+        if args.log_restarts {
+            let mut current: HashMap<&str, Option<ProcessKey>> = HashMap::new();
+            for p in &cpu_stats.processes {
+                current
+                    .entry(p.name)
+                    .and_modify(|key| *key = None)
+                    .or_insert(Some(p.key()));
+            }
+            // Names shared by several watched processes can't be told apart
+            for (name, key) in current.into_iter().filter_map(|(n, k)| Some((n, k?))) {
+                if let Some(previous) = watched_identities.insert(name.to_string(), key) {
+                    if previous != key {
+                        append_line(
+                            &mut restart_message,
+                            &format!(
+                                "[RESTARTED] Process '{}' restarted -> PID {} replaced by {}",
+                                name,
+                                format_pid(previous.0),
+                                format_pid(key.0),
+                            ),
+                        );
+                    }
+                }
+            }
+        }
+
+        if resolve_users {
+            for p in cpu_stats.processes.iter_mut() {
+                p.user_name = source.user_name(p.process.pid());
+            }
+        }
+
+        // Only keep user-space processes if requested
+        if args.exclude_kernel {
+            cpu_stats.processes.retain(|p| !is_kernel_thread(p.process));
+        }
+        if !args.include.is_empty() || !args.exclude.is_empty() {
+            cpu_stats
+                .processes
+                .retain(|p| is_name_included(&args.include, &args.exclude, p.process.name()));
+        }
+
+        if cpu_stats.processes.is_empty() {
+            warn("no-processes", "no processes to measure");
+        }
+
+        let mut inaccessible_count = 0;
+        if args.mark_inaccessible {
+            for p in cpu_stats.processes.iter_mut() {
+                p.inaccessible = is_inaccessible(p.process);
+                inaccessible_count += p.inaccessible as usize;
+            }
+        }
+
+        // Startup spikes (JIT warmup, loading, ...) shouldn't count
+        if let Some(settle_delay) = args.settle_delay {
+            for p in cpu_stats.processes.iter_mut() {
+                p.settling = p.process.run_time() < settle_delay;
+            }
+        }
+
+        // Start CPU calculation
+        for p_info in &cpu_stats.processes {
+            p_info.process.cpu_usage();
+        }
+
+        // Wait to collect data between time points
+        let measurement_start = clock.now();
+        #[cfg(target_os = "linux")]
+        let cpu_times_start = (args.show_cpu_breakdown && !args.minimal)
+            .then(read_cpu_times)
+            .flatten();
+        #[cfg(target_os = "linux")]
+        let energy_start = args.show_power.then(read_energy_counters).flatten();
+        #[cfg(target_os = "linux")]
+        let swap_start = args.show_swap_activity.then(read_swap_counters).flatten();
+        #[cfg(target_os = "linux")]
+        let ctxsw_start = match args.show_ctxsw {
+            true => cpu_stats
+                .processes
+                .iter()
+                .filter_map(|p| Some((p.process.pid(), read_context_switches(p.process.pid())?)))
+                .collect::<HashMap<Pid, (u64, u64)>>(),
+            false => HashMap::new(),
+        };
+        #[cfg(target_os = "linux")]
+        let faults_start = match args.show_faults {
+            true => cpu_stats
+                .processes
+                .iter()
+                .filter_map(|p| Some((p.process.pid(), read_page_faults(p.process.pid())?)))
+                .collect::<HashMap<Pid, (u64, u64)>>(),
+            false => HashMap::new(),
+        };
+        #[cfg(target_os = "linux")]
+        let disk_start = match args.show_disk {
+            true => cpu_stats
+                .processes
+                .iter()
+                .filter_map(|p| Some((p.process.pid(), read_disk_bytes(p.process.pid())?)))
+                .collect::<HashMap<Pid, (u64, u64)>>(),
+            false => HashMap::new(),
+        };
+        sleep_while_running(
+            clock,
+            running,
+            until_deadline(Duration::from_secs(args.measurement_time)),
+        );
+        // Don't report a cut short measurement
+        if !running.load(Ordering::SeqCst) || past_deadline() {
+            break;
+        }
+        cpu_stats.interval = clock.now().duration_since(measurement_start);
+        // Everything reported about this measurement carries the same timestamp
+        let measured_at = clock.local_now();
+        #[cfg(target_os = "linux")]
+        if let (Some(start), Some(end)) = (cpu_times_start, read_cpu_times()) {
+            cpu_stats.breakdown = end.breakdown_since(&start);
+        }
+        if args.show_priority {
+            for p in cpu_stats.processes.iter_mut() {
+                p.priority = read_priority(p.process.pid());
+            }
+        }
+        if args.show_fds {
+            for p in cpu_stats.processes.iter_mut() {
+                p.fd_count = read_fd_count(p.process.pid());
+            }
+            cpu_stats.fd_total = read_fd_total();
+        }
+        #[cfg(target_os = "linux")]
+        for p in cpu_stats.processes.iter_mut() {
+            if let (Some(start), Some(end)) = (
+                ctxsw_start.get(&p.process.pid()),
+                read_context_switches(p.process.pid()),
+            ) {
+                p.context_switches =
+                    Some((end.0.saturating_sub(start.0), end.1.saturating_sub(start.1)));
+            }
+            if let (Some(start), Some(end)) = (
+                faults_start.get(&p.process.pid()),
+                read_page_faults(p.process.pid()),
+            ) {
+                p.page_faults =
+                    Some((end.0.saturating_sub(start.0), end.1.saturating_sub(start.1)));
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if args.show_disk {
+            let seconds = cpu_stats.interval.as_secs_f32().max(f32::EPSILON);
+            let mut new_smoothed_disk_rates: HashMap<ProcessKey, (f32, f32)> = HashMap::new();
+            for p in cpu_stats.processes.iter_mut() {
+                let (Some(start), Some(end)) = (
+                    disk_start.get(&p.process.pid()),
+                    read_disk_bytes(p.process.pid()),
+                ) else {
+                    continue;
+                };
+                let mut rates = (
+                    end.0.saturating_sub(start.0) as f32 / seconds,
+                    end.1.saturating_sub(start.1) as f32 / seconds,
+                );
+                // Disk rates are spikier than CPU usage, new processes are seeded with their first reading
+                if let Some(alpha) = args.io_smoothing {
+                    if let Some(previous) = smoothed_disk_rates.get(&p.key()) {
+                        rates = (
+                            alpha * rates.0 + (1.0 - alpha) * previous.0,
+                            alpha * rates.1 + (1.0 - alpha) * previous.1,
+                        );
+                    }
+                    new_smoothed_disk_rates.insert(p.key(), rates);
+                }
+                p.disk_rates = Some(rates);
+            }
+            smoothed_disk_rates = new_smoothed_disk_rates;
+        }
+        #[cfg(target_os = "linux")]
+        if let (Some(start), Some(end)) = (swap_start, read_swap_counters()) {
+            let seconds = cpu_stats.interval.as_secs_f32().max(f32::EPSILON);
+            cpu_stats.swap_rates = Some((
+                end.0.saturating_sub(start.0) as f32 / seconds,
+                end.1.saturating_sub(start.1) as f32 / seconds,
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if args.show_power {
+            cpu_stats.power = match (energy_start, read_energy_counters()) {
+                (Some(start), Some(end)) => end.power_since(&start, cpu_stats.interval),
+                _ => None,
+            };
+            if cpu_stats.power.is_none() {
+                warn(
+                    "power",
+                    "failed to read RAPL energy counters (missing or not readable without root)",
+                );
+            }
+        }
+
+        // Rate the usage by how fast the cores ran at the last refresh
+        // An unknown current frequency would scale all usage down to 0
+        let frequencies = max_frequency
+            .map(|max| (source.cpu_frequency(), max))
+            .filter(|&(current, _)| current > 0);
+        let frequency_scale = frequencies.map_or(1.0, |(current, max)| current as f32 / max as f32);
+
+        // Update CPU usage
+        cpu_stats
+            .processes
+            .iter_mut()
+            .for_each(|p| p.got_cpu_usage = p.process.cpu_usage() / cpu_count * frequency_scale);
+
+        // Smooth the usage so the ordering doesn't jitter between measurements
+        if let Some(alpha) = args.process_smoothing {
+            let mut new_smoothed_usage: HashMap<ProcessKey, f32> = HashMap::new();
+            for p in cpu_stats.processes.iter_mut() {
+                let raw = p.got_cpu_usage;
+                // New processes are seeded with their first reading
+                let smoothed = smoothed_usage
+                    .get(&p.key())
+                    .map_or(raw, |previous| alpha * raw + (1.0 - alpha) * previous);
+                new_smoothed_usage.insert(p.key(), smoothed);
+                p.raw_cpu_usage = Some(raw);
+                p.got_cpu_usage = smoothed;
+            }
+            smoothed_usage = new_smoothed_usage;
+        }
+
+        // Average the usage over the measurements within the window
+        if let Some(window) = args.average_over.map(Duration::from_secs) {
+            let now = clock.now();
+            let mut new_usage_windows: HashMap<ProcessKey, VecDeque<(Instant, f32)>> =
+                HashMap::new();
+            for p in cpu_stats.processes.iter_mut() {
+                let mut samples = usage_windows.remove(&p.key()).unwrap_or_default();
+                samples.push_back((now, p.got_cpu_usage));
+                while samples
+                    .front()
+                    .is_some_and(|(measured_at, _)| now.duration_since(*measured_at) > window)
+                {
+                    samples.pop_front();
+                }
+
+                p.raw_cpu_usage = Some(p.got_cpu_usage);
+                p.got_cpu_usage =
+                    samples.iter().map(|(_, usage)| usage).sum::<f32>() / samples.len() as f32;
+                new_usage_windows.insert(p.key(), samples);
+            }
+            usage_windows = new_usage_windows;
+        }
+
+        // Keep a running mean per process
+        let mut new_usage_sums: HashMap<ProcessKey, (u32, f32)> = HashMap::new();
+        for p in cpu_stats.processes.iter_mut() {
+            let (count, sum) = usage_sums.get(&p.key()).copied().unwrap_or_default();
+            let (count, sum) = (count + 1, sum + p.raw_cpu_usage.unwrap_or(p.got_cpu_usage));
+            p.avg_cpu_usage = sum / count as f32;
+            new_usage_sums.insert(p.key(), (count, sum));
+        }
+        usage_sums = new_usage_sums;
+
+        // Compare against the saved snapshot
+        if let Some(baseline_snapshot) = &baseline_snapshot {
+            for p in cpu_stats.processes.iter_mut() {
+                p.baseline_delta =
+                    Some(p.got_cpu_usage - baseline_snapshot.get(p.name).copied().unwrap_or(0.0));
+            }
+        }
+
+        // Calculate total usage by all processes (unsmoothed, it is stable enough)
+        let processes_cpu_usage: f32 = cpu_stats
+            .processes
+            .iter()
+            .map(|v| v.raw_cpu_usage.unwrap_or(v.got_cpu_usage))
+            .sum();
+        let core_usages = source.cpu_usages();
+        let selected_core_usages = cores
+            .iter()
+            .map(|&core| core_usages[core] * frequency_scale)
+            .collect::<Vec<f32>>();
+        let global_cpu_usage = match selected_core_usages.is_empty() {
+            true => source.global_cpu_usage() * frequency_scale,
+            false => selected_core_usages.iter().sum::<f32>() / selected_core_usages.len() as f32,
+        };
+        let total_cpu_usage = match args.total_source {
+            TotalSource::Processes => processes_cpu_usage,
+            TotalSource::Global => global_cpu_usage,
+        };
+        let process_count = cpu_stats.processes.len();
+
+        // Attribute the children of busy parents to them
+        let mut collapsed_count = 0;
+        if args.processes_only_above_parent {
+            collapsed_count = collapse_into_busy_parents(
+                &mut cpu_stats.processes,
+                args.process_log_threshold,
+                args.expand,
+            );
+        }
+
+        // Groups are led by their lowest PID, usually the parent of the others
+        if args.aggregate_by_name {
+            cpu_stats.processes.sort_by_key(|p| p.process.pid());
+            cpu_stats = collapse_by_name(&cpu_stats, cpu_stats.processes.len());
+        }
+
+        // Before `max_processes` drops any, so a percentage is of all processes
+        let number_of_processes_to_show = args
+            .number_of_processes_to_show
+            .of(cpu_stats.processes.len());
+
+        // Idle processes can still be busy by memory, so nothing below may drop them
+        let memory_exceeded = |p: &ProcessStats| {
+            args.memory_log_threshold
+                .is_some_and(|t| p.metric(condition::Metric::Mem) >= t)
+        };
+
+        // Sort by usage, ties in a deterministic order
+        let by_usage = |a: &ProcessStats, b: &ProcessStats| {
+            let by_pid = || a.process.pid().cmp(&b.process.pid());
+            // A total order, so a NaN reading can't panic the sort
+            b.got_cpu_usage
+                .total_cmp(&a.got_cpu_usage)
+                .then_with(|| match args.tie_break {
+                    TieBreak::Pid => by_pid(),
+                    TieBreak::Name => a.name.cmp(b.name).then_with(by_pid),
+                })
+        };
+        match args.max_processes.map(|max| max as usize) {
+            // Select the top processes in linear time and only sort those
+            Some(max) if max < cpu_stats.processes.len() => {
+                cpu_stats
+                    .processes
+                    .select_nth_unstable_by(max - 1, by_usage);
+                let mut rank = 0;
+                cpu_stats.processes.retain(|p| {
+                    rank += 1;
+                    rank <= max || memory_exceeded(p)
+                });
+                cpu_stats.processes.sort_by(by_usage);
+            }
+            _ => cpu_stats.processes.sort_by(by_usage),
+        }
+
+        // Nothing is responsible for anything if nothing was used
+        if total_cpu_usage > 0.0 {
+            for p in cpu_stats.processes.iter_mut() {
+                p.share_of_total = p.got_cpu_usage / total_cpu_usage * 100.0;
+            }
+        }
+
+        // Attribute the package power by CPU share, ignoring idle power and memory/GPU differences
+        if let Some(power) = cpu_stats.power {
+            for p in cpu_stats.processes.iter_mut() {
+                p.power_estimate = Some(power * p.share_of_total / 100.0);
+            }
+        }
+
+        // Processes stay normalized, only the total is rescaled
+        let normalized_total_cpu_usage = total_cpu_usage;
+        let total_cpu_usage = match args.total_scale {
+            TotalScale::Normalized => total_cpu_usage,
+            TotalScale::Absolute => total_cpu_usage * cpu_count,
+        };
+
+        let mut extra_header_lines =
+            vec![format!("Normalized to {} {} cores", core_count, core_kind)];
+        // Small percentages of many cores are hard to picture
+        if core_count >= MANY_CORES {
+            extra_header_lines.push(format!(
+                "~{:.1} of {} cores busy",
+                Decimal(normalized_total_cpu_usage / 100.0 * cpu_count),
+                core_count
+            ));
+        }
+        if args.total_scale == TotalScale::Absolute {
+            extra_header_lines.push(format!(
+                "Total on absolute scale (100 % per core, max {:.0} %)",
+                Decimal(cpu_count * 100.0)
+            ));
+        }
+        if let Some(breakdown) = &cpu_stats.breakdown {
+            extra_header_lines.push(format!(
+                "iowait {:.2} % | steal {:.2} %",
+                Decimal(breakdown.iowait),
+                Decimal(breakdown.steal)
+            ));
+        }
+        if let Some(power) = cpu_stats.power {
+            extra_header_lines.push(format!("Estimated package power ~{:.1} W", Decimal(power)));
+        }
+        if let Some(fd_total) = cpu_stats.fd_total {
+            extra_header_lines.push(format!("{} open file descriptors", fd_total));
+        }
+        if let Some((own_cpu_usage, own_memory)) = own_usage {
+            extra_header_lines.push(format!(
+                "Logger itself: {:.2} % CPU, {}",
+                Decimal(own_cpu_usage),
+                format_memory(own_memory)
+            ));
+        }
+        if let Some((swapped_in, swapped_out)) = cpu_stats.swap_rates {
+            extra_header_lines.push(format!(
+                "swap in {} | swap out {}",
+                format_rate(swapped_in),
+                format_rate(swapped_out)
+            ));
+        }
+        // A few cores per line to stay within the table, a line per 64 cores on big machines
+        if cores.len() >= MANY_CORES {
+            extra_header_lines.extend(format_core_heatmap(&cores, &selected_core_usages));
+        } else {
+            for chunk in cores
+                .iter()
+                .zip(&selected_core_usages)
+                .collect::<Vec<_>>()
+                .chunks(6)
+            {
+                extra_header_lines.push(
+                    chunk
+                        .iter()
+                        .map(|(core, usage)| format!("CPU{} {:.1} %", core, Decimal(usage)))
+                        .collect::<Vec<String>>()
+                        .join(" | "),
+                );
+            }
+        }
+        if let Some((current, max)) = frequencies {
+            extra_header_lines.push(format!(
+                "Frequency-aware: {} of {} MHz -> usage x{:.2}",
+                current, max, frequency_scale
+            ));
+        }
+        if inaccessible_count > 0 {
+            extra_header_lines.push(format!(
+                "{} processes with unreadable stats (n/a)",
+                inaccessible_count
+            ));
+        }
+        if collapsed_count > 0 {
+            extra_header_lines.push(format!(
+                "{} child processes {} busy parents",
+                collapsed_count,
+                match args.expand {
+                    true => "counted towards",
+                    false => "collapsed into",
+                }
+            ));
+        }
+
+        // Pins follow the identity, so a reused PID isn't pinned again
+        for p in cpu_stats.processes.iter_mut() {
+            if pending_pins.remove(&p.process.pid()) {
+                pinned.insert(p.key(), p.name.to_string());
+            }
+            p.pinned = pinned.contains_key(&p.key());
+        }
+        for pid in pending_pins.drain() {
+            warn(
+                "pin",
+                &format!("no process with PID {} to pin", format_pid(pid)),
+            );
+        }
+        let running_keys = cpu_stats
+            .processes
+            .iter()
+            .filter(|p| p.pinned)
+            .map(|p| p.key())
+            .collect::<HashSet<ProcessKey>>();
+        // Exited pinned processes are shown once more before they are dropped
+        pinned.retain(|key, name| {
+            let running = running_keys.contains(key);
+            if !running {
+                extra_header_lines.push(format!("* {} [PID {}] (exited)", name, format_pid(key.0)));
+            }
+            running
+        });
+
+        if let Some(message) = &restart_message {
+            log_to_file(&log_file, message, &measured_at, clock.now());
+            extra_header_lines.extend(message.lines().map(String::from));
+        }
+
+        // The first readings are often inaccurate -> don't log or alert on them
+        let warming_up = measurement_count < args.warmup;
+        if warming_up {
+            extra_header_lines.push(format!(
+                "Warming up ({}/{})",
+                measurement_count + 1,
+                args.warmup
+            ));
+        } else {
+            histogram.record(total_cpu_usage);
+
+            if let Some(summary) = hourly_summary.as_mut() {
+                // Finish the summary once the local clock rolled over to a new hour
+                let hour = HourlySummary::hour_of(&measured_at);
+                if hour != summary.hour {
+                    if let Some(message) = summary.format() {
+                        log_to_file(&log_file, &message, &measured_at, clock.now());
+                    }
+                    *summary = HourlySummary::new(hour);
+                }
+                summary.record(total_cpu_usage, &cpu_stats);
+            }
+            if let Some(summary) = run_summary.as_mut() {
+                summary.record(total_cpu_usage, &cpu_stats);
+            }
+        }
+        // Unlike warming up, the grace period only mutes alerts
+        let in_alert_grace = alert_grace_until.is_some_and(|until| clock.now() < until);
+        if let Some(until) = alert_grace_until.filter(|_| in_alert_grace && !warming_up) {
+            extra_header_lines.push(format!(
+                "Alert grace period ({}s left)",
+                until.duration_since(clock.now()).as_secs() + 1
+            ));
+        }
+        if let Some(pause_file) = &args.pause_file {
+            let exists = Path::new(pause_file).exists();
+            if exists != paused {
+                let message = match exists {
+                    true => format!("Paused, '{}' exists", pause_file),
+                    false => format!("Resumed, '{}' was removed", pause_file),
+                };
+                log_to_file(&log_file, &message, &measured_at, clock.now());
+            }
+            paused = exists;
+            if paused {
+                extra_header_lines.push(format!("Paused (remove '{}' to resume)", pause_file));
+            }
+        }
+        let alerting = !warming_up && !in_alert_grace && !paused;
+        let process_log_threshold = alerting.then_some(args.process_log_threshold);
+        // Processes matching no rule fall back to the global thresholds
+        let rule = |p: &ProcessStats| {
+            args.rules_file
+                .as_ref()
+                .and_then(|rules| rules.find(p.process.name()))
+        };
+        // Without a condition or rule a process is busy above the CPU or the memory threshold
+        let is_busy = |p: &ProcessStats| match (rule(p), &args.busy_when) {
+            (Some(rule), _) => alerting && rule.exceeded(&|metric| p.metric(metric)),
+            (None, Some(condition)) => alerting && condition.eval(&|metric| p.metric(metric)),
+            (None, None) => {
+                process_log_threshold.is_some_and(|t| p.got_cpu_usage >= t || memory_exceeded(p))
+            }
+        };
+
+        // Hide sub-threshold noise (after the total was calculated)
+        if args.min_display_usage > 0.0 {
+            cpu_stats
+                .processes
+                .retain(|p| p.got_cpu_usage >= args.min_display_usage || memory_exceeded(p));
+        }
+
+        let mut formatted_stats: Option<String> = None;
+
+        // Alerting on the total is disabled while warming up, the baseline is still being learned or in the grace period
+        let total_log_threshold = match baseline.as_mut() {
+            _ if warming_up => None,
+            None => Some(args.total_log_threshold),
+            Some(baseline) => {
+                if baseline.update(total_cpu_usage, clock.now()) {
+                    let learned = baseline.learned.unwrap();
+                    log_to_file(
+                        &log_file,
+                        &format!(
+                            "Learned baseline total CPU usage of {:.2}% -> monitoring with threshold of {:.2}%",
+                            Decimal(learned),
+                            Decimal(baseline.threshold(args.baseline_multiplier).unwrap()),
+                        ),
+                        &measured_at, clock.now(),
+                    );
+                }
+
+                baseline.threshold(args.baseline_multiplier)
+            }
+        }
+        // The baseline keeps learning during the grace period and while paused
+        .filter(|_| !in_alert_grace && !paused);
+
+        //* Handle thresholds
+        let mut total_cpu_usage_message: Option<String> = None;
+        if let Some(total_log_threshold) = total_log_threshold.filter(|t| total_cpu_usage >= *t) {
+            // A new incident starts when the total crosses the threshold
+            if args.incident_ids && incident.is_none() {
+                incident_count += 1;
+                incident = Some(incident_count);
+            }
+            if args.incident_dir.is_some() {
+                incident_report
+                    .get_or_insert_with(|| IncidentReport::new(measured_at, total_log_threshold))
+                    .record(total_cpu_usage, &cpu_stats, measured_at);
+            }
+
+            // We always have to format the stats here
+            formatted_stats = Some(format_stats(
+                &cpu_stats,
+                total_cpu_usage,
+                number_of_processes_to_show,
+                &layout,
+                &extra_header_lines,
+                false,
+                &measured_at,
+            ));
+
+            total_cpu_usage_message = Some(format!(
+                "[TOTAL] Total CPU usage threshold of {:.2}% exceeded -> {:.2}%{}",
+                Decimal(total_log_threshold),
+                Decimal(total_cpu_usage),
+                format_incident(incident),
+            ));
+
+            // Only the logged list is collapsed, the CLI keeps showing every process
+            let collapsed_stats = args
+                .collapse_breach_list
+                .then(|| collapse_by_name(&cpu_stats, number_of_processes_to_show));
+            let logged_stats = collapsed_stats.as_ref().unwrap_or(&cpu_stats);
+
+            // If we would push the whole logged message into total_cpu_usage_message the
+            // CLI would display the usage table twice
+            let mut logged_message = format!(
+                "{}\n{}",
+                total_cpu_usage_message.as_ref().unwrap(),
+                match &collapsed_stats {
+                    Some(collapsed_stats) => format_stats(
+                        collapsed_stats,
+                        total_cpu_usage,
+                        number_of_processes_to_show,
+                        &layout,
+                        &extra_header_lines,
+                        false,
+                        &measured_at,
+                    ),
+                    None => formatted_stats.clone().unwrap(),
+                },
+            );
+            if log_format == SinkFormat::Compact {
+                logged_message = format_stats_compact(
+                    logged_stats,
+                    total_cpu_usage,
+                    number_of_processes_to_show,
+                    args.include_memory_on_breach,
+                ) + &format_incident(incident);
+            } else if args.include_memory_on_breach {
+                logged_message = format!(
+                    "{}\n[MEMORY] Top memory consuming processes\n{}",
+                    logged_message,
+                    format_memory_stats(&cpu_stats, number_of_processes_to_show),
+                );
+            }
+
+            let dedup_key = format!(
+                "total|{}|{:.0}",
+                cpu_stats.processes.first().map_or("", |p| p.name),
+                total_cpu_usage,
+            );
+            if deduplicator.as_mut().is_none_or(|d| {
+                d.should_log(
+                    &dedup_key,
+                    total_cpu_usage_message.as_ref().unwrap(),
+                    clock.now(),
+                )
+            }) {
+                match (&args.log_template, log_format) {
+                    (Some(template), _) => log_raw_to_file(
+                        &log_file,
+                        &template.render(
+                            &LogEvent {
+                                reason: "total",
+                                pid: None,
+                                name: None,
+                                cpu: total_cpu_usage,
+                                threshold: total_log_threshold,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    ),
+                    (None, SinkFormat::Logfmt) => log_raw_to_file(
+                        &log_file,
+                        &format_stats_logfmt(
+                            &cpu_stats,
+                            total_cpu_usage,
+                            number_of_processes_to_show,
+                            &[
+                                ("event", String::from("total")),
+                                ("threshold", format!("{:.2}", total_log_threshold)),
+                            ]
+                            .into_iter()
+                            .chain(incident.map(|id| ("incident", id.to_string())))
+                            .collect::<Vec<(&str, String)>>(),
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    ),
+                    (None, SinkFormat::Json | SinkFormat::Csv) => log_raw_to_file(
+                        &log_file,
+                        &format_stats_record(
+                            log_format,
+                            "total",
+                            total_cpu_usage,
+                            &cpu_stats
+                                .processes
+                                .iter()
+                                .take(number_of_processes_to_show)
+                                .collect::<Vec<&ProcessStats>>(),
+                            &[
+                                ("threshold", total_log_threshold.into()),
+                                ("incident", incident.into()),
+                            ],
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    ),
+                    _ => log_to_file(&log_file, &logged_message, &measured_at, clock.now()),
+                }
+                #[cfg(all(windows, feature = "eventlog"))]
+                if let Some(event_log) = &event_log {
+                    event_log.report(
+                        eventlog::EventKind::Warning,
+                        total_cpu_usage_message.as_ref().unwrap(),
+                    );
+                }
+                #[cfg(all(target_os = "linux", feature = "journald"))]
+                if let Some(journal) = &journal {
+                    journal.send(
+                        journald::Priority::Warning,
+                        total_cpu_usage_message.as_ref().unwrap(),
+                        &journal_fields("total", total_cpu_usage, total_log_threshold, incident),
+                    );
+                }
+            }
+
+            #[cfg(unix)]
+            write_to_event_fifo(
+                &args.event_fifo,
+                &serde_json::json!({
+                    "schema_version": JSON_SCHEMA_VERSION,
+                    "timestamp": json_timestamp(&measured_at),
+                    "timestamp_iso": json_iso_timestamp(&measured_at),
+                    "instance": instance,
+                    "reason": "total",
+                    "threshold": total_log_threshold,
+                    "value": total_cpu_usage,
+                    "over_by": total_cpu_usage - total_log_threshold,
+                    "cpu_percent_normalized": normalized_total_cpu_usage,
+                    "cpu_percent_per_core": normalized_total_cpu_usage * cpu_count,
+                    "interval_secs": cpu_stats.interval.as_secs_f64(),
+                    "iowait": cpu_stats.breakdown.as_ref().map(|b| b.iowait),
+                    "steal": cpu_stats.breakdown.as_ref().map(|b| b.steal),
+                    "swap_in_bytes_per_sec": cpu_stats.swap_rates.map(|(swapped_in, _)| swapped_in),
+                    "swap_out_bytes_per_sec": cpu_stats.swap_rates.map(|(_, swapped_out)| swapped_out),
+                    "core_count": core_count,
+                    "core_kind": core_kind,
+                }),
+            );
+        }
+
+        // Note when the total drops back below the threshold it exceeded before
+        if let Some(total_log_threshold) =
+            total_log_threshold.filter(|_| total_breached && total_cpu_usage_message.is_none())
+        {
+            let message = format!(
+                "[RECOVERED] Total CPU usage back below threshold of {:.2}% -> {:.2}%{}",
+                Decimal(total_log_threshold),
+                Decimal(total_cpu_usage),
+                format_incident(incident),
+            );
+            match &args.log_template {
+                Some(template) => log_raw_to_file(
+                    &log_file,
+                    &template.render(
+                        &LogEvent {
+                            reason: "recovered",
+                            pid: None,
+                            name: None,
+                            cpu: total_cpu_usage,
+                            threshold: total_log_threshold,
+                            incident,
+                        },
+                        &measured_at,
+                    ),
+                    clock.now(),
+                ),
+                None => log_to_file(&log_file, &message, &measured_at, clock.now()),
+            }
+            #[cfg(all(windows, feature = "eventlog"))]
+            if let Some(event_log) = &event_log {
+                event_log.report(eventlog::EventKind::Information, &message);
+            }
+            #[cfg(all(target_os = "linux", feature = "journald"))]
+            if let Some(journal) = &journal {
+                journal.send(
+                    journald::Priority::Info,
+                    &message,
+                    &journal_fields("recovered", total_cpu_usage, total_log_threshold, incident),
+                );
+            }
+            if let (Some(dir), Some(report)) = (&args.incident_dir, incident_report.take()) {
+                if let Err(e) = report.write(dir, incident, &measured_at, &instance) {
+                    warn(
+                        "incident-write",
+                        &format!("failed to write incident report to '{}': {}", dir, e),
+                    );
+                }
+            }
+        }
+        total_breached = total_cpu_usage_message.is_some();
+        // The incident is retired with its recovery
+        if !total_breached {
+            incident = None;
+            // Incidents cut short by a pause or the grace period are not reported
+            incident_report = None;
+        }
+
+        let mut process_cpu_usage_message: Option<String> = None;
+        let mut logged_process_cpu_usage_message: Option<String> = None;
+        let mut new_alerted: HashSet<ProcessKey> = HashSet::new();
+        cpu_stats
+            .processes
+            .iter()
+            .filter(|p| is_busy(p) && !p.settling)
+            .for_each(|p| {
+                let rule = rule(p);
+                let threshold = rule
+                    .and_then(|rule| rule.cpu)
+                    .unwrap_or(args.process_log_threshold);
+                let mut message = format!(
+                    "[PROCESS] {} -> [Pid: {}] Name: '{}' Usage: {:.2}%",
+                    match (rule, &args.busy_when) {
+                        (Some(rule), _) => {
+                            format!("Rule '{}' ({}) matched", rule.pattern(), rule.describe())
+                        }
+                        (None, Some(condition)) => {
+                            format!("Busy condition '{}' met", condition.source())
+                        }
+                        (None, None) if p.got_cpu_usage < args.process_log_threshold => format!(
+                            "Single process memory threshold of {} MB exceeded",
+                            Decimal(args.memory_log_threshold.unwrap())
+                        ),
+                        (None, None) if memory_exceeded(p) => format!(
+                            "Single process CPU usage threshold of {:.2}% and memory threshold of {} MB exceeded",
+                            Decimal(args.process_log_threshold),
+                            Decimal(args.memory_log_threshold.unwrap())
+                        ),
+                        (None, None) => format!(
+                            "Single process CPU usage threshold of {:.2}% exceeded",
+                            Decimal(args.process_log_threshold)
+                        ),
+                    },
+                    format_pid(p.process.pid()),
+                    p.name,
+                    Decimal(p.got_cpu_usage),
+                );
+                if p.merged > 1 {
+                    message += &format!(" Processes: {}", p.merged);
+                }
+                if args.show_memory || args.memory_log_threshold.is_some() {
+                    message += &format!(" Memory: {}", format_memory(p.memory));
+                }
+                let exe_digest = args
+                    .hash_exe
+                    .then(|| exe_digest(&mut exe_digests, p.process.exe()))
+                    .flatten();
+                if args.hash_exe {
+                    message += &format!(
+                        " Exe: '{}' SHA-256: {}",
+                        format_exe(p.process.exe()),
+                        exe_digest.as_deref().unwrap_or("-"),
+                    );
+                }
+                message += &format_incident(incident);
+
+                // Edge triggered alerting only reports processes that just crossed the threshold
+                new_alerted.insert(p.key());
+                let alert = !args.alert_new_only || !alerted.contains(&p.key());
+
+                let dedup_key = format!(
+                    "process|{}|{}|{:.0}",
+                    p.process.pid(),
+                    p.name,
+                    p.got_cpu_usage,
+                );
+                if alert
+                    && deduplicator
+                        .as_mut()
+                        .is_none_or(|d| d.should_log(&dedup_key, &message, clock.now()))
+                {
+                    let logged_line = match (&args.log_template, log_format) {
+                        (Some(template), _) => template.render(
+                            &LogEvent {
+                                reason: "process",
+                                pid: Some(p.process.pid()),
+                                name: Some(p.name),
+                                cpu: p.got_cpu_usage,
+                                threshold,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        (None, SinkFormat::Logfmt) => format_logfmt(
+                            &[
+                                ("ts", format_iso_time(&measured_at)),
+                                ("event", String::from("process")),
+                                ("threshold", format!("{:.2}", threshold)),
+                                ("pid", format_pid(p.process.pid())),
+                                ("name", p.name.to_string()),
+                                ("cpu", format!("{:.2}", p.got_cpu_usage)),
+                            ]
+                            .into_iter()
+                            .chain(incident.map(|id| ("incident", id.to_string())))
+                            .collect::<Vec<(&str, String)>>(),
+                        ),
+                        (None, SinkFormat::Json | SinkFormat::Csv) => format_stats_record(
+                            log_format,
+                            "process",
+                            total_cpu_usage,
+                            &[p],
+                            &[
+                                ("threshold", threshold.into()),
+                                ("incident", incident.into()),
+                            ],
+                            &measured_at,
+                        ),
+                        _ => message.clone(),
+                    };
+                    append_line(&mut logged_process_cpu_usage_message, &logged_line);
+                    #[cfg(all(target_os = "linux", feature = "journald"))]
+                    if let Some(journal) = &journal {
+                        let mut fields =
+                            journal_fields("process", total_cpu_usage, threshold, incident);
+                        fields.extend(journal_process_fields(p));
+                        journal.send(journald::Priority::Warning, &message, &fields);
+                    }
+                }
+                append_line(&mut process_cpu_usage_message, &message);
+
+                #[cfg(unix)]
+                if alert {
+                    write_to_event_fifo(
+                        &args.event_fifo,
+                        &serde_json::json!({
+                            "schema_version": JSON_SCHEMA_VERSION,
+                            "timestamp": json_timestamp(&measured_at),
+                            "timestamp_iso": json_iso_timestamp(&measured_at),
+                            "instance": instance,
+                            "reason": "process",
+                            "threshold": threshold,
+                            "value": p.got_cpu_usage,
+                            "over_by": p.got_cpu_usage - threshold,
+                            "cpu_percent_normalized": p.got_cpu_usage,
+                            "cpu_percent_per_core": p.got_cpu_usage * cpu_count,
+                            "pid": p.process.pid().as_u32(),
+                            "name": p.name,
+                            "exe_sha256": exe_digest,
+                            "interval_secs": cpu_stats.interval.as_secs_f64(),
+                            "core_count": core_count,
+                            "core_kind": core_kind,
+                        }),
+                    );
+                }
+            });
+        alerted = new_alerted;
+        if let Some(message) = &logged_process_cpu_usage_message {
+            // Templated and structured lines carry their own timestamp
+            match (&args.log_template, log_format) {
+                (Some(_), _) | (None, SinkFormat::Logfmt | SinkFormat::Json | SinkFormat::Csv) => {
+                    log_raw_to_file(&log_file, message, clock.now())
+                }
+                _ => log_to_file(&log_file, message, &measured_at, clock.now()),
+            }
+            #[cfg(all(windows, feature = "eventlog"))]
+            if let Some(event_log) = &event_log {
+                event_log.report(eventlog::EventKind::Warning, message);
+            }
+        }
+
+        // Summarize suppressed duplicates once their window passed
+        if let Some(deduplicator) = deduplicator.as_mut() {
+            for summary in deduplicator.expire(clock.now()) {
+                log_to_file(&log_file, &summary, &measured_at, clock.now());
+            }
+        }
+
+        // Flag processes that grew compared to the saved snapshot
+        if let Some(baseline_alert_delta) = args.baseline_alert_delta.filter(|_| alerting) {
+            let mut baseline_message: Option<String> = None;
+            for p in cpu_stats
+                .processes
+                .iter()
+                .filter(|p| p.baseline_delta.is_some_and(|d| d > baseline_alert_delta))
+            {
+                append_line(
+                    &mut baseline_message,
+                    &format!(
+                        "[PROCESS] Baseline CPU usage delta of {:.2}% exceeded -> [Pid: {}] Name: '{}' Usage: {:.2}% Delta: {:+.2}%{}",
+                        Decimal(baseline_alert_delta),
+                        format_pid(p.process.pid()),
+                        p.name,
+                        Decimal(p.got_cpu_usage),
+                        Decimal(p.baseline_delta.unwrap()),
+                        format_incident(incident),
+                    ),
+                );
+                if let Some(template) = &args.log_template {
+                    log_raw_to_file(
+                        &log_file,
+                        &template.render(
+                            &LogEvent {
+                                reason: "baseline",
+                                pid: Some(p.process.pid()),
+                                name: Some(p.name),
+                                cpu: p.got_cpu_usage,
+                                threshold: baseline_alert_delta,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    );
+                }
+            }
+
+            if let Some(message) = baseline_message {
+                if args.log_template.is_none() {
+                    log_to_file(&log_file, &message, &measured_at, clock.now());
+                }
+                append_line(&mut process_cpu_usage_message, &message);
+            }
+        }
+
+        // Processes that just reached `fd_threshold`, logged again only after dropping below it
+        if let Some(fd_threshold) = args.fd_threshold {
+            let mut new_fd_alerted = HashSet::new();
+            let mut fd_message: Option<String> = None;
+            for p in cpu_stats
+                .processes
+                .iter()
+                .filter(|p| alerting && p.fd_count.is_some_and(|count| count >= fd_threshold))
+            {
+                new_fd_alerted.insert(p.key());
+                if fd_alerted.contains(&p.key()) {
+                    continue;
+                }
+                append_line(
+                    &mut fd_message,
+                    &format!(
+                        "[PROCESS] Open file descriptor threshold of {} reached -> [Pid: {}] Name: '{}' FDs: {}{}",
+                        fd_threshold,
+                        format_pid(p.process.pid()),
+                        p.name,
+                        p.fd_count.unwrap(),
+                        format_incident(incident),
+                    ),
+                );
+                if let Some(template) = &args.log_template {
+                    log_raw_to_file(
+                        &log_file,
+                        &template.render(
+                            &LogEvent {
+                                reason: "fds",
+                                pid: Some(p.process.pid()),
+                                name: Some(p.name),
+                                cpu: p.got_cpu_usage,
+                                threshold: fd_threshold as f32,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    );
+                }
+            }
+            fd_alerted = new_fd_alerted;
+
+            if let Some(message) = fd_message {
+                if args.log_template.is_none() {
+                    log_to_file(&log_file, &message, &measured_at, clock.now());
+                }
+                append_line(&mut process_cpu_usage_message, &message);
+            }
+        }
+
+        // Processes that just reached `major_fault_threshold`, logged again only after dropping below it
+        if let Some(fault_threshold) = args.major_fault_threshold {
+            let mut new_fault_alerted = HashSet::new();
+            let mut fault_message: Option<String> = None;
+            for p in cpu_stats.processes.iter().filter(|p| {
+                alerting
+                    && p.page_faults
+                        .is_some_and(|(_, major)| major >= fault_threshold)
+            }) {
+                new_fault_alerted.insert(p.key());
+                if fault_alerted.contains(&p.key()) {
+                    continue;
+                }
+                append_line(
+                    &mut fault_message,
+                    &format!(
+                        "[PROCESS] Major page fault threshold of {} reached -> [Pid: {}] Name: '{}' Major faults: {}{}",
+                        fault_threshold,
+                        format_pid(p.process.pid()),
+                        p.name,
+                        p.page_faults.unwrap().1,
+                        format_incident(incident),
+                    ),
+                );
+                if let Some(template) = &args.log_template {
+                    log_raw_to_file(
+                        &log_file,
+                        &template.render(
+                            &LogEvent {
+                                reason: "faults",
+                                pid: Some(p.process.pid()),
+                                name: Some(p.name),
+                                cpu: p.got_cpu_usage,
+                                threshold: fault_threshold as f32,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    );
+                }
+            }
+            fault_alerted = new_fault_alerted;
+
+            if let Some(message) = fault_message {
+                if args.log_template.is_none() {
+                    log_to_file(&log_file, &message, &measured_at, clock.now());
+                }
+                append_line(&mut process_cpu_usage_message, &message);
+            }
+        }
+
+        // Processes far above their own norm, logged again only after returning to it
+        if let Some(multiplier) = args.process_anomaly_multiplier {
+            let mut new_usage_norms = HashMap::new();
+            let mut new_anomalous = HashSet::new();
+            let mut anomaly_message: Option<String> = None;
+            for p in cpu_stats.processes.iter() {
+                let usage = p.got_cpu_usage;
+                // New processes have no norm yet and are seeded with their first reading
+                let norm = usage_norms.get(&p.key()).copied();
+                new_usage_norms.insert(
+                    p.key(),
+                    norm.map_or(usage, |norm| {
+                        ANOMALY_ALPHA * usage + (1.0 - ANOMALY_ALPHA) * norm
+                    }),
+                );
+                let Some(norm) = norm.filter(|norm| {
+                    alerting && usage >= ANOMALY_MIN_USAGE && usage > norm * multiplier
+                }) else {
+                    continue;
+                };
+
+                new_anomalous.insert(p.key());
+                if anomalous.contains(&p.key()) {
+                    continue;
+                }
+                append_line(
+                    &mut anomaly_message,
+                    &format!(
+                        "[PROCESS] CPU usage more than {:.1}x the usual {:.2}% -> [Pid: {}] Name: '{}' Usage: {:.2}%{}",
+                        Decimal(multiplier),
+                        Decimal(norm),
+                        format_pid(p.process.pid()),
+                        p.name,
+                        Decimal(usage),
+                        format_incident(incident),
+                    ),
+                );
+                if let Some(template) = &args.log_template {
+                    log_raw_to_file(
+                        &log_file,
+                        &template.render(
+                            &LogEvent {
+                                reason: "anomaly",
+                                pid: Some(p.process.pid()),
+                                name: Some(p.name),
+                                cpu: usage,
+                                threshold: norm * multiplier,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    );
+                }
+            }
+            usage_norms = new_usage_norms;
+            anomalous = new_anomalous;
+
+            if let Some(message) = anomaly_message {
+                if args.log_template.is_none() {
+                    log_to_file(&log_file, &message, &measured_at, clock.now());
+                }
+                append_line(&mut process_cpu_usage_message, &message);
+            }
+        }
+
+        // Users above `user_log_threshold`, summed up over all of their processes
+        let user_usages = match args.group_by_user {
+            true => usage_by_user(&cpu_stats),
+            false => Vec::new(),
+        };
+        let mut user_cpu_usage_message: Option<String> = None;
+        for (user, usage, count) in user_usages.iter().take_while(|(_, usage, _)| {
+            args.user_log_threshold
+                .is_some_and(|t| alerting && *usage >= t)
+        }) {
+            let threshold = args.user_log_threshold.unwrap();
+            let message = format!(
+                "[USER] Single user CPU usage threshold of {:.2}% exceeded -> User: '{}' Usage: {:.2}% ({} processes){}",
+                Decimal(threshold),
+                user,
+                Decimal(usage),
+                count,
+                format_incident(incident),
+            );
+            match &args.log_template {
+                Some(template) => log_raw_to_file(
+                    &log_file,
+                    &template.render(
+                        &LogEvent {
+                            reason: "user",
+                            pid: None,
+                            name: Some(user),
+                            cpu: *usage,
+                            threshold,
+                            incident,
+                        },
+                        &measured_at,
+                    ),
+                    clock.now(),
+                ),
+                None => log_to_file(&log_file, &message, &measured_at, clock.now()),
+            }
+            #[cfg(all(windows, feature = "eventlog"))]
+            if let Some(event_log) = &event_log {
+                event_log.report(eventlog::EventKind::Warning, &message);
+            }
+            #[cfg(all(target_os = "linux", feature = "journald"))]
+            if let Some(journal) = &journal {
+                let mut fields = journal_fields("user", total_cpu_usage, threshold, incident);
+                fields.extend([
+                    ("USER_NAME", user.to_string()),
+                    ("CPU_USER", format!("{:.2}", usage)),
+                ]);
+                journal.send(journald::Priority::Warning, &message, &fields);
+            }
+            append_line(&mut user_cpu_usage_message, &message);
+        }
+
+        // Cores that stayed busy for consecutive measurements, hidden in the total by the idle others
+        let mut core_saturation_message: Option<String> = None;
+        if let Some(threshold) = args.core_saturation_threshold.filter(|_| alerting) {
+            let duration = Duration::from_secs(args.core_saturation_duration);
+            saturated_cores
+                .retain(|&core, _| core_usages.get(core).is_some_and(|u| *u >= threshold));
+            for (core, usage) in core_usages.iter().enumerate() {
+                if *usage < threshold {
+                    continue;
+                }
+                let (since, logged) = saturated_cores
+                    .entry(core)
+                    .or_insert((measurement_start, false));
+                let saturated_for = clock.now().duration_since(*since);
+                if *logged || saturated_for < duration {
+                    continue;
+                }
+                // Once per saturation, a core has to drop below the threshold to be logged again
+                *logged = true;
+
+                let message = format!(
+                    "[CORE] Core {} above {:.2}% for {}s -> {:.2}%{}",
+                    core,
+                    Decimal(threshold),
+                    saturated_for.as_secs(),
+                    Decimal(usage),
+                    format_incident(incident),
+                );
+                match &args.log_template {
+                    Some(template) => log_raw_to_file(
+                        &log_file,
+                        &template.render(
+                            &LogEvent {
+                                reason: "core",
+                                pid: None,
+                                name: Some(&format!("cpu{}", core)),
+                                cpu: *usage,
+                                threshold,
+                                incident,
+                            },
+                            &measured_at,
+                        ),
+                        clock.now(),
+                    ),
+                    None => log_to_file(&log_file, &message, &measured_at, clock.now()),
+                }
+                #[cfg(all(windows, feature = "eventlog"))]
+                if let Some(event_log) = &event_log {
+                    event_log.report(eventlog::EventKind::Warning, &message);
+                }
+                #[cfg(all(target_os = "linux", feature = "journald"))]
+                if let Some(journal) = &journal {
+                    let mut fields = journal_fields("core", total_cpu_usage, threshold, incident);
+                    fields.extend([
+                        ("CORE", core.to_string()),
+                        ("CPU_CORE", format!("{:.2}", usage)),
+                    ]);
+                    journal.send(journald::Priority::Warning, &message, &fields);
+                }
+                append_line(&mut core_saturation_message, &message);
+            }
+        }
+
+        //* Log the top process
+        if args.always_log_top && !warming_up && !paused {
+            let top = cpu_stats.processes.first();
+            match log_format {
+                SinkFormat::Logfmt => log_raw_to_file(
+                    &log_file,
+                    &format_logfmt(
+                        &[
+                            ("ts", format_iso_time(&measured_at)),
+                            ("event", String::from("top")),
+                            ("total", format!("{:.2}", total_cpu_usage)),
+                        ]
+                        .into_iter()
+                        .chain(top.into_iter().flat_map(|p| {
+                            [
+                                ("pid", format_pid(p.process.pid())),
+                                ("name", p.name.to_string()),
+                                ("cpu", format!("{:.2}", p.got_cpu_usage)),
+                            ]
+                        }))
+                        .collect::<Vec<(&str, String)>>(),
+                    ),
+                    clock.now(),
+                ),
+                SinkFormat::Json | SinkFormat::Csv => log_raw_to_file(
+                    &log_file,
+                    &format_stats_record(
+                        log_format,
+                        "top",
+                        total_cpu_usage,
+                        &top.into_iter().collect::<Vec<&ProcessStats>>(),
+                        &[],
+                        &measured_at,
+                    ),
+                    clock.now(),
+                ),
+                _ => log_to_file(
+                    &log_file,
+                    &format!(
+                        "[TOP] {:.2}% | top: {}",
+                        Decimal(total_cpu_usage),
+                        top.map_or(String::from("-"), |p| format!(
+                            "[Pid: {}] {} {:.2}%",
+                            format_pid(p.process.pid()),
+                            p.name,
+                            Decimal(p.got_cpu_usage)
+                        )),
+                    ),
+                    &measured_at,
+                    clock.now(),
+                ),
+            }
+        }
+
+        //* Log handoffs of the top process
+        let top = cpu_stats.processes.first();
+        if args.log_on_change
+            && !warming_up
+            && !paused
+            && top.map(|p| p.key()) != top_process.as_ref().map(|(key, _)| *key)
+        {
+            let previous = top_process
+                .as_ref()
+                .map(|((pid, _), name)| (format_pid(*pid), name.as_str()));
+            match log_format {
+                SinkFormat::Logfmt => log_raw_to_file(
+                    &log_file,
+                    &format_logfmt(
+                        &[
+                            ("ts", format_iso_time(&measured_at)),
+                            ("event", String::from("top_changed")),
+                            ("total", format!("{:.2}", total_cpu_usage)),
+                        ]
+                        .into_iter()
+                        .chain(previous.iter().flat_map(|(pid, name)| {
+                            [("prev_pid", pid.clone()), ("prev_name", name.to_string())]
+                        }))
+                        .chain(top.into_iter().flat_map(|p| {
+                            [
+                                ("pid", format_pid(p.process.pid())),
+                                ("name", p.name.to_string()),
+                                ("cpu", format!("{:.2}", p.got_cpu_usage)),
+                            ]
+                        }))
+                        .collect::<Vec<(&str, String)>>(),
+                    ),
+                    clock.now(),
+                ),
+                SinkFormat::Json | SinkFormat::Csv => log_raw_to_file(
+                    &log_file,
+                    &format_stats_record(
+                        log_format,
+                        "top_changed",
+                        total_cpu_usage,
+                        &top.into_iter().collect::<Vec<&ProcessStats>>(),
+                        &[
+                            (
+                                "prev_pid",
+                                top_process
+                                    .as_ref()
+                                    .map(|((pid, _), _)| pid.as_u32())
+                                    .into(),
+                            ),
+                            (
+                                "prev_name",
+                                top_process.as_ref().map(|(_, name)| name.as_str()).into(),
+                            ),
+                        ],
+                        &measured_at,
+                    ),
+                    clock.now(),
+                ),
+                _ => log_to_file(
+                    &log_file,
+                    &format!(
+                        "[TOP] Top process changed from {} to {} | total: {:.2}%",
+                        previous.map_or(String::from("-"), |(pid, name)| format!(
+                            "[Pid: {}] {}",
+                            pid, name
+                        )),
+                        top.map_or(String::from("-"), |p| format!(
+                            "[Pid: {}] {} {:.2}%",
+                            format_pid(p.process.pid()),
+                            p.name,
+                            Decimal(p.got_cpu_usage)
+                        )),
+                        Decimal(total_cpu_usage),
+                    ),
+                    &measured_at,
+                    clock.now(),
+                ),
+            }
+        }
+        // Also tracked while paused, so resuming doesn't log a stale handoff
+        if !warming_up {
+            top_process = top.map(|p| (p.key(), p.name.to_string()));
+        }
+
+        //* Keep a quiet log alive
+        let log_quiet = log_file.as_ref().is_some_and(|f| {
+            args.heartbeat.is_some_and(|h| {
+                clock.now().duration_since(f.last_written.get()) >= Duration::from_secs(h)
+            })
+        });
+        if log_quiet {
+            match log_format {
+                SinkFormat::Logfmt => log_raw_to_file(
+                    &log_file,
+                    &format_logfmt(&[
+                        ("ts", format_iso_time(&measured_at)),
+                        ("event", String::from("heartbeat")),
+                        ("total", format!("{:.2}", total_cpu_usage)),
+                    ]),
+                    clock.now(),
+                ),
+                SinkFormat::Json | SinkFormat::Csv => log_raw_to_file(
+                    &log_file,
+                    &format_stats_record(
+                        log_format,
+                        "heartbeat",
+                        total_cpu_usage,
+                        &[],
+                        &[],
+                        &measured_at,
+                    ),
+                    clock.now(),
+                ),
+                _ => log_to_file(
+                    &log_file,
+                    &format!("[HEARTBEAT] Alive, total {:.2}%", Decimal(total_cpu_usage)),
+                    &measured_at,
+                    clock.now(),
+                ),
+            }
+        }
+
+        //* Act on persistent breaches
+        if let Some(summary) = run_summary.as_mut() {
+            summary.total_breaches += total_cpu_usage_message.is_some() as u64;
+            summary.process_breaches += alerted.len() as u64;
+        }
+
+        let mut breach_action_message: Option<String> = None;
+        let mut new_breach_counts: HashMap<ProcessKey, u32> = HashMap::new();
+        for p in cpu_stats
+            .processes
+            .iter()
+            .filter(|p| is_busy(p) && !p.settling)
+        {
+            let count = count_breach(&breach_counts, p.key());
+
+            let rule = rule(p);
+            let action = rule
+                .and_then(|rule| rule.action)
+                .unwrap_or(args.on_process_breach);
+            let Some(signal) = action.signal() else {
+                continue;
+            };
+            if count < args.breach_persist {
+                new_breach_counts.insert(p.key(), count);
+                continue;
+            }
+
+            // Only act again after another `breach_persist` measurements
+            let result = match p.process.kill_with(signal) {
+                Some(true) => "Sent",
+                Some(false) => "Failed to send",
+                None => "Platform does not support sending",
+            };
+            let message = format!(
+                "[PROCESS] {} SIG{} to [Pid: {}] Name: '{}' after {} consecutive measurements {}{}",
+                result,
+                format!("{:?}", action).to_uppercase(),
+                format_pid(p.process.pid()),
+                p.name,
+                count,
+                match (rule, &args.busy_when) {
+                    (Some(rule), _) => format!("matching rule '{}'", rule.pattern()),
+                    (None, Some(condition)) => format!("matching '{}'", condition.source()),
+                    (None, None) if p.got_cpu_usage < args.process_log_threshold => {
+                        format!("above {} MB", Decimal(args.memory_log_threshold.unwrap()))
+                    }
+                    (None, None) if memory_exceeded(p) => format!(
+                        "above {:.2}% and {} MB",
+                        Decimal(args.process_log_threshold),
+                        Decimal(args.memory_log_threshold.unwrap())
+                    ),
+                    (None, None) => {
+                        format!("above {:.2}%", Decimal(args.process_log_threshold))
+                    }
+                },
+                format_incident(incident),
+            );
+            match &args.log_template {
+                Some(template) => log_raw_to_file(
+                    &log_file,
+                    &template.render(
+                        &LogEvent {
+                            reason: "action",
+                            pid: Some(p.process.pid()),
+                            name: Some(p.name),
+                            cpu: p.got_cpu_usage,
+                            threshold: rule
+                                .and_then(|rule| rule.cpu)
+                                .unwrap_or(args.process_log_threshold),
+                            incident,
+                        },
+                        &measured_at,
+                    ),
+                    clock.now(),
+                ),
+                None => log_to_file(&log_file, &message, &measured_at, clock.now()),
+            }
+            #[cfg(all(windows, feature = "eventlog"))]
+            if let Some(event_log) = &event_log {
+                event_log.report(eventlog::EventKind::Error, &message);
+            }
+            #[cfg(all(target_os = "linux", feature = "journald"))]
+            if let Some(journal) = &journal {
+                let mut fields = journal_fields(
+                    "action",
+                    total_cpu_usage,
+                    rule.and_then(|rule| rule.cpu)
+                        .unwrap_or(args.process_log_threshold),
+                    incident,
+                );
+                fields.extend(journal_process_fields(p));
+                journal.send(journald::Priority::Error, &message, &fields);
+            }
+            append_line(&mut breach_action_message, &message);
+        }
+        breach_counts = new_breach_counts;
+
+        //* Print results
+        if args.cli && !cli_latched {
+            cli_latched = total_cpu_usage_message.is_some()
+                || process_cpu_usage_message.is_some()
+                || user_cpu_usage_message.is_some()
+                || core_saturation_message.is_some();
+            if !cli_latched && measurement_count == 0 {
+                println!("Monitoring, waiting for a threshold to be exceeded...");
+            }
+        }
+        if args.cli && cli_latched {
+            let below_display_gate = args
+                .min_total_to_display
+                .is_some_and(|gate| total_cpu_usage < gate);
+            let has_messages = [
+                &total_cpu_usage_message,
+                &process_cpu_usage_message,
+                &user_cpu_usage_message,
+                &core_saturation_message,
+                &breach_action_message,
+            ]
+            .iter()
+            .any(|message| message.is_some());
+            let redraw_throttled = !has_messages
+                && args.redraw_interval.is_some_and(|interval| {
+                    last_redraw.is_some_and(|redrawn| {
+                        clock.now().duration_since(redrawn) < Duration::from_secs(interval)
+                    })
+                });
+            match args.format {
+                // Keep the last interesting frame and only note once that it's quiet now
+                OutputFormat::Table if below_display_gate => {
+                    if !display_gated {
+                        println!(
+                            "\nIdle since {} - total {:.2} % below {:.2} %, not redrawing",
+                            format_iso_time(&measured_at),
+                            Decimal(total_cpu_usage),
+                            Decimal(args.min_total_to_display.unwrap()),
+                        );
+                        display_gated = true;
+                    }
+                }
+                // Measurements keep going, the next redraw shows the latest one
+                OutputFormat::Table if redraw_throttled => {}
+                OutputFormat::Table => {
+                    display_gated = false;
+                    last_redraw = Some(clock.now());
+
+                    // Reassure that the monitor is alive while nothing is close to a threshold
+                    let peak_process_usage =
+                        cpu_stats.processes.first().map_or(0.0, |p| p.got_cpu_usage);
+                    let total_quiet =
+                        total_log_threshold.is_none_or(|t| total_cpu_usage < t * NEAR_BREACH_RATIO);
+                    let processes_quiet =
+                        peak_process_usage < args.process_log_threshold * NEAR_BREACH_RATIO;
+                    if args.idle_message && total_quiet && processes_quiet {
+                        extra_header_lines.push(format!(
+                            "All quiet - peak process {:.2} %",
+                            Decimal(peak_process_usage)
+                        ));
+                    }
+
+                    // Ensure we only format stats if needed (settling processes are only dimmed and the gauge is
+                    // only colored on the terminal)
+                    if args.settle_delay.is_some() || layout.gauge.is_some() {
+                        formatted_stats = None;
+                    }
+                    formatted_stats = formatted_stats.or_else(|| {
+                        Some(format_stats(
+                            &cpu_stats,
+                            total_cpu_usage,
+                            number_of_processes_to_show,
+                            &layout,
+                            &extra_header_lines,
+                            true,
+                            &measured_at,
+                        ))
+                    });
+
+                    // Clear old output (we use a library because different consoles /os's support different ways of clearing)
+                    #[cfg(unix)]
+                    let paged = args.pager;
+                    #[cfg(not(unix))]
+                    let paged = false;
+                    if !paged {
+                        clear_screen(&mut clear_method);
+                    }
+
+                    // Everything below the table, to know how much room the table has
+                    let mut below_table = String::new();
+                    if args.group_by_user {
+                        below_table.push_str(&format!(
+                            "\n{}\n",
+                            format_user_stats(&user_usages, number_of_processes_to_show)
+                        ));
+                    }
+                    for message in [
+                        &total_cpu_usage_message,
+                        &process_cpu_usage_message,
+                        &user_cpu_usage_message,
+                        &core_saturation_message,
+                        &breach_action_message,
+                    ]
+                    .into_iter()
+                    .flatten()
+                    {
+                        below_table.push_str(&format!("\n{}\n", message));
+                    }
+
+                    // The sum of processes can diverge from the global figure due to rounding and sampling
+                    if args.debug && (processes_cpu_usage - global_cpu_usage).abs() > 5.0 {
+                        below_table.push_str(&format!(
+                            "\nDebug: sum of processes {:.2}% differs from global CPU usage {:.2}%\n",
+                            processes_cpu_usage, global_cpu_usage,
+                        ));
+                    }
+
+                    // Drop processes until everything fits, leaving the last line for the cursor
+                    if let Some(height) = terminal_height().filter(|_| args.compact_cli && !paged) {
+                        let available = height.saturating_sub(below_table.lines().count() + 1);
+                        let mut shown = number_of_processes_to_show;
+                        loop {
+                            let lines = formatted_stats.as_ref().unwrap().lines().count();
+                            if lines <= available || shown == 0 {
+                                break;
+                            }
+                            shown = shown.saturating_sub(lines - available);
+                            formatted_stats = Some(format_stats(
+                                &cpu_stats,
+                                total_cpu_usage,
+                                shown,
+                                &layout,
+                                &extra_header_lines,
+                                true,
+                                &measured_at,
+                            ));
+                        }
+                    }
+
+                    // Write new output
+                    println!("{}", formatted_stats.as_ref().unwrap());
+                    print!("{}", below_table);
+                }
+                OutputFormat::Oneline => {
+                    println!(
+                        "{}",
+                        format_stats_oneline(&cpu_stats, total_cpu_usage, process_count)
+                    );
+                }
+                OutputFormat::Logfmt => {
+                    println!(
+                        "{}",
+                        format_stats_logfmt(
+                            &cpu_stats,
+                            total_cpu_usage,
+                            number_of_processes_to_show,
+                            &[],
+                            &measured_at,
+                        )
+                    );
+                }
+                OutputFormat::Json | OutputFormat::Csv => {
+                    if args.format == OutputFormat::Csv && !csv_header_printed {
+                        println!("{}", csv_header());
+                        csv_header_printed = true;
+                    }
+                    let top = cpu_stats
+                        .processes
+                        .iter()
+                        .take(number_of_processes_to_show)
+                        .collect::<Vec<&ProcessStats>>();
+                    println!(
+                        "{}",
+                        match args.format {
+                            OutputFormat::Csv =>
+                                format_stats_csv("measurement", total_cpu_usage, &top, &measured_at),
+                            _ => format_stats_json(
+                                "measurement",
+                                total_cpu_usage,
+                                &top,
+                                &[],
+                                &measured_at
+                            ),
+                        }
+                    );
+                }
+            }
+        }
+
+        if let Some(graphite_sink) = graphite_sink.as_mut().filter(|_| !warming_up) {
+            graphite_sink.send(
+                &format_stats_graphite(
+                    &cpu_stats,
+                    total_cpu_usage,
+                    number_of_processes_to_show,
+                    &args.graphite_prefix,
+                    args.instance_tag.as_deref(),
+                    &measured_at,
+                ),
+                clock.now(),
+            );
+        }
+
+        let snapshot = || {
+            let snapshot = snapshot_json(
+                &cpu_stats,
+                total_cpu_usage,
+                normalized_total_cpu_usage,
+                cpu_count,
+                number_of_processes_to_show,
+                &measured_at,
+                &instance,
+            );
+            match args.json_detail {
+                JsonDetail::Summary => summarize_snapshot(snapshot),
+                JsonDetail::Full => snapshot,
+            }
+        };
+
+        if args.export_on_exit.is_some() {
+            if history.len() as u64 >= args.history_size {
+                history.pop_front();
+            }
+            history.push_back(match export_format {
+                SinkFormat::Logfmt => format_stats_logfmt(
+                    &cpu_stats,
+                    total_cpu_usage,
+                    number_of_processes_to_show,
+                    &[],
+                    &measured_at,
+                ),
+                _ => snapshot().to_string(),
+            });
+        }
+
+        #[cfg(feature = "kafka")]
+        if let Some(kafka_sink) = kafka_sink.as_ref().filter(|_| !warming_up) {
+            kafka_sink.send(&snapshot());
+        }
+
+        // Tell systemd we are up (after the first measurement) and still alive
+        #[cfg(all(feature = "sd-notify", target_os = "linux"))]
+        {
+            if !notified_ready {
+                let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]);
+                notified_ready = true;
+            }
+            if watchdog_enabled {
+                let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Watchdog]);
+            }
+        }
+
+        measurement_count += 1;
+        if args
+            .iterations
+            .is_some_and(|iterations| measurement_count >= iterations)
+        {
+            break;
+        }
+
+        // Sample less often while quiet and more often while busy
+        if args.interval_profile == IntervalProfile::Adaptive {
+            if total_cpu_usage < args.interval_low_water {
+                time_between_measurements = (time_between_measurements * 2).max(1);
+            } else if total_cpu_usage > args.interval_high_water {
+                time_between_measurements /= 2;
+            }
+            time_between_measurements = time_between_measurements
+                .clamp(args.min_interval, args.max_interval.max(args.min_interval));
+        }
+
+        // Wait for next iteration
+        CYCLE_STARTED_MS.store(0, Ordering::SeqCst);
+        let wait = match args.period_semantics {
+            PeriodSemantics::Gap => Duration::from_secs(time_between_measurements),
+            PeriodSemantics::Total => Duration::from_secs(time_between_measurements)
+                .saturating_sub(clock.now().duration_since(period_start)),
+        };
+        sleep_while_running(clock, running, until_deadline(wait));
+    }
+
+    //* Shutdown
+    #[cfg(feature = "kafka")]
+    if let Some(kafka_sink) = &kafka_sink {
+        kafka_sink.flush();
+    }
+
+    #[cfg(all(feature = "sd-notify", target_os = "linux"))]
+    let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+
+    // Don't lose the last (partial) hour
+    if let Some(message) = hourly_summary.as_ref().and_then(HourlySummary::format) {
+        log_to_file(&log_file, &message, &clock.local_now(), clock.now());
+    }
+
+    if args.histogram {
+        let formatted_histogram = histogram.format();
+        println!("{}", formatted_histogram);
+
+        if args.log_histogram {
+            log_to_file(
+                &log_file,
+                &formatted_histogram,
+                &clock.local_now(),
+                clock.now(),
+            );
+        }
+    }
+
+    if let Some(SinkTarget { path, .. }) = &args.export_on_exit {
+        let count = history.len();
+        let mut export = history.into_iter().collect::<Vec<String>>().join("\n");
+        export.push('\n');
+        match write_atomic(Path::new(path), &export) {
+            Ok(()) => println!("Exported {} measurements to '{}'", count, path),
+            Err(e) => eprintln!("Failed to export measurements to '{}': {}", path, e),
+        }
+    }
+
+    if let (Some(path), Some(summary)) = (&args.summary_json, &run_summary) {
+        let summary = summary
+            .to_json(
+                measurement_count,
+                clock.now(),
+                &clock.local_now(),
+                &instance,
+            )
+            .to_string();
+        match path.as_str() {
+            "-" => println!("{}", summary),
+            path => {
+                if let Err(e) = write_atomic(Path::new(path), &(summary + "\n")) {
+                    eprintln!("Failed to write summary to '{}': {}", path, e);
+                }
+            }
+        }
+    }
+
+    let stopped = format!("Stopped after {} measurements", measurement_count);
+    log_to_file(&log_file, &stopped, &clock.local_now(), clock.now());
+    if let Some(log_file) = &log_file {
+        if let LogTarget::File(file) = &*log_file.target.borrow() {
+            let _ = file.sync_all();
+        }
+    }
+    // Structured output stays parseable
+    if args.cli {
+        match args.format {
+            OutputFormat::Table | OutputFormat::Oneline => println!("{}", stopped),
+            _ => eprintln!("{}", stopped),
+        }
+    }
+    // Don't hide that warnings were suppressed right before stopping
+    flush_warnings();
+}
+
+/// Add the usage of every process to its topmost ancestor using at least `threshold` on its own,
+/// removing the children from the list unless `expand` is set. Returns the number of children
+fn collapse_into_busy_parents(
+    processes: &mut Vec<ProcessStats>,
+    threshold: f32,
+    expand: bool,
+) -> usize {
+    // Guards against PID reuse forming a loop between snapshots
+    const MAX_DEPTH: usize = 64;
+
+    let index_of: HashMap<Pid, usize> = processes
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (p.process.pid(), i))
+        .collect();
+    let parent_of = |i: usize| {
+        processes[i]
+            .process
+            .parent()
+            .and_then(|pid| index_of.get(&pid).copied())
+    };
+    let targets: Vec<Option<usize>> = (0..processes.len())
+        .map(|i| {
+            std::iter::successors(parent_of(i), |&parent| parent_of(parent))
+                .take(MAX_DEPTH)
+                .filter(|&parent| processes[parent].got_cpu_usage >= threshold)
+                .last()
+        })
+        .collect();
+
+    // Only the usage is summed, the other values stay the parent's own
+    for (i, target) in targets.iter().enumerate() {
+        if let Some(target) = *target {
+            let (usage, raw_usage) = (processes[i].got_cpu_usage, processes[i].raw_cpu_usage);
+            let parent = &mut processes[target];
+            parent.got_cpu_usage += usage;
+            parent.raw_cpu_usage = parent.raw_cpu_usage.zip(raw_usage).map(|(a, b)| a + b);
+        }
+    }
+
+    if !expand {
+        let mut targets = targets.iter();
+        processes.retain(|_| targets.next().unwrap().is_none());
+    }
+
+    targets.iter().filter(|t| t.is_some()).count()
+}
+
+/// Merge the top processes with the same name into the first (busiest) of them, summing their usage
+fn collapse_by_name<'a>(cpu_stats: &CPUStats<'a>, num_processes: usize) -> CPUStats<'a> {
+    let mut processes: Vec<ProcessStats<'a>> = Vec::new();
+    let mut index_of: HashMap<&str, usize> = HashMap::new();
+    for p in cpu_stats.processes.iter().take(num_processes) {
+        match index_of.get(p.name) {
+            Some(&i) => {
+                let merged = &mut processes[i];
+                merged.got_cpu_usage += p.got_cpu_usage;
+                merged.raw_cpu_usage = merged
+                    .raw_cpu_usage
+                    .zip(p.raw_cpu_usage)
+                    .map(|(a, b)| a + b);
+                merged.share_of_total += p.share_of_total;
+                merged.avg_cpu_usage += p.avg_cpu_usage;
+                merged.fd_count = merged.fd_count.zip(p.fd_count).map(|(a, b)| a + b);
+                merged.pinned |= p.pinned;
+                merged.merged += 1;
+                merged.memory += p.memory;
+            }
+            None => {
+                index_of.insert(p.name, processes.len());
+                processes.push(p.clone());
+            }
+        }
+    }
+    // Merging may have moved a group above a busier single process
+    processes.sort_by(|a, b| b.got_cpu_usage.total_cmp(&a.got_cpu_usage));
+
+    CPUStats {
+        processes,
+        interval: cpu_stats.interval,
+        breakdown: cpu_stats.breakdown,
+        power: cpu_stats.power,
+        swap_rates: cpu_stats.swap_rates,
+        fd_total: cpu_stats.fd_total,
+    }
+}
+
+/// Poll until a process of `pid_file` or `watch_port` runs, returns whether one did before `timeout` passed
+fn wait_for_watched_process(
+    args: &Args,
+    source: &mut dyn ProcessSource,
+    clock: &dyn Clock,
+    running: &AtomicBool,
+    timeout: Duration,
+) -> bool {
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    let deadline = clock.now() + timeout;
+    while running.load(Ordering::SeqCst) {
+        source.refresh(false);
+        let processes = source.processes();
+        let mut pids = processes.iter().map(|p| p.pid());
+        let appeared = match &args.pid_file {
+            Some(pid_file) => {
+                read_pid_file(pid_file).is_ok_and(|watched| pids.any(|pid| watched.contains(&pid)))
+            }
+            // Without a way to map ports, all processes are watched
+            None => {
+                read_port_owners(&args.watch_port, pids).is_none_or(|owners| !owners.is_empty())
+            }
+        };
+        if appeared {
+            return true;
+        }
+
+        let remaining = deadline.saturating_duration_since(clock.now());
+        if remaining.is_zero() {
+            break;
+        }
+        sleep_while_running(clock, running, remaining.min(POLL_INTERVAL));
+    }
+
+    false
+}
+
+/// Sleep in small steps, returning early once `running` is cleared
+fn sleep_while_running(clock: &dyn Clock, running: &AtomicBool, duration: Duration) {
+    const STEP: Duration = Duration::from_millis(100);
+
+    let deadline = clock.now() + duration;
+    while running.load(Ordering::SeqCst) {
+        let remaining = deadline.saturating_duration_since(clock.now());
+        if remaining.is_zero() {
+            break;
+        }
+        clock.sleep(remaining.min(STEP));
+    }
+}
+
+/// Milliseconds since the epoch
+fn epoch_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Watch [CYCLE_STARTED_MS] on a background thread and act once a cycle runs for longer than `timeout`
+fn spawn_cycle_watchdog(timeout: Duration, action: CycleTimeoutAction, log_path: Option<String>) {
+    std::thread::spawn(move || {
+        // Only report every hanging cycle once
+        let mut reported_cycle = 0;
+        loop {
+            std::thread::sleep(Duration::from_secs(1));
+
+            let started = CYCLE_STARTED_MS.load(Ordering::SeqCst);
+            let elapsed = Duration::from_millis(epoch_millis().saturating_sub(started));
+            if started == 0 || elapsed <= timeout || started == reported_cycle {
+                continue;
+            }
+            reported_cycle = started;
+
+            let message = format!(
+                "[ERROR] Measurement cycle running for {:.1}s exceeded the cycle timeout of {}s{}",
+                elapsed.as_secs_f32(),
+                timeout.as_secs(),
+                match action {
+                    CycleTimeoutAction::Log => "",
+                    CycleTimeoutAction::Abort => " -> exiting",
+                },
+            );
+            eprintln!("{}", message);
+            // The log file belongs to the hanging measurement loop, write through a handle of our own
+            if let Some(path) = &log_path {
+                if let Ok(mut file) = open_append(path) {
+                    let _ = writeln!(file, "{} | {}", format_iso_time(&Local::now()), message);
+                }
+            }
+
+            if action == CycleTimeoutAction::Abort {
+                std::process::exit(1);
+            }
+        }
+    });
+}
+
+/// Formats stats into a nice looking table
+fn format_stats(
+    cpu_stats: &CPUStats,
+    total_cpu_usage: f32,
+    num_processes: usize,
+    layout: &TableLayout,
+    extra_header_lines: &[String],
+    styled: bool,
+    measured_at: &DateTime<Local>,
+) -> String {
+    let columns = &layout.columns;
+    let widths = column_widths(columns);
+    let width = 1 + widths.iter().map(|w| w + 3).sum::<usize>();
+
+    // Pinned processes are always shown and don't take up the slots of the others
+    let shown = cpu_stats
+        .processes
+        .iter()
+        .filter(|p| p.pinned)
+        .chain(
+            cpu_stats
+                .processes
+                .iter()
+                .filter(|p| !p.pinned)
+                .take(num_processes),
+        )
+        .collect::<Vec<&ProcessStats>>();
+    let mut titles = columns
+        .iter()
+        .map(|c| c.title().to_string())
+        .collect::<Vec<String>>();
+    let mut rows = shown
+        .iter()
+        .map(|p| columns.iter().map(|c| c.value(p)).collect::<Vec<String>>())
+        .collect::<Vec<Vec<String>>>();
+    if let Some(i) = columns.iter().position(|c| *c == Column::Cpu) {
+        let mut values = rows
+            .iter_mut()
+            .map(|row| &mut row[i])
+            .collect::<Vec<&mut String>>();
+        align_column(&mut titles[i], &mut values, widths[i], layout.usage_align);
+    }
+
+    format!(
+        "{header}\n{total_cpu_usage}{gauge}\n{timestamp}{extra_header_lines}\n{divider}\n{column_names}\n{column_names_divider}\n{stats}\n{footer}",
+        header = format_args!("{:-^width$}", layout.expand(&layout.title, measured_at)),
+        total_cpu_usage = {
+            let total = format!("{:.2} %", Decimal(total_cpu_usage));
+            let line = format!("|{: ^inner$}|", total, inner = width - 2);
+            // Colored after padding, the color codes would count towards the width
+            match styled && !layout.color_bands.is_empty() {
+                true => line.replacen(&total, &format!("{}{}\x1b[0m", layout.band_color(total_cpu_usage), total), 1),
+                false => line,
+            }
+        },
+        // Padded by hand, the color codes would count towards the width
+        gauge = layout.gauge.as_ref().map_or(String::new(), |gauge| {
+            format!("\n| {} |", gauge.format(total_cpu_usage, width - 4, styled))
+        }),
+        timestamp = format_args!("|{: ^inner$}|", format_iso_time(measured_at), inner = width - 2),
+        extra_header_lines = extra_header_lines
+            .iter()
+            .map(|l| format!("\n|{: ^inner$}|", l, inner = width - 2))
+            .collect::<String>(),
+        divider = format_args!("{:-^width$}", ""),
+        column_names = format_row(&titles, &widths),
+        column_names_divider = widths.iter().map(|w| format!("|{:-<w$}", "", w = w + 2)).collect::<String>() + "|",
+        stats = shown.iter().zip(&rows).map(|(p, values)| {
+            let row = format_row(values, &widths);
+            let mut style = String::new();
+            if styled && p.settling {
+                style += "\x1b[2m";
+            }
+            if styled && p.pinned {
+                style += "\x1b[1;7m";
+            }
+            if let Some(color) = layout.usage_color(p.got_cpu_usage).filter(|_| styled) {
+                style += &color;
+            }
+            match style.is_empty() {
+                true => row,
+                false => format!("{}{}\x1b[0m", style, row),
+            }
+        }).fold(String::new(), |ret, new| format!("{}\n{}", ret, new)).trim(),
+        footer = format_args!("{:-^width$}", layout.footer.as_deref().map_or(String::new(), |f| layout.expand(f, measured_at))),
+    )
+}
+
+/// Align the values of a column (and its title) within the column width
+fn align_column(
+    title: &mut String,
+    values: &mut [&mut String],
+    width: usize,
+    alignment: Alignment,
+) {
+    match alignment {
+        Alignment::Left => {}
+        Alignment::Right => {
+            *title = format!("{: >width$}", title, width = width);
+            for value in values.iter_mut() {
+                **value = format!("{: >width$}", value, width = width);
+            }
+        }
+        Alignment::Decimal => {
+            // Integer digits of a value, the whole value if it has no decimal point
+            let integer_width = |value: &str| value.find('.').unwrap_or(value.len());
+            let max_integer_width = values.iter().map(|v| integer_width(v)).max().unwrap_or(0);
+            for value in values.iter_mut() {
+                let padding = max_integer_width - integer_width(value);
+                **value = format!("{}{}", " ".repeat(padding), value);
+            }
+        }
+    }
+}
+
+/// Columns and labels of the table printed by [format_stats]
+struct TableLayout {
+    columns: Vec<Column>,
+    title: String,
+    footer: Option<String>,
+    /// Substituted for `{host}` in the title and footer
+    host: String,
+    /// Only applied to styled (CLI) output
+    color_scheme: ColorScheme,
+    /// Usage the row colors are relative to
+    color_threshold: f32,
+    /// Ascending `color_thresholds`, replacing the color scheme if set
+    color_bands: Vec<f32>,
+    usage_align: Alignment,
+    /// Only set if `gauge` is used
+    gauge: Option<Gauge>,
+}
+
+/// Bar of the total CPU usage in the table header
+struct Gauge {
+    /// Usage of a full bar according to `total_scale`
+    max: f32,
+    /// The bar turns yellow from 80% of this usage on and red from the usage on
+    threshold: f32,
+    /// Stdout is a terminal and `NO_COLOR` isn't set
+    colored: bool,
+}
+
+impl Gauge {
+    /// Format the bar to be `width` characters wide, colored only if `styled`
+    fn format(&self, usage: f32, width: usize, styled: bool) -> String {
+        let label = format!(" {:.2} %", Decimal(usage));
+        let bar_width = width.saturating_sub(label.len() + 2);
+        let filled = ((usage / self.max.max(f32::EPSILON)).clamp(0.0, 1.0) * bar_width as f32)
+            .round() as usize;
+        let fill = "#".repeat(filled);
+        let fill = match styled && self.colored && filled > 0 {
+            true => format!(
+                "{}{}\x1b[0m",
+                match usage {
+                    u if u >= self.threshold => "\x1b[31m",
+                    u if u >= self.threshold * 0.8 => "\x1b[33m",
+                    _ => "\x1b[32m",
+                },
+                fill
+            ),
+            false => fill,
+        };
+
+        format!("[{}{}]{}", fill, ".".repeat(bar_width - filled), label)
+    }
+}
+
+impl TableLayout {
+    /// Replace the `{host}` and `{time}` placeholders of a label
+    fn expand(&self, label: &str, time: &DateTime<Local>) -> String {
+        label
+            .replace("{host}", &self.host)
+            .replace("{time}", &format_iso_time(time))
+    }
+
+    /// ANSI sequence coloring a row by its usage according to the color scheme
+    fn usage_color(&self, usage: f32) -> Option<String> {
+        if !self.color_bands.is_empty() {
+            return Some(self.band_color(usage).to_string());
+        }
+
+        let threshold = self.color_threshold.max(f32::EPSILON);
+        match self.color_scheme {
+            ColorScheme::None => None,
+            ColorScheme::Threshold => Some(String::from(match usage {
+                u if u >= threshold => "\x1b[31m",
+                u if u >= threshold * 0.8 => "\x1b[33m",
+                _ => "\x1b[32m",
+            })),
+            ColorScheme::Gradient => {
+                // Fade in red up to half the threshold, then fade out green (24 bit colors)
+                let t = (usage / threshold).clamp(0.0, 1.0);
+                let red = (220.0 * (t * 2.0).min(1.0)) as u8;
+                let green = (200.0 * (2.0 - t * 2.0).min(1.0)) as u8;
+                Some(format!("\x1b[38;2;{};{};0m", red, green))
+            }
+        }
+    }
+
+    /// ANSI sequence of the highest of the `color_bands` a usage reached, green below all of them
+    fn band_color(&self, usage: f32) -> &'static str {
+        // Yellow, orange (256 colors) and red, the highest bands get the most severe colors
+        const COLORS: [&str; 3] = ["\x1b[33m", "\x1b[38;5;208m", "\x1b[31m"];
+        let reached = self
+            .color_bands
+            .iter()
+            .filter(|&&band| usage >= band)
+            .count();
+        match reached {
+            0 => "\x1b[32m",
+            _ => COLORS[COLORS.len() - self.color_bands.len() + reached - 1],
+        }
+    }
+}
+
+/// Widths of the table columns, the name column takes up the remaining space of an 80 wide table
+fn column_widths(columns: &[Column]) -> Vec<usize> {
+    let fixed_width: usize = columns
+        .iter()
+        .filter_map(|c| c.width())
+        .map(|w| w + 3)
+        .sum();
+    let name_width = 79usize.saturating_sub(fixed_width + 3).max(15);
+
+    columns
+        .iter()
+        .map(|c| c.width().unwrap_or(name_width))
+        .collect()
+}
+
+/// Formats a single row of the table
+fn format_row(values: &[String], widths: &[usize]) -> String {
+    values
+        .iter()
+        .zip(widths)
+        .map(|(v, w)| format!("| {: <w$} ", v, w = w))
+        .collect::<String>()
+        + "|"
+}
+
+/// Serializes a measurement with the top processes to JSON
+fn snapshot_json(
+    cpu_stats: &CPUStats,
+    total_cpu_usage: f32,
+    normalized_total_cpu_usage: f32,
+    cpu_count: f32,
+    num_processes: usize,
+    measured_at: &DateTime<Local>,
+    instance: &str,
+) -> serde_json::Value {
+    serde_json::json!({
+        "schema_version": JSON_SCHEMA_VERSION,
+        "timestamp": json_timestamp(measured_at),
+        "timestamp_iso": json_iso_timestamp(measured_at),
+        "instance": instance,
+        "total_cpu_usage": total_cpu_usage,
+        "cpu_percent_normalized": normalized_total_cpu_usage,
+        "cpu_percent_per_core": normalized_total_cpu_usage * cpu_count,
+        "interval_secs": cpu_stats.interval.as_secs_f64(),
+        "swap_in_bytes_per_sec": cpu_stats.swap_rates.map(|(swapped_in, _)| swapped_in),
+        "swap_out_bytes_per_sec": cpu_stats.swap_rates.map(|(_, swapped_out)| swapped_out),
+        "fd_total": cpu_stats.fd_total,
+        "processes": cpu_stats
+            .processes
+            .iter()
+            .take(num_processes)
+            .map(|p| {
+                serde_json::json!({
+                    "pid": p.process.pid().as_u32(),
+                    "name": p.name,
+                    "start_time": p.process.start_time(),
+                    "memory": p.memory,
+                    "got_cpu_usage": p.got_cpu_usage,
+                    "cpu_percent_normalized": p.got_cpu_usage,
+                    "cpu_percent_per_core": p.got_cpu_usage * cpu_count,
+                    "raw_cpu_usage": p.raw_cpu_usage,
+                    "share_of_total": p.share_of_total,
+                    "avg_cpu": p.avg_cpu_usage,
+                    "ctxsw_voluntary": p.context_switches.map(|(voluntary, _)| voluntary),
+                    "ctxsw_involuntary": p.context_switches.map(|(_, involuntary)| involuntary),
+                    "minor_faults": p.page_faults.map(|(minor, _)| minor),
+                    "major_faults": p.page_faults.map(|(_, major)| major),
+                    "nice": p.priority.map(|(nice, _)| nice),
+                    "rt_priority": p.priority.and_then(|(_, rt_priority)| rt_priority),
+                    "fds": p.fd_count,
+                    "inaccessible": p.inaccessible,
+                })
+            })
+            .collect::<Vec<serde_json::Value>>(),
+    })
+}
+
+/// Reduce a snapshot to the total and the top process, keeping only the fields the schema requires
+fn summarize_snapshot(mut snapshot: serde_json::Value) -> serde_json::Value {
+    const FIELDS: [&str; 8] = [
+        "schema_version",
+        "timestamp",
+        "instance",
+        "total_cpu_usage",
+        "cpu_percent_normalized",
+        "cpu_percent_per_core",
+        "interval_secs",
+        "processes",
+    ];
+    const PROCESS_FIELDS: [&str; 10] = [
+        "pid",
+        "name",
+        "start_time",
+        "memory",
+        "got_cpu_usage",
+        "cpu_percent_normalized",
+        "cpu_percent_per_core",
+        "raw_cpu_usage",
+        "share_of_total",
+        "avg_cpu",
+    ];
+
+    if let Some(snapshot) = snapshot.as_object_mut() {
+        snapshot.retain(|field, _| FIELDS.contains(&field.as_str()));
+    }
+    if let Some(processes) = snapshot["processes"].as_array_mut() {
+        processes.truncate(1);
+        for process in processes.iter_mut().filter_map(|p| p.as_object_mut()) {
+            process.retain(|field, _| PROCESS_FIELDS.contains(&field.as_str()));
+        }
+    }
+
+    snapshot
+}
+
+/// Keep a core busy and check that this process is measured using it, returns whether it was
+fn self_test(source: &mut dyn ProcessSource, clock: &dyn Clock) -> bool {
+    const LOAD_DURATION: Duration = Duration::from_secs(3);
+    // sysinfo ignores readings of processes without any CPU time so far, like this one after starting
+    const SETTLE_DURATION: Duration = Duration::from_secs(1);
+    // In percent of a single core, leaves room for scheduling noise on busy systems
+    const MIN_DETECTED: f32 = 50.0;
+
+    let pid = sysinfo::get_current_pid().expect("failed to get own PID");
+    let stop = Arc::new(AtomicBool::new(false));
+    let load = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                std::hint::spin_loop();
+            }
+        })
+    };
+
+    println!(
+        "Self-test: generating load on one core for {}s...",
+        (SETTLE_DURATION + LOAD_DURATION).as_secs()
+    );
+    clock.sleep(SETTLE_DURATION);
+    source.refresh(false);
+    clock.sleep(LOAD_DURATION);
+    source.refresh(false);
+    stop.store(true, Ordering::Relaxed);
+    load.join().expect("load thread panicked");
+
+    let Some(detected) = source
+        .processes()
+        .iter()
+        .find(|p| p.pid() == pid)
+        .map(|p| p.cpu_usage())
+    else {
+        println!("Self-test failed: this process (PID {}) was not found", pid);
+        return false;
+    };
+
+    let normalized = detected / source.core_count() as f32;
+    let passed = detected >= MIN_DETECTED;
+    println!(
+        "Self-test {}: measured {:.2}% of a core ({:.2}% normalized to {} logical cores), expected at least {:.0}%",
+        if passed { "passed" } else { "failed" },
+        detected,
+        normalized,
+        source.core_count(),
+        MIN_DETECTED,
+    );
+
+    passed
+}
+
+/// Print every process of `source` sorted by PID
+fn list_processes(source: &mut dyn ProcessSource, format: ListFormat) {
+    source.resolve_users();
+    source.refresh(false);
+
+    let mut processes = source.processes();
+    processes.sort_by_key(|p| p.pid());
+    let rows = processes
+        .iter()
+        .map(|p| {
+            [
+                format_pid(p.pid()),
+                p.name().to_string(),
+                full_name(*p).to_string(),
+                source.user_name(p.pid()).unwrap_or("").to_string(),
+                p.exe().display().to_string(),
+                p.cmd().join(" "),
+            ]
+        })
+        .collect::<Vec<[String; 6]>>();
+
+    match format {
+        ListFormat::Table => {
+            let titles =
+                ["PID", "Name", "Full name", "User", "Executable", "Command"].map(String::from);
+            // No fixed widths, every column is as wide as its longest value
+            let widths = (0..titles.len())
+                .map(|i| {
+                    rows.iter()
+                        .chain([&titles])
+                        .map(|row| row[i].chars().count())
+                        .max()
+                        .unwrap()
+                })
+                .collect::<Vec<usize>>();
+
+            println!("{}", format_row(&titles, &widths));
+            for row in &rows {
+                println!("{}", format_row(row, &widths));
+            }
+        }
+        ListFormat::Json => {
+            let processes = processes
+                .iter()
+                .zip(&rows)
+                .map(|(p, [_, name, full_name, user, exe, cmd])| {
+                    serde_json::json!({
+                        "pid": p.pid().as_u32(),
+                        "name": name,
+                        "full_name": full_name,
+                        "user": user,
+                        "exe": exe,
+                        "cmd": cmd,
+                    })
+                })
+                .collect::<Vec<serde_json::Value>>();
+            println!("{:#}", serde_json::Value::Array(processes));
+        }
+    }
+}
+
+/// JSON Schema describing the breach events, measurement snapshots and run summary
+fn json_schema() -> serde_json::Value {
+    let number = serde_json::json!({ "type": "number" });
+    let nullable_number = serde_json::json!({ "type": ["number", "null"] });
+    let timestamp = serde_json::json!({
+        "oneOf": [
+            { "type": "string", "format": "date-time" },
+            { "type": "integer", "description": "Unix epoch in seconds or milliseconds with --json-timestamp-epoch" },
+        ],
+    });
+    let timestamp_iso = serde_json::json!({
+        "type": ["string", "null"],
+        "format": "date-time",
+        "description": "Only set with --json-iso-timestamp",
+    });
+    let schema_version = serde_json::json!({ "const": JSON_SCHEMA_VERSION });
+    let instance = serde_json::json!({
+        "type": "string",
+        "description": "--instance-tag, the hostname by default",
+    });
+    let normalized = serde_json::json!({
+        "type": "number",
+        "description": "CPU usage where 100 % means all cores are busy",
+    });
+    let per_core = serde_json::json!({
+        "type": "number",
+        "description": "CPU usage where 100 % means a single core is busy",
+    });
+    let swap_rate = serde_json::json!({
+        "type": ["number", "null"],
+        "description": "System wide, only set with --show-swap-activity",
+    });
+
+    serde_json::json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "cpu-usage-logger output",
+        "oneOf": [
+            { "$ref": "#/definitions/event" },
+            { "$ref": "#/definitions/snapshot" },
+            { "$ref": "#/definitions/summary" },
+            { "$ref": "#/definitions/incident" },
+        ],
+        "definitions": {
+            "event": {
+                "description": "Threshold breach, written to the event FIFO",
+                "type": "object",
+                "required": [
+                    "schema_version", "timestamp", "instance", "reason", "threshold", "value", "over_by",
+                    "cpu_percent_normalized", "cpu_percent_per_core", "interval_secs",
+                    "core_count", "core_kind",
+                ],
+                "properties": {
+                    "schema_version": schema_version,
+                    "timestamp": timestamp,
+                    "timestamp_iso": timestamp_iso,
+                    "instance": instance,
+                    "reason": { "enum": ["total", "process"] },
+                    "threshold": { "type": "number", "description": "Breached threshold in percent" },
+                    "value": { "type": "number", "description": "Measured usage in percent" },
+                    "over_by": {
+                        "type": "number",
+                        "description": "Percentage points the value is above the threshold",
+                    },
+                    "cpu_percent_normalized": normalized,
+                    "cpu_percent_per_core": per_core,
+                    "interval_secs": number,
+                    "iowait": nullable_number,
+                    "steal": nullable_number,
+                    "swap_in_bytes_per_sec": swap_rate,
+                    "swap_out_bytes_per_sec": swap_rate,
+                    "core_count": { "type": "integer" },
+                    "core_kind": { "type": "string" },
+                    "pid": { "type": "integer", "description": "Only set for process breaches" },
+                    "name": { "type": "string", "description": "Only set for process breaches" },
+                    "exe_sha256": {
+                        "type": ["string", "null"],
+                        "description": "Only set for process breaches, null unless hashed with --hash-exe",
+                    },
+                },
+            },
+            "snapshot": {
+                "description": "Measurement with the top processes, published to Kafka and written by --export-on-exit",
+                "type": "object",
+                "required": [
+                    "schema_version", "timestamp", "instance", "total_cpu_usage", "cpu_percent_normalized",
+                    "cpu_percent_per_core", "interval_secs", "processes",
+                ],
+                "properties": {
+                    "schema_version": schema_version,
+                    "timestamp": timestamp,
+                    "timestamp_iso": timestamp_iso,
+                    "instance": instance,
+                    "total_cpu_usage": number,
+                    "cpu_percent_normalized": normalized,
+                    "cpu_percent_per_core": per_core,
+                    "interval_secs": number,
+                    "swap_in_bytes_per_sec": swap_rate,
+                    "swap_out_bytes_per_sec": swap_rate,
+                    "fd_total": {
+                        "description": "File descriptors allocated system wide, only set with --show-fds",
+                        "type": ["integer", "null"],
+                    },
+                    "processes": {
+                        "description": "Top processes, only the top one with its required fields with --json-detail summary",
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": [
+                                "pid", "name", "start_time", "memory", "got_cpu_usage",
+                                "cpu_percent_normalized", "cpu_percent_per_core", "raw_cpu_usage",
+                                "share_of_total", "avg_cpu",
+                            ],
+                            "properties": {
+                                "pid": { "type": "integer" },
+                                "name": { "type": "string" },
+                                "start_time": { "type": "integer", "description": "Seconds since the epoch" },
+                                "memory": { "type": "integer", "description": "Resident memory in bytes" },
+                                "got_cpu_usage": number,
+                                "cpu_percent_normalized": normalized,
+                                "cpu_percent_per_core": per_core,
+                                "raw_cpu_usage": nullable_number,
+                                "share_of_total": number,
+                                "avg_cpu": number,
+                                "ctxsw_voluntary": { "type": ["integer", "null"] },
+                                "ctxsw_involuntary": { "type": ["integer", "null"] },
+                                "minor_faults": {
+                                    "description": "During the measurement, only set with --show-faults",
+                                    "type": ["integer", "null"],
+                                },
+                                "major_faults": {
+                                    "description": "During the measurement, only set with --show-faults",
+                                    "type": ["integer", "null"],
+                                },
+                                "nice": {
+                                    "description": "Only set with --show-priority",
+                                    "type": ["integer", "null"],
+                                },
+                                "rt_priority": {
+                                    "description": "Only set with --show-priority for processes with a real-time scheduling policy",
+                                    "type": ["integer", "null"],
+                                },
+                                "fds": {
+                                    "description": "Open file descriptors, only set with --show-fds for accessible processes",
+                                    "type": ["integer", "null"],
+                                },
+                                "inaccessible": {
+                                    "description": "Stats couldn't be read, only set with --mark-inaccessible",
+                                    "type": "boolean",
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+            "summary": {
+                "description": "Totals of a run, written on shutdown by --summary-json",
+                "type": "object",
+                "required": [
+                    "schema_version", "timestamp", "instance", "measurements", "total_breaches",
+                    "process_breaches", "peak_total_cpu_usage", "peak_process", "duration_secs",
+                ],
+                "properties": {
+                    "schema_version": schema_version,
+                    "timestamp": timestamp,
+                    "timestamp_iso": timestamp_iso,
+                    "instance": instance,
+                    "measurements": { "type": "integer" },
+                    "total_breaches": { "type": "integer", "description": "Measurements above the total threshold" },
+                    "process_breaches": { "type": "integer", "description": "Processes above the process threshold summed over all measurements" },
+                    "peak_total_cpu_usage": nullable_number,
+                    "peak_process": {
+                        "type": ["object", "null"],
+                        "properties": {
+                            "name": { "type": "string" },
+                            "pid": { "type": "integer" },
+                            "cpu_usage": number,
+                        },
+                    },
+                    "duration_secs": number,
+                },
+            },
+            "incident": {
+                "description": "Total breach through its recovery, written by --incident-dir",
+                "type": "object",
+                "required": [
+                    "schema_version", "timestamp", "instance", "incident", "started_at", "ended_at",
+                    "duration_secs", "threshold", "peak_total_cpu_usage", "measurements", "processes", "timeline",
+                ],
+                "properties": {
+                    "schema_version": schema_version,
+                    "timestamp": timestamp,
+                    "timestamp_iso": timestamp_iso,
+                    "instance": instance,
+                    "incident": {
+                        "type": ["integer", "null"],
+                        "description": "Only set with --incident-ids",
+                    },
+                    "started_at": timestamp,
+                    "ended_at": timestamp,
+                    "duration_secs": number,
+                    "threshold": { "type": "number", "description": "Breached total threshold in percent" },
+                    "peak_total_cpu_usage": number,
+                    "measurements": { "type": "integer", "description": "Measurements above the threshold" },
+                    "processes": {
+                        "description": "Processes with the most CPU time during the incident",
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["name", "pid", "cpu_percent_secs", "avg_cpu_usage"],
+                            "properties": {
+                                "name": { "type": "string" },
+                                "pid": { "type": "integer" },
+                                "cpu_percent_secs": {
+                                    "type": "number",
+                                    "description": "CPU usage in percent times seconds, summed over the measurements",
+                                },
+                                "avg_cpu_usage": number,
+                            },
+                        },
+                    },
+                    "timeline": {
+                        "description": "Measurements during the incident, sampled evenly for long incidents",
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["timestamp", "total_cpu_usage", "top_process"],
+                            "properties": {
+                                "timestamp": timestamp,
+                                "total_cpu_usage": number,
+                                "top_process": {
+                                    "type": ["object", "null"],
+                                    "properties": {
+                                        "name": { "type": "string" },
+                                        "pid": { "type": "integer" },
+                                        "cpu_usage": number,
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Formats the top memory consuming processes into a table
+fn format_memory_stats(cpu_stats: &CPUStats, num_processes: usize) -> String {
+    let mut processes = cpu_stats.processes.iter().collect::<Vec<&ProcessStats>>();
+    processes.sort_by_key(|p| std::cmp::Reverse(p.memory));
+
+    format!(
+        "{header}\n{column_names}\n{column_names_divider}\n{stats}\n{divider}",
+        header = format_args!("{:-^80}", "Memory usage"),
+        divider = format_args!("{:-^80}", ""),
+        column_names = format_args!(
+            "| {0: <10} | {1: <50} | {2: <10} |",
+            "PID", "Name", "Memory"
+        ),
+        column_names_divider = format_args!("|{0:-<12}|{1:-<52}|{2:-<12}|", "", "", ""),
+        stats = processes
+            .iter()
+            .take(num_processes)
+            .map(|p| {
+                format!(
+                    "| {0: <10} | {1: <50} | {2: <10} |",
+                    format_pid(p.process.pid()),
+                    p.name.to_string(),
+                    format_memory(p.memory),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    )
+}
+
+/// CPU usage and number of processes of every user, busiest first (`-` if the user is unknown)
+fn usage_by_user<'a>(cpu_stats: &CPUStats<'a>) -> Vec<(&'a str, f32, usize)> {
+    let mut usages: HashMap<&str, (f32, usize)> = HashMap::new();
+    for p in &cpu_stats.processes {
+        let (usage, count) = usages.entry(p.user_name.unwrap_or("-")).or_default();
+        *usage += p.got_cpu_usage;
+        *count += 1;
+    }
+
+    let mut usages = usages
+        .into_iter()
+        .map(|(user, (usage, count))| (user, usage, count))
+        .collect::<Vec<(&str, f32, usize)>>();
+    usages.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(b.0)));
+
+    usages
+}
+
+/// Formats the top users by CPU usage into a table
+fn format_user_stats(user_usages: &[(&str, f32, usize)], num_users: usize) -> String {
+    format!(
+        "{header}\n{column_names}\n{column_names_divider}\n{stats}\n{divider}",
+        header = format_args!("{:-^80}", "CPU usage by user"),
+        divider = format_args!("{:-^80}", ""),
+        column_names = format_args!(
+            "| {0: <50} | {1: <10} | {2: <10} |",
+            "User", "Processes", "Usage"
+        ),
+        column_names_divider = format_args!("|{0:-<52}|{1:-<12}|{2:-<12}|", "", "", ""),
+        stats = user_usages
+            .iter()
+            .take(num_users)
+            .map(|(user, usage, count)| {
+                format!(
+                    "| {0: <50} | {1: <10} | {2: <10} |",
+                    user,
+                    count,
+                    format!("{:.2} %", Decimal(usage)),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    )
+}
+
+/// Columns for which the platform didn't report any data on all processes
+fn unsupported_columns(source: &dyn ProcessSource, columns: &[Column]) -> Vec<Column> {
+    let processes = source.processes();
+
+    columns
+        .iter()
+        .copied()
+        .filter(|c| match c {
+            Column::User => processes
+                .iter()
+                .all(|p| source.user_name(p.pid()).is_none()),
+            Column::Mem => processes.iter().all(|p| p.memory() == 0),
+            _ => false,
+        })
+        .collect()
+}
+
+/// Formats a rate of bytes per second in [IO_UNIT]
+fn format_rate(bytes_per_second: f32) -> String {
+    const KIB: f32 = 1024.0;
+    const MIB: f32 = 1024.0 * 1024.0;
+    const GIB: f32 = 1024.0 * 1024.0 * 1024.0;
+
+    match *IO_UNIT.lock().unwrap() {
+        IoUnit::Kib => format!("{:.1} KiB/s", Decimal(bytes_per_second / KIB)),
+        IoUnit::Mib => format!("{:.2} MiB/s", Decimal(bytes_per_second / MIB)),
+        IoUnit::Auto => match bytes_per_second {
+            rate if rate >= GIB => format!("{:.1} GiB/s", Decimal(rate / GIB)),
+            rate if rate >= MIB => format!("{:.1} MiB/s", Decimal(rate / MIB)),
+            rate if rate >= KIB => format!("{:.1} KiB/s", Decimal(rate / KIB)),
+            rate => format!("{:.0} B/s", Decimal(rate)),
+        },
+    }
+}
+
+/// Number formatted with the `decimal_separator`, the precision and sign flag of the placeholder are kept
+/// (e.g. `format!("{:+.2}", Decimal(1.5))`), width and alignment are not
+struct Decimal<T>(T);
+
+impl<T: std::fmt::Display> std::fmt::Display for Decimal<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let number = match (f.precision(), f.sign_plus()) {
+            (Some(precision), true) => format!("{:+.*}", precision, self.0),
+            (Some(precision), false) => format!("{:.*}", precision, self.0),
+            (None, true) => format!("{:+}", self.0),
+            (None, false) => self.0.to_string(),
+        };
+        match DECIMAL_COMMA.load(Ordering::Relaxed) {
+            true => f.write_str(&number.replace('.', ",")),
+            false => f.write_str(&number),
+        }
+    }
+}
+
+/// Per-core usage as one character per core (`.` below 10 %, `1`-`9` per started 10 %, `#` at 100 %),
+/// 64 cores per line labeled with the first and last core
+fn format_core_heatmap(cores: &[usize], usages: &[f32]) -> Vec<String> {
+    cores
+        .iter()
+        .zip(usages)
+        .collect::<Vec<_>>()
+        .chunks(64)
+        .map(|chunk| {
+            let heat = chunk
+                .iter()
+                .map(|(_, usage)| match (**usage / 10.0) as u32 {
+                    0 => '.',
+                    level @ 1..=9 => char::from_digit(level, 10).unwrap(),
+                    _ => '#',
+                })
+                .collect::<String>();
+            format!("CPU{}-{} {}", chunk[0].0, chunk[chunk.len() - 1].0, heat)
+        })
+        .collect()
+}
+
+/// Formats a number of bytes in MB
+fn format_memory(bytes: u64) -> String {
+    format!("{:.1} MB", Decimal(bytes as f64 / 1_000_000.0))
+}
+
+/// Formats stats into a single status line
+fn format_stats_oneline(
+    cpu_stats: &CPUStats,
+    total_cpu_usage: f32,
+    process_count: usize,
+) -> String {
+    let top = match cpu_stats.processes.first() {
+        Some(p) => format!("{} {:.0}%", p.name, Decimal(p.got_cpu_usage)),
+        None => String::from("-"),
+    };
+
+    format!(
+        "CPU {:.0}% | top: {} | procs:{}",
+        Decimal(total_cpu_usage),
+        top,
+        process_count,
+    )
+}
+
+/// Get a single line with the total and the top processes (and their memory if `include_memory` is set)
+fn format_stats_compact(
+    cpu_stats: &CPUStats,
+    total_cpu_usage: f32,
+    num_processes: usize,
+    include_memory: bool,
+) -> String {
+    let top = cpu_stats
+        .processes
+        .iter()
+        .take(num_processes)
+        .map(|p| {
+            let name = match p.merged {
+                1 => p.name.to_string(),
+                merged => format!("{} x{}", p.name, merged),
+            };
+            match include_memory {
+                true => format!(
+                    "{} {:.2}% ({})",
+                    name,
+                    Decimal(p.got_cpu_usage),
+                    format_memory(p.memory)
+                ),
+                false => format!("{} {:.2}%", name, Decimal(p.got_cpu_usage)),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    format!("[TOTAL] {:.2}% | top: {}", Decimal(total_cpu_usage), top)
+}
+
+/// Get one logfmt line per top process, prefixed by the timestamp and extra fields
+fn format_stats_logfmt(
+    cpu_stats: &CPUStats,
+    total_cpu_usage: f32,
+    num_processes: usize,
+    extra_fields: &[(&str, String)],
+    measured_at: &DateTime<Local>,
+) -> String {
+    let ts = format_iso_time(measured_at);
+    let total_cpu = format!("{:.2}", total_cpu_usage);
+
+    let mut lines = cpu_stats
+        .processes
+        .iter()
+        .take(num_processes)
+        .map(|p| {
+            let mut fields = vec![("ts", ts.clone())];
+            fields.extend_from_slice(extra_fields);
+            fields.extend([
+                ("total_cpu", total_cpu.clone()),
+                ("pid", format_pid(p.process.pid())),
+                ("name", p.name.to_string()),
+                ("cpu", format!("{:.2}", p.got_cpu_usage)),
+            ]);
+            format_logfmt(&fields)
+        })
+        .collect::<Vec<String>>();
+
+    // Still report the total if there is nothing to list
+    if lines.is_empty() {
+        let mut fields = vec![("ts", ts)];
+        fields.extend_from_slice(extra_fields);
+        fields.push(("total_cpu", total_cpu));
+        lines.push(format_logfmt(&fields));
+    }
+
+    lines.join("\n")
+}
+
+/// Get a JSON line of a measurement (or logged event) with the given processes
+fn format_stats_json(
+    event: &str,
+    total_cpu_usage: f32,
+    processes: &[&ProcessStats],
+    extra_fields: &[(&str, serde_json::Value)],
+    measured_at: &DateTime<Local>,
+) -> String {
+    let mut record = serde_json::json!({
+        "timestamp": json_timestamp(measured_at),
+        "event": event,
+        "total_cpu_usage": total_cpu_usage,
+        "processes": processes
+            .iter()
+            .map(|p| {
+                serde_json::json!({
+                    "pid": p.process.pid().as_u32(),
+                    "name": p.name,
+                    "got_cpu_usage": p.got_cpu_usage,
+                })
+            })
+            .collect::<Vec<serde_json::Value>>(),
+    });
+    for (key, value) in extra_fields {
+        record[*key] = value.clone();
+    }
+
+    record.to_string()
+}
+
+/// Get CSV rows (see `CSV_HEADER`) of a measurement (or logged event), one per given process
+fn format_stats_csv(
+    event: &str,
+    total_cpu_usage: f32,
+    processes: &[&ProcessStats],
+    measured_at: &DateTime<Local>,
+) -> String {
+    let ts = format_iso_time(measured_at);
+    let total_cpu = format!("{:.2}", Decimal(total_cpu_usage));
+    let row = |pid: String, name: &str, cpu: String| {
+        format_csv(&[
+            ts.clone(),
+            event.to_string(),
+            total_cpu.clone(),
+            pid,
+            name.to_string(),
+            cpu,
+            String::new(),
+        ])
+    };
+
+    // Still report the total if there is nothing to list
+    match processes.is_empty() {
+        true => row(String::new(), "", String::new()),
+        false => processes
+            .iter()
+            .map(|p| {
+                row(
+                    format_pid(p.process.pid()),
+                    p.name,
+                    format!("{:.2}", Decimal(p.got_cpu_usage)),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n"),
+    }
+}
+
+/// Get a logged event as a JSON line or, with `SinkFormat::Csv`, as CSV rows
+fn format_stats_record(
+    format: SinkFormat,
+    event: &str,
+    total_cpu_usage: f32,
+    processes: &[&ProcessStats],
+    extra_fields: &[(&str, serde_json::Value)],
+    measured_at: &DateTime<Local>,
+) -> String {
+    match format {
+        SinkFormat::Csv => format_stats_csv(event, total_cpu_usage, processes, measured_at),
+        _ => format_stats_json(event, total_cpu_usage, processes, extra_fields, measured_at),
+    }
+}
+
+/// Get Graphite plaintext lines for the total and the top processes (summed up by name)
+fn format_stats_graphite(
+    cpu_stats: &CPUStats,
+    total_cpu_usage: f32,
+    num_processes: usize,
+    prefix: &str,
+    instance_tag: Option<&str>,
+    measured_at: &DateTime<Local>,
+) -> String {
+    let timestamp = measured_at.timestamp();
+    // As a Graphite tag, so the metric paths stay the same
+    let tags = instance_tag.map_or(String::new(), |tag| format!(";instance={}", tag));
+
+    let mut usage_by_name: BTreeMap<String, f32> = BTreeMap::new();
+    for p in cpu_stats.processes.iter().take(num_processes) {
+        // Dots would split the path and spaces the line
+        let name = p
+            .name
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect::<String>();
+        *usage_by_name.entry(name).or_default() += p.got_cpu_usage;
+    }
+
+    let mut lines = format!(
+        "{}.total{} {:.2} {}\n",
+        prefix, tags, total_cpu_usage, timestamp
+    );
+    for (name, usage) in usage_by_name {
+        lines += &format!(
+            "{}.process.{}{} {:.2} {}\n",
+            prefix, name, tags, usage, timestamp
+        );
+    }
+
+    lines
+}
+
+/// Values a [LogTemplate] placeholder can be replaced with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TemplateField {
+    Ts,
+    Reason,
+    Pid,
+    Name,
+    Cpu,
+    Threshold,
+    Incident,
+}
+
+impl TemplateField {
+    const ALL: [(&'static str, TemplateField); 7] = [
+        ("ts", TemplateField::Ts),
+        ("reason", TemplateField::Reason),
+        ("pid", TemplateField::Pid),
+        ("name", TemplateField::Name),
+        ("cpu", TemplateField::Cpu),
+        ("threshold", TemplateField::Threshold),
+        ("incident", TemplateField::Incident),
+    ];
+}
+
+/// Part of a [LogTemplate]
+#[derive(Clone, Debug)]
+enum TemplateSegment {
+    Text(String),
+    Field(TemplateField),
+}
+
+/// Line format of `log_template`, parsed once so unknown placeholders are rejected at startup
+#[derive(Clone, Debug)]
+struct LogTemplate {
+    segments: Vec<TemplateSegment>,
+}
+
+/// A threshold event rendered by a [LogTemplate]
+struct LogEvent<'a> {
+    /// `total`, `recovered`, `process`, `baseline`, `fds`, `faults`, `anomaly`, `user` or `action`
+    reason: &'a str,
+    /// Only set for process events
+    pid: Option<Pid>,
+    name: Option<&'a str>,
+    cpu: f32,
+    threshold: f32,
+    /// Only set with `incident_ids` during a total breach
+    incident: Option<u64>,
+}
+
+impl LogTemplate {
+    fn render(&self, event: &LogEvent, time: &DateTime<Local>) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                TemplateSegment::Text(text) => text.clone(),
+                TemplateSegment::Field(field) => match field {
+                    TemplateField::Ts => format_iso_time(time),
+                    TemplateField::Reason => event.reason.to_string(),
+                    TemplateField::Pid => event.pid.map_or(String::from("-"), format_pid),
+                    TemplateField::Name => event.name.unwrap_or("-").to_string(),
+                    TemplateField::Cpu => format!("{:.2}", event.cpu),
+                    TemplateField::Threshold => format!("{:.2}", event.threshold),
+                    TemplateField::Incident => event
+                        .incident
+                        .map_or(String::from("-"), |id| id.to_string()),
+                },
+            })
+            .collect()
+    }
+}
+
+/// Parse `log_template`, `{{` and `}}` are literal braces
+fn parse_log_template(value: &str) -> Result<LogTemplate, String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut chars = value.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let name = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                let field = TemplateField::ALL
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .map(|(_, field)| *field)
+                    .ok_or_else(|| {
+                        format!(
+                            "unknown placeholder '{{{}}}', expected one of: {}",
+                            name,
+                            TemplateField::ALL
+                                .iter()
+                                .map(|(n, _)| *n)
+                                .collect::<Vec<&str>>()
+                                .join(", ")
+                        )
+                    })?;
+                if !text.is_empty() {
+                    segments.push(TemplateSegment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(TemplateSegment::Field(field));
+            }
+            '}' => return Err(String::from("unmatched '}', use '}}' for a literal brace")),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        segments.push(TemplateSegment::Text(text));
+    }
+
+    Ok(LogTemplate { segments })
+}
+
+/// Structured journald fields every event carries
+#[cfg(all(target_os = "linux", feature = "journald"))]
+fn journal_fields(
+    reason: &str,
+    total_cpu_usage: f32,
+    threshold: f32,
+    incident: Option<u64>,
+) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("REASON", reason.to_string()),
+        ("CPU_TOTAL", format!("{:.2}", total_cpu_usage)),
+        ("THRESHOLD", format!("{:.2}", threshold)),
+    ];
+    fields.extend(incident.map(|id| ("INCIDENT", id.to_string())));
+
+    fields
+}
+
+/// Structured journald fields of the process an event is about
+#[cfg(all(target_os = "linux", feature = "journald"))]
+fn journal_process_fields(p: &ProcessStats) -> [(&'static str, String); 3] {
+    [
+        ("PID", p.process.pid().to_string()),
+        ("PROCESS_NAME", p.name.to_string()),
+        ("CPU_PROCESS", format!("{:.2}", p.got_cpu_usage)),
+    ]
+}
+
+/// Suffix tagging a message with the incident it belongs to, empty outside of incidents
+fn format_incident(incident: Option<u64>) -> String {
+    incident.map_or(String::new(), |id| format!(" [Incident: {}]", id))
+}
+
+/// Format key=value pairs as a logfmt line, quoting values where needed
+fn format_logfmt(fields: &[(&str, String)]) -> String {
+    fields
+        .iter()
+        .map(|(key, value)| {
+            if value.is_empty() || value.contains([' ', '=', '"', '\\']) {
+                let escaped = value.replace('\\', "\\\\").replace('"', "\\\"");
+                format!("{}=\"{}\"", key, escaped)
+            } else {
+                format!("{}={}", key, value)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Get a CSV row delimited by [csv_delimiter], quoting fields with the delimiter, quotes or line breaks
+fn format_csv(fields: &[String]) -> String {
+    let delimiter = csv_delimiter();
+    fields
+        .iter()
+        .map(
+            |field| match field.contains(delimiter) || field.contains(['"', '\n', '\r']) {
+                true => format!("\"{}\"", field.replace('"', "\"\"")),
+                false => field.clone(),
+            },
+        )
+        .collect::<Vec<String>>()
+        .join(delimiter)
+}
+
+/// Log a message to a file with the timestamp of `time`, ending in a new line
+fn log_to_file(log_file: &Option<LogFile>, message: &str, time: &DateTime<Local>, now: Instant) {
+    // Don't log anything if no path specified
+    let Some(log_file) = log_file else {
+        return;
+    };
+
+    let processed_message = if log_file.format == SinkFormat::Logfmt {
+        // Keep the file parseable by turning free text into msg fields
+        message
+            .split('\n')
+            .filter(|m| !m.trim().is_empty())
+            .map(|m| format_logfmt(&[("ts", format_iso_time(time)), ("msg", m.to_string())]))
+            .collect::<Vec<String>>()
+            .join("\n")
+    } else if log_file.format == SinkFormat::Json {
+        message
+            .split('\n')
+            .filter(|m| !m.trim().is_empty())
+            .map(|m| {
+                serde_json::json!({
+                    "timestamp": json_timestamp(time),
+                    "event": "msg",
+                    "msg": m,
+                })
+                .to_string()
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    } else if log_file.format == SinkFormat::Csv {
+        message
+            .split('\n')
+            .filter(|m| !m.trim().is_empty())
+            .map(|m| {
+                format_csv(&[
+                    format_iso_time(time),
+                    String::from("msg"),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                    m.to_string(),
+                ])
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    } else if log_file.format == SinkFormat::Compact {
+        // No blank lines around messages, one event per line
+        let pre_text = format!("{} | ", format_iso_time(time));
+        message
+            .split('\n')
+            .filter(|m| !m.trim().is_empty())
+            .map(|m| format!("{}{}", pre_text, m))
+            .collect::<Vec<String>>()
+            .join("\n")
+    } else {
+        // Prepend ISO timestamp to every line
+        let pre_text = format!("{} | ", format_iso_time(time));
+        format!("\n{}\n", message)
+            .split('\n')
+            .map(|m| format!("{}{}", pre_text, m))
+            .collect::<Vec<String>>()
+            .join("\n")
+    };
+
+    write_to_log_file(log_file, &processed_message, now);
+}
+
+/// Log already formatted lines to a file as they are, ending in a new line
+fn log_raw_to_file(log_file: &Option<LogFile>, message: &str, now: Instant) {
+    if let Some(log_file) = log_file {
+        write_to_log_file(log_file, message, now);
+    }
+}
+
+/// Write to the log file, syncing it if requested
+fn write_to_log_file(log_file: &LogFile, processed_message: &str, now: Instant) {
+    log_file.switch_slot(now);
+    let message = match &log_file.line_prefix {
+        Some(prefix) => processed_message
+            .split('\n')
+            .map(|line| format!("{}{}", prefix, line))
+            .collect::<Vec<String>>()
+            .join("\n"),
+        None => processed_message.to_string(),
+    };
+    // Whole messages are written at once, so a multi-line event never spans two files
+    log_file.rotate_if_full(message.len() + 1);
+    let target = log_file.target.borrow();
+    let LogTarget::File(file) = &*target else {
+        println!("{}", message);
+        return;
+    };
+    // Every CSV file starts with the header, also new slots and the fallback
+    let message =
+        match log_file.format == SinkFormat::Csv && file.metadata().is_ok_and(|m| m.len() == 0) {
+            true => format!("{}\n{}", csv_header(), message),
+            false => message,
+        };
+
+    // A single write, so an interrupted or killed logger can't leave half a line behind
+    let result = (&*file)
+        .write_all(format!("{}\n", message).as_bytes())
+        .map_err(|e| warn("log-write", &format!("failed to write to log file: {}", e)))
+        // Only pay for hitting the disk if durability was requested
+        .and_then(|_| match log_file.sync {
+            true => file
+                .sync_all()
+                .map_err(|e| warn("log-sync", &format!("failed to sync log file: {}", e))),
+            false => Ok(()),
+        });
+    drop(target);
+
+    match result {
+        Ok(()) => {
+            log_file.failed_writes.set(0);
+            log_file.last_written.set(now);
+        }
+        // Retry the lost message on the fallback
+        Err(()) if log_file.write_failed() => write_to_log_file(log_file, processed_message, now),
+        Err(()) => {}
+    }
+}
+
+/// Append a line to an optional multi-line message
+fn append_line(message: &mut Option<String>, line: &str) {
+    *message = Some(match message.take() {
+        Some(existing) => format!("{}\n{}", existing, line),
+        None => line.to_string(),
+    });
+}
+
+/// Create a FIFO at the given path if nothing exists there yet
+#[cfg(unix)]
+fn create_event_fifo(file_path: &str) {
+    use std::os::unix::ffi::OsStrExt;
+
+    if std::path::Path::new(file_path).exists() {
+        return;
+    }
+
+    let c_path = std::ffi::CString::new(std::ffi::OsStr::new(file_path).as_bytes())
+        .expect("event FIFO path contains a NUL byte");
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+        panic!(
+            "failed to create event FIFO '{}': {}",
+            file_path,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Write a JSON event as a single line to a FIFO, dropping it if no reader is attached
+#[cfg(unix)]
+fn write_to_event_fifo(file_path: &Option<String>, event: &serde_json::Value) {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Don't write anything if no path specified
+    let Some(file_path) = file_path else {
+        return;
+    };
+
+    // Opening a FIFO for writing in non-blocking mode fails if there is no reader,
+    // in which case the event is simply dropped
+    let Ok(mut fifo) = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(file_path)
+    else {
+        return;
+    };
+
+    write_event_line(&mut fifo, event);
+}
+
+/// Write an event line with a single write, dropping it if the pipe is full
+///
+/// Lines up to `PIPE_BUF` bytes are written to a pipe either whole or not at all,
+/// `writeln!` could split one over several writes and leave half a line behind
+#[cfg(unix)]
+fn write_event_line(fifo: &mut impl Write, event: &serde_json::Value) {
+    let line = format!("{}\n", event);
+    // A full pipe fails with WouldBlock, a short write isn't retried so it can't block either
+    let _ = fifo.write(line.as_bytes());
+}
+
+/// Get the SHA-256 of an executable, hashing every path only once
+fn exe_digest(digests: &mut HashMap<PathBuf, Option<String>>, exe: &Path) -> Option<String> {
+    if exe.as_os_str().is_empty() {
+        return None;
+    }
+
+    digests
+        .entry(exe.to_path_buf())
+        .or_insert_with(|| match sha256::file_digest(exe) {
+            Ok(digest) => Some(digest),
+            Err(e) => {
+                warn(
+                    "hash-exe",
+                    &format!("failed to hash '{}': {}", exe.display(), e),
+                );
+                None
+            }
+        })
+        .clone()
+}
+
+/// Stable short hash standing in for a name with `hash_names`
+fn hash_name(name: &str) -> String {
+    sha256::digest(name.as_bytes())[..8].to_string()
+}
+
+/// Get the path of an executable for output, hashed with `hash_names`
+fn format_exe(exe: &Path) -> String {
+    match HASH_NAMES.load(Ordering::Relaxed) {
+        true if !exe.as_os_str().is_empty() => hash_name(&exe.to_string_lossy()),
+        _ => exe.display().to_string(),
+    }
+}
+
+/// Get the untruncated name of a process from its executable or command line
+fn full_name(process: &dyn ProcessInfo) -> &str {
+    let exe_stem = process.exe().file_stem().and_then(|stem| stem.to_str());
+    let cmd_name = process
+        .cmd()
+        .first()
+        .and_then(|arg| Path::new(arg).file_name())
+        .and_then(|name| name.to_str());
+
+    exe_stem
+        .or(cmd_name)
+        .filter(|name| !name.is_empty())
+        .unwrap_or(process.name())
+}
+
+/// Guess whether a process is a kernel thread (children of `kthreadd` or well-known kernel thread names)
+#[cfg(target_os = "linux")]
+fn is_kernel_thread(process: &dyn ProcessInfo) -> bool {
+    const KTHREADD: usize = 2;
+    const KERNEL_THREAD_PREFIXES: [&str; 8] = [
+        "kworker/",
+        "ksoftirqd/",
+        "migration/",
+        "rcu_",
+        "watchdog/",
+        "irq/",
+        "cpuhp/",
+        "kthreadd",
+    ];
+
+    process.pid() == Pid::from(KTHREADD)
+        || process.parent() == Some(Pid::from(KTHREADD))
+        || KERNEL_THREAD_PREFIXES
+            .iter()
+            .any(|prefix| process.name().starts_with(prefix))
+}
+
+/// Kernel threads are not exposed as processes on other platforms
+#[cfg(not(target_os = "linux"))]
+fn is_kernel_thread(_process: &dyn ProcessInfo) -> bool {
+    false
+}
+
+/// Whether a process name passes `include` (if any) and none of `exclude`
+fn is_name_included(include: &[String], exclude: &[String], name: &str) -> bool {
+    let name = name.to_lowercase();
+    let matches = |pattern: &String| {
+        let pattern = pattern.to_lowercase();
+        match pattern.contains(['*', '?']) {
+            true => rules::matches(&pattern, &name),
+            false => name.contains(&pattern),
+        }
+    };
+
+    (include.is_empty() || include.iter().any(matches)) && !exclude.iter().any(matches)
+}
+
+/// Guess whether the stats of a process couldn't be read, sysinfo reports denied access as zeros instead of an error
+fn is_inaccessible(process: &dyn ProcessInfo) -> bool {
+    // Kernel threads legitimately have neither memory nor an executable
+    process.memory() == 0 && process.exe().as_os_str().is_empty() && !is_kernel_thread(process)
+}
+
+/// Read the global CPU time counters (`cpu user nice system idle iowait irq softirq steal ...`)
+#[cfg(target_os = "linux")]
+fn read_cpu_times() -> Option<CpuTimes> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let times = stat
+        .lines()
+        .find(|l| l.starts_with("cpu "))?
+        .split_whitespace()
+        .skip(1)
+        .map(|v| v.parse::<u64>())
+        .collect::<Result<Vec<u64>, _>>()
+        .ok()?;
+
+    Some(CpuTimes {
+        total: times.iter().take(8).sum(),
+        iowait: *times.get(4)?,
+        steal: *times.get(7)?,
+    })
+}
+
+/// Get the command line the tool was started with, hiding the values of [REDACTED_OPTIONS] if `redact` is set
+fn format_command_line(redact: bool) -> String {
+    let mut redact_next = false;
+
+    std::env::args()
+        .map(|arg| {
+            let next_redacted = std::mem::replace(&mut redact_next, false);
+            if !redact {
+                return arg;
+            }
+            if next_redacted {
+                return String::from("<redacted>");
+            }
+
+            // Handle both `--option value` and `--option=value`
+            match arg.split_once('=') {
+                Some((option, _)) if REDACTED_OPTIONS.contains(&option) => {
+                    format!("{}=<redacted>", option)
+                }
+                _ => {
+                    redact_next = REDACTED_OPTIONS.contains(&arg.as_str());
+                    arg
+                }
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Get the CPUs a process may run on as a list of ranges (e.g. `0-3,8`)
+#[cfg(target_os = "linux")]
+fn read_affinity(pid: Pid) -> Option<String> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    let result = unsafe {
+        libc::sched_getaffinity(
+            pid.as_u32() as libc::pid_t,
+            std::mem::size_of::<libc::cpu_set_t>(),
+            &mut set,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+
+    let cpus = (0..libc::CPU_SETSIZE as usize)
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+        .collect::<Vec<usize>>();
+
+    // Collapse consecutive CPUs into ranges
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == cpu => *end = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+
+    Some(
+        ranges
+            .iter()
+            .map(|(start, end)| match start == end {
+                true => start.to_string(),
+                false => format!("{}-{}", start, end),
+            })
+            .collect::<Vec<String>>()
+            .join(","),
+    )
+}
+
+/// Affinity is only exposed on Linux
+#[cfg(not(target_os = "linux"))]
+fn read_affinity(_pid: Pid) -> Option<String> {
+    None
+}
+
+/// Read the nice value and, for real-time scheduling policies, the real-time priority from `/proc/[pid]/stat`
+#[cfg(target_os = "linux")]
+fn read_priority(pid: Pid) -> Option<(i32, Option<u32>)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name may contain spaces, fields after it start with the state (field 3)
+    let fields = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+    let priority = fields.get(18 - 3)?.parse::<i64>().ok()?;
+    let nice = fields.get(19 - 3)?.parse::<i32>().ok()?;
+
+    // Real-time processes have a negated priority of -2 to -100 (real-time priority 1 to 99)
+    Some((nice, (priority < 0).then(|| (-priority - 1) as u32)))
+}
+
+/// Priorities are only exposed on Linux
+#[cfg(not(target_os = "linux"))]
+fn read_priority(_pid: Pid) -> Option<(i32, Option<u32>)> {
+    None
+}
+
+/// Count the open file descriptors of a process in `/proc/[pid]/fd`, [None] for processes of other users
+#[cfg(target_os = "linux")]
+fn read_fd_count(pid: Pid) -> Option<usize> {
+    Some(fs::read_dir(format!("/proc/{}/fd", pid)).ok()?.count())
+}
+
+/// File descriptors are only counted on Linux
+#[cfg(not(target_os = "linux"))]
+fn read_fd_count(_pid: Pid) -> Option<usize> {
+    None
+}
+
+/// Find which of `pids` have a socket open that listens on (TCP) or is bound to (UDP) one of `ports`, by matching the
+/// socket inodes of `/proc/net` against the links in `/proc/[pid]/fd`
+#[cfg(target_os = "linux")]
+fn read_port_owners(ports: &[u16], pids: impl Iterator<Item = Pid>) -> Option<HashSet<Pid>> {
+    // TCP_LISTEN and TCP_CLOSE, which unconnected UDP sockets report
+    const TABLES: [(&str, &str); 4] =
+        [("tcp", "0A"), ("tcp6", "0A"), ("udp", "07"), ("udp6", "07")];
+
+    let mut inodes: HashSet<u64> = HashSet::new();
+    for (table, listening) in TABLES {
+        let Ok(content) = fs::read_to_string(format!("/proc/net/{}", table)) else {
+            continue;
+        };
+        // sl local_address rem_address st tx_queue:rx_queue tr:tm->when retrnsmt uid timeout inode ...
+        for fields in content
+            .lines()
+            .skip(1)
+            .map(|l| l.split_whitespace().collect::<Vec<&str>>())
+        {
+            let (Some(local_address), Some(&state), Some(inode)) =
+                (fields.get(1), fields.get(3), fields.get(9))
+            else {
+                continue;
+            };
+            let port = local_address
+                .rsplit_once(':')
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if state == listening && port.is_some_and(|port| ports.contains(&port)) {
+                inodes.extend(inode.parse::<u64>().ok().filter(|&inode| inode != 0));
+            }
+        }
+    }
+
+    let socket_inode = |link: PathBuf| -> Option<u64> {
+        link.to_str()?
+            .strip_prefix("socket:[")?
+            .strip_suffix(']')?
+            .parse()
+            .ok()
+    };
+    Some(
+        pids.filter(|pid| {
+            // Workers inheriting the listening socket of their parent all count
+            fs::read_dir(format!("/proc/{}/fd", pid)).is_ok_and(|fds| {
+                fds.flatten()
+                    .filter_map(|fd| fs::read_link(fd.path()).ok())
+                    .filter_map(socket_inode)
+                    .any(|inode| inodes.contains(&inode))
+            })
+        })
+        .collect(),
+    )
+}
+
+/// Listening sockets are only mapped to processes on Linux
+#[cfg(not(target_os = "linux"))]
+fn read_port_owners(_ports: &[u16], _pids: impl Iterator<Item = Pid>) -> Option<HashSet<Pid>> {
+    None
+}
+
+/// Read the number of allocated file handles of the whole system from `/proc/sys/fs/file-nr`
+#[cfg(target_os = "linux")]
+fn read_fd_total() -> Option<u64> {
+    fs::read_to_string("/proc/sys/fs/file-nr")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// File descriptors are only counted on Linux
+#[cfg(not(target_os = "linux"))]
+fn read_fd_total() -> Option<u64> {
+    None
+}
+
+/// Read the voluntary and involuntary context switches of a process so far
+#[cfg(target_os = "linux")]
+fn read_context_switches(pid: Pid) -> Option<(u64, u64)> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let read_value = |key: &str| -> Option<u64> {
+        status
+            .lines()
+            .find_map(|l| l.strip_prefix(key))?
+            .trim()
+            .parse()
+            .ok()
+    };
+
+    Some((
+        read_value("voluntary_ctxt_switches:")?,
+        read_value("nonvoluntary_ctxt_switches:")?,
+    ))
+}
+
+/// Read the minor and major page faults of a process so far from `/proc/[pid]/stat`
+#[cfg(target_os = "linux")]
+fn read_page_faults(pid: Pid) -> Option<(u64, u64)> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The name may contain spaces, fields after it start with the state (field 3)
+    let fields = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+
+    Some((
+        fields.get(10 - 3)?.parse().ok()?,
+        fields.get(12 - 3)?.parse().ok()?,
+    ))
+}
+
+/// Read the bytes a process read from and wrote to disk so far (not readable for other users' processes without root)
+#[cfg(target_os = "linux")]
+fn read_disk_bytes(pid: Pid) -> Option<(u64, u64)> {
+    let io = fs::read_to_string(format!("/proc/{}/io", pid)).ok()?;
+    let read_value = |key: &str| -> Option<u64> {
+        io.lines()
+            .find_map(|l| l.strip_prefix(key))?
+            .trim()
+            .parse()
+            .ok()
+    };
+
+    Some((read_value("read_bytes:")?, read_value("write_bytes:")?))
+}
+
+/// Read the bytes swapped in and out since boot from `/proc/vmstat`
+#[cfg(target_os = "linux")]
+fn read_swap_counters() -> Option<(u64, u64)> {
+    let vmstat = fs::read_to_string("/proc/vmstat").ok()?;
+    let read_value = |key: &str| -> Option<u64> {
+        vmstat
+            .lines()
+            .find_map(|l| l.strip_prefix(key))?
+            .trim()
+            .parse()
+            .ok()
+    };
+    // Counted in pages
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+    Some((
+        read_value("pswpin ")? * page_size,
+        read_value("pswpout ")? * page_size,
+    ))
+}
+
+/// Read the energy counters of all CPU packages (`intel-rapl:N`, without their subzones)
+#[cfg(target_os = "linux")]
+fn read_energy_counters() -> Option<EnergyCounters> {
+    let mut zones = fs::read_dir("/sys/class/powercap")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| {
+                    name.starts_with("intel-rapl:") && name.matches(':').count() == 1
+                })
+        })
+        .collect::<Vec<_>>();
+    zones.sort();
+
+    let read_value = |path: &Path, file: &str| -> Option<u64> {
+        fs::read_to_string(path.join(file))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    let packages = zones
+        .iter()
+        .map(|zone| {
+            Some((
+                read_value(zone, "energy_uj")?,
+                read_value(zone, "max_energy_range_uj")?,
+            ))
+        })
+        .collect::<Option<Vec<(u64, u64)>>>()?;
+
+    (!packages.is_empty()).then_some(EnergyCounters { packages })
+}
+
+/// Read the mean maximum frequency of all cores in MHz
+#[cfg(target_os = "linux")]
+fn read_max_frequency() -> Option<u64> {
+    let frequencies = fs::read_dir("/sys/devices/system/cpu/cpufreq")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| fs::read_to_string(entry.path().join("cpuinfo_max_freq")).ok());
+
+    mean_max_frequency(frequencies)
+}
+
+/// Mean of `cpuinfo_max_freq` readings in kHz as MHz, some drivers (and VMs) report 0 for unknown
+#[cfg(target_os = "linux")]
+fn mean_max_frequency(readings: impl Iterator<Item = String>) -> Option<u64> {
+    let frequencies = readings
+        .filter_map(|khz| khz.trim().parse::<u64>().ok())
+        .filter(|&khz| khz > 0)
+        .collect::<Vec<u64>>();
+
+    match frequencies.is_empty() {
+        true => None,
+        false => Some(frequencies.iter().sum::<u64>() / frequencies.len() as u64 / 1000),
+    }
+    .filter(|&mhz| mhz > 0)
+}
+
+/// Read the CPU usage per process name from a saved JSON snapshot
+fn read_baseline_file(file_path: &str) -> HashMap<String, f32> {
+    let content = fs::read_to_string(file_path).expect("failed to read baseline file");
+    let snapshot: serde_json::Value =
+        serde_json::from_str(&content).expect("failed to parse baseline file");
+
+    let mut usage_by_name: HashMap<String, f32> = HashMap::new();
+    for process in snapshot["processes"]
+        .as_array()
+        .expect("baseline file has no 'processes' array")
+    {
+        if let (Some(name), Some(usage)) =
+            (process["name"].as_str(), process["got_cpu_usage"].as_f64())
+        {
+            *usage_by_name.entry(name.to_string()).or_insert(0.0) += usage as f32;
+        }
+    }
+
+    usage_by_name
+}
+
+/// Read a set of PIDs from a file (one per line), silently skipping invalid lines
+fn read_pid_file(file_path: &str) -> std::io::Result<HashSet<Pid>> {
+    Ok(fs::read_to_string(file_path)?
+        .lines()
+        .filter_map(|l| l.trim().parse::<Pid>().ok())
+        .collect())
+}
+
+/// Parse a single core (`2`) or an inclusive range of cores (`4-7`) for `cores`
+fn parse_core_range(value: &str) -> Result<(usize, usize), String> {
+    let parse = |core: &str| core.trim().parse::<usize>().map_err(|e| format!("{}", e));
+    let (first, last) = match value.split_once('-') {
+        Some((first, last)) => (parse(first)?, parse(last)?),
+        None => (parse(value)?, parse(value)?),
+    };
+    if first > last {
+        return Err(format!("range {} is reversed", value));
+    }
+
+    Ok((first, last))
+}
+
+/// Parse a number of processes, absolute or as a percentage like `10%`
+fn parse_process_count(value: &str) -> Result<ProcessCount, String> {
+    match value.strip_suffix('%') {
+        Some(percent) => {
+            let percent: f32 = percent.trim().parse().map_err(|e| format!("{}", e))?;
+            if percent > 0.0 && percent <= 100.0 {
+                Ok(ProcessCount::Percent(percent))
+            } else {
+                Err(String::from(
+                    "percentage must be greater than 0 and at most 100",
+                ))
+            }
+        }
+        None => value
+            .parse()
+            .map(ProcessCount::Count)
+            .map_err(|e| format!("{}", e)),
+    }
+}
+
+/// Parse `path[:format]`, a suffix that isn't a format name is part of the path (e.g. `C:\\logs\\cpu.log`)
+fn parse_sink_target(value: &str, accepted: &[SinkFormat]) -> Result<SinkTarget, String> {
+    let suffix = value.rsplit_once(':').and_then(|(path, suffix)| {
+        let (_, format) = SinkFormat::ALL.iter().find(|(name, _)| *name == suffix)?;
+        Some((path, suffix, *format))
+    });
+    match suffix {
+        Some((_, name, format)) if !accepted.contains(&format) => Err(format!(
+            "can't be written as {}, expected one of: {}",
+            name,
+            SinkFormat::ALL
+                .iter()
+                .filter(|(_, f)| accepted.contains(f))
+                .map(|(name, _)| *name)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )),
+        Some((path, _, format)) => Ok(SinkTarget {
+            path: path.to_string(),
+            format: Some(format),
+        }),
+        None => Ok(SinkTarget {
+            path: value.to_string(),
+            format: None,
+        }),
+    }
+}
+
+/// Parse `log_file`
+fn parse_log_file_target(value: &str) -> Result<SinkTarget, String> {
+    parse_sink_target(
+        value,
+        &[
+            SinkFormat::Text,
+            SinkFormat::Compact,
+            SinkFormat::Logfmt,
+            SinkFormat::Json,
+            SinkFormat::Csv,
+        ],
+    )
+}
+
+/// Parse `export_on_exit`
+fn parse_export_target(value: &str) -> Result<SinkTarget, String> {
+    parse_sink_target(value, &[SinkFormat::Json, SinkFormat::Logfmt])
+}
+
+/// Parse an `instance_tag`, it has to fit into log lines and Graphite tags as it is
+fn parse_instance_tag(value: &str) -> Result<String, String> {
+    match value
+        .chars()
+        .find(|c| c.is_whitespace() || matches!(c, ';' | '~' | '='))
+    {
+        _ if value.is_empty() => Err(String::from("must not be empty")),
+        Some(c) => Err(format!("must not contain {:?}", c)),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Parse the EWMA weight for `process_smoothing`
+fn parse_smoothing_alpha(value: &str) -> Result<f32, String> {
+    let alpha: f32 = value.parse().map_err(|e| format!("{}", e))?;
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(alpha)
+    } else {
+        Err(String::from("must be greater than 0 and at most 1"))
+    }
+}
+
+/// Print a warning to stderr, rate limited per kind of warning to avoid flooding
+fn warn(kind: &'static str, message: &str) {
+    let interval = Duration::from_secs(WARN_INTERVAL_SECS.load(Ordering::Relaxed));
+    let mut warnings = WARNINGS.lock().unwrap();
+
+    match warnings.get_mut(kind) {
+        Some((printed_at, suppressed)) if printed_at.elapsed() < interval => {
+            *suppressed += 1;
+        }
+        Some((printed_at, suppressed)) => {
+            match *suppressed {
+                0 => eprintln!("warning: {}", message),
+                _ => eprintln!(
+                    "warning: {} ({} similar warnings suppressed)",
+                    message, suppressed
+                ),
+            }
+            *printed_at = Instant::now();
+            *suppressed = 0;
+        }
+        None => {
+            eprintln!("warning: {}", message);
+            warnings.insert(kind, (Instant::now(), 0));
+        }
+    }
+}
+
+/// Print how many warnings of each kind were suppressed since they were last printed
+fn flush_warnings() {
+    for (kind, (_, suppressed)) in WARNINGS.lock().unwrap().iter_mut() {
+        if *suppressed > 0 {
+            eprintln!(
+                "warning: {} similar '{}' warnings suppressed",
+                suppressed, kind
+            );
+            *suppressed = 0;
+        }
+    }
+}
+
+/// Get a PID formatted according to `pid_format` and `pid_width`
+fn format_pid(pid: Pid) -> String {
+    let width = PID_WIDTH.load(Ordering::Relaxed) as usize;
+
+    match PID_HEX.load(Ordering::Relaxed) {
+        true => format!("0x{:0width$x}", pid.as_u32(), width = width),
+        false => format!("{:0width$}", pid.as_u32(), width = width),
+    }
+}
+
+/// Get a DateTime as an ISO 8601 formatted string
+fn format_iso_time(time: &DateTime<Local>) -> String {
+    // 2023-03-08T21:19:47.101+01:00
+    let precision = TIMESTAMP_PRECISION.load(Ordering::Relaxed) as usize;
+
+    // chrono only supports 3, 6 or 9 digits, so cut the nanoseconds down instead
+    let fraction = match precision {
+        0 => String::new(),
+        _ => format!(
+            ".{}",
+            &format!("{:09}", time.timestamp_subsec_nanos())[..precision]
+        ),
+    };
+
+    format!(
+        "{}{}{}",
+        time.format("%Y-%m-%dT%H:%M:%S"),
+        fraction,
+        time.format("%:z")
+    )
+}
+
+/// Timestamp of a JSON record, an epoch number with `json_timestamp_epoch` or else an ISO 8601 string
+fn json_timestamp(time: &DateTime<Local>) -> serde_json::Value {
+    match *JSON_TIMESTAMP_EPOCH.lock().unwrap() {
+        Some(EpochUnit::Seconds) => time.timestamp().into(),
+        Some(EpochUnit::Milliseconds) => time.timestamp_millis().into(),
+        None => format_iso_time(time).into(),
+    }
+}
+
+/// ISO 8601 timestamp next to an epoch one with `json_iso_timestamp`
+fn json_iso_timestamp(time: &DateTime<Local>) -> Option<String> {
+    JSON_ISO_TIMESTAMP
+        .load(Ordering::Relaxed)
+        .then(|| format_iso_time(time))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use source::fake::{FakeClock, FakeProcess, FakeSource};
+
+    /// File in the temp dir unique to a test, removed when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "cpu-usage-logger-{}-{}.log",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_file(&path);
+            TempFile(path)
+        }
+
+        fn read(&self) -> String {
+            fs::read_to_string(&self.0).unwrap_or_default()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Run the measurement loop over one scripted snapshot per iteration
+    fn run(log: &TempFile, extra_args: &[&str], snapshots: Vec<Vec<FakeProcess>>) -> String {
+        run_on(log, extra_args, snapshots, &FakeClock::new())
+    }
+
+    fn run_on(
+        log: &TempFile,
+        extra_args: &[&str],
+        snapshots: Vec<Vec<FakeProcess>>,
+        clock: &FakeClock,
+    ) -> String {
+        let mut argv = vec![
+            "cpu-usage-logger",
+            "--format",
+            "oneline",
+            "--warmup",
+            "0",
+            "--log-file",
+            log.0.to_str().unwrap(),
+        ];
+        argv.extend_from_slice(extra_args);
+        let iterations = snapshots.len().to_string();
+        argv.extend_from_slice(&["--iterations", &iterations]);
+        let args = Args::parse_from(argv);
+
+        monitor_with(
+            &args,
+            &AtomicBool::new(true),
+            &mut FakeSource::new(snapshots),
+            clock,
+        );
+        log.read()
+    }
+
+    #[test]
+    fn log_timestamps_come_from_the_clock() {
+        let log = TempFile::new("clock");
+        let started = DateTime::parse_from_rfc3339("2001-02-03T04:05:06+00:00")
+            .unwrap()
+            .with_timezone(&Local);
+        let content = run_on(
+            &log,
+            &["--total-log-threshold", "30"],
+            (0..2)
+                .map(|_| vec![FakeProcess::new(1, "busy", 50.0)])
+                .collect(),
+            &FakeClock::starting_at(started),
+        );
+
+        let times = content
+            .lines()
+            .map(|line| {
+                let (time, _) = line.split_once(" | ").expect(&content);
+                DateTime::parse_from_rfc3339(time).expect(&content)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(times[0], started, "{}", content);
+        assert!(content.contains("[TOTAL]"), "{}", content);
+        // Measurements are stamped with the time the fake clock slept to, not the real one
+        assert!(
+            times
+                .iter()
+                .all(|&time| time >= started && time < started + chrono::Duration::minutes(1)),
+            "{}",
+            content
+        );
+        assert!(times.last().unwrap() > &started, "{}", content);
+    }
+
+    #[test]
+    fn log_slots_switch_after_the_interval_on_the_clock() {
+        let log = TempFile::new("slots");
+        let slot = |i: usize| TempFile(log.0.with_extension(format!("{}.log", i)));
+        let slots = [slot(0), slot(1)];
+        // A measurement every 6 seconds of the fake clock, which don't pass for real
+        run(
+            &log,
+            &[
+                "--log-files",
+                "2",
+                "--log-switch-interval",
+                "6",
+                "--total-log-threshold",
+                "0",
+            ],
+            (0..2)
+                .map(|_| vec![FakeProcess::new(1, "busy", 50.0)])
+                .collect(),
+        );
+
+        let first = slots[0].read();
+        let second = slots[1].read();
+        assert_eq!(first.matches("[TOTAL]").count(), 1, "{}", first);
+        assert_eq!(second.matches("[TOTAL]").count(), 1, "{}", second);
+    }
+
+    #[test]
+    fn heartbeat_is_logged_once_the_log_was_quiet_on_the_clock() {
+        let log = TempFile::new("heartbeat");
+        // Measurements 1, 7 and 13 seconds after starting on the fake clock
+        let content = run(
+            &log,
+            &[
+                "--heartbeat",
+                "10",
+                "--total-log-threshold",
+                "100",
+                "--process-log-threshold",
+                "100",
+            ],
+            (0..3)
+                .map(|_| vec![FakeProcess::new(1, "idle", 1.0)])
+                .collect(),
+        );
+
+        assert_eq!(content.matches("[HEARTBEAT]").count(), 1, "{}", content);
+    }
+
+    #[test]
+    fn hourly_summary_rolls_over_with_the_clock() {
+        use chrono::TimeZone;
+
+        let log = TempFile::new("hourly");
+        // Measurements at 04:59:51, 04:59:57 and 05:00:03
+        let clock = FakeClock::starting_at(Local.with_ymd_and_hms(2001, 2, 3, 4, 59, 50).unwrap());
+        let content = run_on(
+            &log,
+            &["--hourly-summary"],
+            (0..3)
+                .map(|_| vec![FakeProcess::new(1, "busy", 10.0)])
+                .collect(),
+            &clock,
+        );
+
+        let summaries = content
+            .lines()
+            .filter(|line| line.contains("Hourly summary"))
+            .collect::<Vec<&str>>();
+        assert_eq!(summaries.len(), 2, "{}", content);
+        assert!(
+            summaries[0].contains("2001-02-03T04:00 (2 measurements)"),
+            "{}",
+            content
+        );
+        // The final partial hour
+        assert!(
+            summaries[1].contains("2001-02-03T05:00 (1 measurements)"),
+            "{}",
+            content
+        );
+    }
+
+    /// Timings for `max_processes`, run with `cargo test --release -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn benchmark_selecting_the_top_processes() {
+        // Usage and PID, ordered like the measurement loop orders processes
+        let processes = (0..50_000u32)
+            .map(|pid| {
+                (
+                    (pid.wrapping_mul(2_654_435_761) % 10_000) as f32 / 100.0,
+                    pid,
+                )
+            })
+            .collect::<Vec<(f32, u32)>>();
+        let by_usage = |a: &(f32, u32), b: &(f32, u32)| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1));
+        let runs = 100;
+
+        let started = Instant::now();
+        for _ in 0..runs {
+            let mut sorted = processes.clone();
+            sorted.sort_by(by_usage);
+            std::hint::black_box(&sorted);
+        }
+        let sort = started.elapsed() / runs;
+
+        let started = Instant::now();
+        for _ in 0..runs {
+            let mut top = processes.clone();
+            top.select_nth_unstable_by(4, by_usage);
+            top.truncate(5);
+            top.sort_by(by_usage);
+            std::hint::black_box(&top);
+        }
+        let select = started.elapsed() / runs;
+
+        println!(
+            "{} processes: sorting all took {:?}, selecting the top 5 took {:?}",
+            processes.len(),
+            sort,
+            select
+        );
+    }
+
+    #[test]
+    fn reused_pid_starts_counting_breaches_anew() {
+        let pid = Pid::from(7);
+        let breach_counts = HashMap::from([((pid, 100), 2)]);
+
+        // The process that kept running continues its count
+        assert_eq!(count_breach(&breach_counts, (pid, 100)), 3);
+        // A new process got the PID in between, the old one's breaches aren't its own
+        assert_eq!(count_breach(&breach_counts, (pid, 200)), 1);
+    }
+
+    #[test]
+    fn table_and_log_file_share_the_measurement_timestamp() {
+        let path = std::env::temp_dir().join(format!(
+            "cpu-usage-logger-timestamps-{}.log",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        let measured_at = DateTime::parse_from_rfc3339("2024-05-06T07:08:09.123456789+02:00")
+            .unwrap()
+            .with_timezone(&Local);
+        let cpu_stats = CPUStats::from(Vec::new());
+        let layout = TableLayout {
+            columns: vec![Column::Pid, Column::Name, Column::Cpu],
+            title: String::from("CPU usage"),
+            footer: None,
+            host: String::from("test-host"),
+            color_scheme: ColorScheme::None,
+            color_threshold: 15.0,
+            color_bands: Vec::new(),
+            usage_align: Alignment::Left,
+            gauge: None,
+        };
+
+        let table = format_stats(&cpu_stats, 50.0, 5, &layout, &[], false, &measured_at);
+        let log_file = Some(LogFile::open(
+            path.to_str().unwrap(),
+            false,
+            SinkFormat::Text,
+            None,
+            None,
+            Instant::now(),
+        ));
+        log_to_file(&log_file, "[TOTAL] exceeded", &measured_at, Instant::now());
+        drop(log_file);
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        let _ = fs::remove_file(&path);
+
+        let expected = format_iso_time(&measured_at);
+        let table_timestamp = table
+            .lines()
+            .map(|line| line.trim_matches(|c| c == '|' || c == ' '))
+            .find(|line| line.starts_with("2024-"))
+            .expect(&table);
+        let log_timestamp = content
+            .lines()
+            .find(|line| line.contains("[TOTAL]"))
+            .and_then(|line| line.split(" | ").next())
+            .expect(&content);
+        assert_eq!(table_timestamp, expected);
+        assert_eq!(log_timestamp, expected);
+    }
+
+    #[test]
+    fn total_recovery_is_logged_once_after_the_breach() {
+        let log = TempFile::new("recovery");
+        let usages = [50.0, 60.0, 10.0, 5.0];
+        let content = run(
+            &log,
+            &[
+                "--total-log-threshold",
+                "30",
+                "--process-log-threshold",
+                "100",
+            ],
+            usages
+                .iter()
+                .map(|&usage| vec![FakeProcess::new(1, "busy", usage)])
+                .collect(),
+        );
+
+        let lines = content.lines().collect::<Vec<&str>>();
+        let breached = lines
+            .iter()
+            .position(|line| line.contains("[TOTAL]"))
+            .expect(&content);
+        let recovered = lines
+            .iter()
+            .position(|line| line.contains("[RECOVERED]"))
+            .expect(&content);
+        assert!(breached < recovered, "{}", content);
+        assert_eq!(content.matches("[RECOVERED]").count(), 1, "{}", content);
+        assert!(lines[recovered].contains("-> 10.00%"), "{}", content);
+    }
+
+    /// The same PID in both measurements, taken over by a new process in between
+    fn reused_pid(old_usage: f32, new_usage: f32) -> Vec<Vec<FakeProcess>> {
+        vec![
+            vec![FakeProcess::new(7, "old", old_usage).started_at(100)],
+            vec![FakeProcess::new(7, "new", new_usage).started_at(200)],
+        ]
+    }
+
+    #[test]
+    fn reused_pid_does_not_inherit_smoothing_or_alerted_state() {
+        let log = TempFile::new("reuse-smoothing");
+        let content = run(
+            &log,
+            &[
+                "--total-log-threshold",
+                "1000",
+                "--process-log-threshold",
+                "15",
+                "--process-smoothing",
+                "0.5",
+                "--alert-new-only",
+            ],
+            reused_pid(90.0, 20.0),
+        );
+
+        let processes = content
+            .lines()
+            .filter(|line| line.contains("[PROCESS]"))
+            .collect::<Vec<&str>>();
+        assert_eq!(processes.len(), 2, "{}", content);
+        assert!(
+            processes[0].contains("Name: 'old' Usage: 90.00%"),
+            "{}",
+            content
+        );
+        // Smoothed with the old process it would be 55% and, already alerted, not logged at all
+        assert!(
+            processes[1].contains("Name: 'new' Usage: 20.00%"),
+            "{}",
+            content
+        );
+    }
+
+    #[test]
+    fn reused_pid_does_not_inherit_the_average() {
+        let log = TempFile::new("reuse-average");
+        let content = run(
+            &log,
+            &["--total-log-threshold", "1000", "--busy-when", "avg>50"],
+            reused_pid(90.0, 20.0),
+        );
+
+        let processes = content
+            .lines()
+            .filter(|line| line.contains("[PROCESS]"))
+            .collect::<Vec<&str>>();
+        // Averaged with the old process it would be 55% and busy again
+        assert_eq!(processes.len(), 1, "{}", content);
+        assert!(processes[0].contains("Name: 'old'"), "{}", content);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn full_event_fifo_only_holds_whole_lines() {
+        use std::io::Read;
+        use std::os::fd::FromRawFd;
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        let mut writer = unsafe { File::from_raw_fd(fds[1]) };
+        for fd in fds {
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            assert_eq!(
+                unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) },
+                0
+            );
+        }
+
+        // Far more than the pipe holds, with lines that don't divide its capacity
+        let event = serde_json::json!({ "process": "x".repeat(100) });
+        for _ in 0..10_000 {
+            write_event_line(&mut writer, &event);
+        }
+
+        let mut contents = Vec::new();
+        let mut buffer = [0; 4096];
+        while let Ok(read @ 1..) = reader.read(&mut buffer) {
+            contents.extend_from_slice(&buffer[..read]);
+        }
+        let contents = String::from_utf8(contents).unwrap();
+        assert!(!contents.is_empty());
+        assert!(contents.ends_with('\n'));
+        for line in contents.lines() {
+            assert_eq!(
+                serde_json::from_str::<serde_json::Value>(line).unwrap(),
+                event
+            );
+        }
+    }
+
+    #[test]
+    fn write_atomic_replaces_the_file_without_leaving_the_temp_file() {
+        let log = TempFile::new("atomic");
+        fs::write(&log.0, "old contents that are longer\n").unwrap();
+
+        write_atomic(&log.0, "new\n").unwrap();
+
+        assert_eq!(log.read(), "new\n");
+        let mut tmp_name = log.0.file_name().unwrap().to_os_string();
+        tmp_name.push(".tmp");
+        assert!(!log.0.with_file_name(tmp_name).exists());
+    }
+
+    #[test]
+    fn memory_breach_survives_cpu_based_filters() {
+        let log = TempFile::new("memory-filters");
+        let content = run(
+            &log,
+            &[
+                "--total-log-threshold",
+                "1000",
+                "--process-log-threshold",
+                "50",
+                "--memory-log-threshold",
+                "500",
+                "--max-processes",
+                "1",
+                "--min-display-usage",
+                "5",
+            ],
+            vec![vec![
+                FakeProcess::new(1, "busy", 90.0),
+                FakeProcess::new(2, "leaky", 0.0).using_memory(800_000_000),
+                FakeProcess::new(3, "idle", 1.0),
+            ]],
+        );
+
+        let processes = content
+            .lines()
+            .filter(|line| line.contains("[PROCESS]"))
+            .collect::<Vec<&str>>();
+        assert_eq!(processes.len(), 2, "{}", content);
+        assert!(
+            processes[0].contains("CPU usage threshold of 50.00% exceeded"),
+            "{}",
+            content
+        );
+        assert!(
+            processes[1].contains("memory threshold of 500 MB exceeded -> [Pid: 2]"),
+            "{}",
+            content
+        );
+    }
+
+    #[test]
+    fn memory_and_cpu_breach_mentions_both() {
+        let log = TempFile::new("memory-and-cpu");
+        let content = run(
+            &log,
+            &[
+                "--total-log-threshold",
+                "1000",
+                "--process-log-threshold",
+                "50",
+                "--memory-log-threshold",
+                "500",
+            ],
+            vec![vec![
+                FakeProcess::new(1, "busy", 90.0).using_memory(800_000_000)
+            ]],
+        );
+
+        assert!(
+            content
+                .contains("CPU usage threshold of 50.00% and memory threshold of 500 MB exceeded"),
+            "{}",
+            content
+        );
+    }
+
+    #[test]
+    fn aggregated_processes_breach_with_their_summed_memory() {
+        let log = TempFile::new("memory-aggregated");
+        let content = run(
+            &log,
+            &[
+                "--total-log-threshold",
+                "1000",
+                "--process-log-threshold",
+                "100",
+                "--memory-log-threshold",
+                "500",
+                "--aggregate-by-name",
+            ],
+            vec![vec![
+                FakeProcess::new(1, "worker", 2.0).using_memory(300_000_000),
+                FakeProcess::new(2, "worker", 1.0).using_memory(400_000_000),
+            ]],
+        );
+
+        // Each of them stays below the threshold on its own
+        assert!(
+            content.contains("memory threshold of 500 MB exceeded -> [Pid: 1] Name: 'worker'"),
+            "{}",
+            content
+        );
+        assert!(
+            content.contains("Processes: 2 Memory: 700.0 MB"),
+            "{}",
+            content
+        );
+    }
+
+    #[test]
+    fn rotation_compresses_the_rotated_file_and_shifts_the_archives() {
+        let log = TempFile::new("rotation");
+        let path = log.0.to_str().unwrap().to_string();
+        let rotated = |name: &str| TempFile(format!("{}.{}", path, name).into());
+        let (first, first_archive, second_archive) =
+            (rotated("1"), rotated("1.gz"), rotated("2.gz"));
+        fs::write(&log.0, "current\n").unwrap();
+        fs::write(&first_archive.0, "previous").unwrap();
+
+        let rotation = LogRotation {
+            path,
+            max_bytes: 1,
+            max_files: 2,
+            compress: true,
+            compressing: RefCell::new(None),
+        };
+        rotation.rotate().unwrap();
+        // Waits for the compression
+        drop(rotation);
+
+        assert!(!first.0.exists());
+        assert_eq!(
+            fs::read(&first_archive.0).unwrap(),
+            gzip::compress(b"current\n")
+        );
+        assert_eq!(second_archive.read(), "previous");
+        assert_eq!(log.read(), "");
+    }
+
+    #[test]
+    fn compress_rotated_requires_either_kind_of_rotation() {
+        let parse = |extra: &[&str]| {
+            Args::try_parse_from(
+                [
+                    "cpu-usage-logger",
+                    "--log-file",
+                    "cpu.log",
+                    "--compress-rotated",
+                ]
+                .iter()
+                .chain(extra),
+            )
+        };
+        assert!(parse(&["--log-files", "2"]).is_ok());
+        assert!(parse(&["--max-log-size", "1"]).is_ok());
+        assert_eq!(
+            parse(&[]).unwrap_err().kind(),
+            clap::error::ErrorKind::MissingRequiredArgument
+        );
+    }
+
+    #[test]
+    fn memory_threshold_conflicts_with_conditions_and_rules() {
+        let rules = TempFile::new("memory-rules");
+        fs::write(&rules.0, "[[rule]]\npattern = \"busy\"\ncpu = 50\n").unwrap();
+        let rules_path = rules.0.to_str().unwrap();
+        for other in [["--busy-when", "cpu>50"], ["--rules-file", rules_path]] {
+            let result = Args::try_parse_from(
+                ["cpu-usage-logger", "--memory-log-threshold", "500"]
+                    .into_iter()
+                    .chain(other),
+            );
+            assert_eq!(
+                result.unwrap_err().kind(),
+                clap::error::ErrorKind::ArgumentConflict
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn max_frequency_of_zero_is_unavailable() {
+        let readings = |khz: &[&str]| khz.iter().map(|k| k.to_string()).collect::<Vec<String>>();
+        assert_eq!(mean_max_frequency(readings(&["0\n"]).into_iter()), None);
+        assert_eq!(mean_max_frequency(readings(&["500"]).into_iter()), None);
+        assert_eq!(
+            mean_max_frequency(readings(&["2000000\n", "0\n", "3000000\n"]).into_iter()),
+            Some(2500)
+        );
+    }
+
+    #[test]
+    fn baseline_skips_the_first_sample_and_floors_the_threshold() {
+        let start = Instant::now();
+        let mut baseline = Baseline::new(Duration::from_secs(10), start);
+
+        // An unprimed 0% reading doesn't drag the average down
+        assert!(!baseline.update(0.0, start));
+        assert!(!baseline.update(20.0, start + Duration::from_secs(5)));
+        assert!(baseline.update(40.0, start + Duration::from_secs(10)));
+        assert_eq!(baseline.learned, Some(30.0));
+        assert_eq!(baseline.threshold(2.0), Some(60.0));
+
+        let mut idle = Baseline::new(Duration::ZERO, start);
+        idle.update(0.0, start);
+        assert!(idle.update(0.0, start));
+        assert_eq!(idle.threshold(2.0), Some(BASELINE_MIN_THRESHOLD));
+    }
+
+    #[test]
+    fn diff_is_a_subcommand_that_excludes_monitoring_options() {
+        let args = Args::parse_from([
+            "cpu-usage-logger",
+            "diff",
+            "a.json",
+            "b.json",
+            "--by",
+            "pid",
+        ]);
+        match args.command {
+            Some(Command::Diff { before, after, by }) => {
+                assert_eq!((before.as_str(), after.as_str()), ("a.json", "b.json"));
+                assert_eq!(by, diff::DiffKey::Pid);
+            }
+            None => panic!("diff not parsed"),
+        }
+        assert!(Args::parse_from(["cpu-usage-logger"]).command.is_none());
+        assert!(
+            Args::try_parse_from(["cpu-usage-logger", "-l", "x.log", "diff", "a", "b"]).is_err()
+        );
+    }
 }
@@ -0,0 +1,355 @@
+//! Process source reading `/proc` directly, lighter than a full sysinfo refresh at tight intervals (Linux only)
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use sysinfo::{CpuRefreshKind, Pid, PidExt, RefreshKind, Signal, System, SystemExt};
+
+use crate::source::{self, ProcessInfo, ProcessSource};
+
+/// A process as read from `/proc/[pid]`
+struct ProcfsProcess {
+    pid: Pid,
+    parent: Option<Pid>,
+    name: String,
+    exe: PathBuf,
+    cmd: Vec<String>,
+    cpu_usage: f32,
+    memory: u64,
+    start_time: u64,
+    run_time: u64,
+    /// Owner of `/proc/[pid]`
+    uid: Option<u32>,
+    /// User and system time in clock ticks
+    ticks: u64,
+}
+
+impl ProcessInfo for ProcfsProcess {
+    fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn exe(&self) -> &Path {
+        &self.exe
+    }
+
+    fn cmd(&self) -> &[String] {
+        &self.cmd
+    }
+
+    fn cpu_usage(&self) -> f32 {
+        self.cpu_usage
+    }
+
+    fn memory(&self) -> u64 {
+        self.memory
+    }
+
+    fn start_time(&self) -> u64 {
+        self.start_time
+    }
+
+    fn run_time(&self) -> u64 {
+        self.run_time
+    }
+
+    fn kill_with(&self, signal: Signal) -> Option<bool> {
+        let signal = match signal {
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Kill => libc::SIGKILL,
+            Signal::Term => libc::SIGTERM,
+            Signal::Stop => libc::SIGSTOP,
+            Signal::Continue => libc::SIGCONT,
+            Signal::User1 => libc::SIGUSR1,
+            Signal::User2 => libc::SIGUSR2,
+            _ => return None,
+        };
+
+        Some(unsafe { libc::kill(self.pid.as_u32() as libc::pid_t, signal) } == 0)
+    }
+}
+
+/// Fields of `/proc/[pid]/stat` the measurement needs
+struct Stat {
+    name: String,
+    parent: Option<Pid>,
+    ticks: u64,
+    /// Clock ticks after boot
+    start_ticks: u64,
+    /// Resident pages
+    rss: u64,
+}
+
+/// Parse `/proc/[pid]/stat`, the name is in parentheses and may contain spaces and parentheses itself
+fn parse_stat(stat: &str) -> Option<Stat> {
+    let name_start = stat.find('(')? + 1;
+    let name_end = stat.rfind(')')?;
+    // Fields after the name, starting with the state (field 3)
+    let fields = stat[name_end + 1..]
+        .split_whitespace()
+        .collect::<Vec<&str>>();
+    let field = |n: usize| fields.get(n - 3)?.parse::<u64>().ok();
+
+    Some(Stat {
+        name: stat[name_start..name_end].to_string(),
+        parent: field(4)
+            .filter(|&ppid| ppid != 0)
+            .map(|ppid| Pid::from(ppid as usize)),
+        ticks: field(14)? + field(15)?,
+        start_ticks: field(22)?,
+        rss: field(24)?,
+    })
+}
+
+/// Busy and total time of every `cpu` line of `/proc/stat`, the global line first
+fn read_cpu_times() -> Vec<(u64, u64)> {
+    let Ok(stat) = fs::read_to_string("/proc/stat") else {
+        return Vec::new();
+    };
+
+    stat.lines()
+        .filter(|l| l.starts_with("cpu"))
+        .map(|l| {
+            // user nice system idle iowait irq softirq steal (guest time is already part of user)
+            let times = l
+                .split_whitespace()
+                .skip(1)
+                .take(8)
+                .map(|t| t.parse::<u64>().unwrap_or(0))
+                .collect::<Vec<u64>>();
+            let total = times.iter().sum::<u64>();
+            let idle = times.get(3).unwrap_or(&0) + times.get(4).unwrap_or(&0);
+            (total - idle, total)
+        })
+        .collect()
+}
+
+/// Boot time in seconds since the epoch, from the `btime` line of `/proc/stat`
+fn read_boot_time() -> u64 {
+    fs::read_to_string("/proc/stat")
+        .ok()
+        .and_then(|stat| {
+            stat.lines()
+                .find_map(|l| l.strip_prefix("btime "))
+                .and_then(|t| t.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
+
+/// Mean of the `cpu MHz` lines of `/proc/cpuinfo`
+fn read_frequency() -> u64 {
+    let Ok(cpuinfo) = fs::read_to_string("/proc/cpuinfo") else {
+        return 0;
+    };
+    let frequencies = cpuinfo
+        .lines()
+        .filter(|l| l.starts_with("cpu MHz"))
+        .filter_map(|l| l.split(':').nth(1)?.trim().parse::<f64>().ok())
+        .collect::<Vec<f64>>();
+
+    (frequencies.iter().sum::<f64>() / frequencies.len().max(1) as f64) as u64
+}
+
+/// User names by UID from `/etc/passwd`
+fn read_users() -> HashMap<u32, String> {
+    fs::read_to_string("/etc/passwd")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split(':');
+            let name = fields.next()?;
+            let uid = fields.nth(1)?.parse().ok()?;
+            Some((uid, name.to_string()))
+        })
+        .collect()
+}
+
+/// The real system, read from `/proc` without sysinfo
+pub struct ProcfsSource {
+    processes: HashMap<Pid, ProcfsProcess>,
+    last_refresh: Option<Instant>,
+    /// As of the last CPU refresh, see [read_cpu_times]
+    cpu_times: Vec<(u64, u64)>,
+    /// Global usage first, then every logical core
+    cpu_usages: Vec<f32>,
+    /// User names by UID
+    users: HashMap<u32, String>,
+    track_frequency: bool,
+    frequency: u64,
+    clock_ticks: f64,
+    page_size: u64,
+    boot_time: u64,
+    core_count: usize,
+}
+
+impl ProcfsSource {
+    pub fn new() -> Self {
+        let mut source = ProcfsSource {
+            processes: HashMap::new(),
+            last_refresh: None,
+            cpu_times: Vec::new(),
+            cpu_usages: Vec::new(),
+            users: read_users(),
+            track_frequency: false,
+            frequency: 0,
+            clock_ticks: unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64,
+            page_size: unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64,
+            boot_time: read_boot_time(),
+            // Read once, the topology doesn't change while running
+            core_count: source::logical_core_count(&System::new_with_specifics(
+                RefreshKind::new().with_cpu(CpuRefreshKind::new()),
+            )),
+        };
+        // Like sysinfo, be populated right away
+        source.refresh(true);
+
+        source
+    }
+
+    /// Read a single process, reusing what doesn't change from its previous reading
+    fn read_process(&self, pid: Pid, elapsed: Option<f64>, now_secs: u64) -> Option<ProcfsProcess> {
+        let dir = PathBuf::from(format!("/proc/{}", pid));
+        let stat = parse_stat(&fs::read_to_string(dir.join("stat")).ok()?)?;
+        let start_time = self.boot_time + (stat.start_ticks as f64 / self.clock_ticks) as u64;
+
+        let previous = self
+            .processes
+            .get(&pid)
+            .filter(|p| p.start_time == start_time);
+        let cpu_usage = match (previous, elapsed) {
+            (Some(previous), Some(elapsed)) if elapsed > 0.0 => {
+                stat.ticks.saturating_sub(previous.ticks) as f64 / self.clock_ticks / elapsed
+                    * 100.0
+            }
+            _ => 0.0,
+        };
+        let (exe, cmd, uid) = match previous {
+            Some(previous) => (previous.exe.clone(), previous.cmd.clone(), previous.uid),
+            None => (
+                fs::read_link(dir.join("exe")).unwrap_or_default(),
+                fs::read(dir.join("cmdline"))
+                    .unwrap_or_default()
+                    .split(|&b| b == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect(),
+                fs::metadata(&dir).ok().map(|m| m.uid()),
+            ),
+        };
+
+        Some(ProcfsProcess {
+            pid,
+            parent: stat.parent,
+            name: stat.name,
+            exe,
+            cmd,
+            cpu_usage: cpu_usage as f32,
+            memory: stat.rss * self.page_size,
+            start_time,
+            run_time: now_secs.saturating_sub(start_time),
+            uid,
+            ticks: stat.ticks,
+        })
+    }
+}
+
+impl ProcessSource for ProcfsSource {
+    fn refresh(&mut self, cpu: bool) {
+        let now = Instant::now();
+        let now_secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let elapsed = self
+            .last_refresh
+            .map(|t| now.duration_since(t).as_secs_f64());
+
+        if cpu {
+            let cpu_times = read_cpu_times();
+            self.cpu_usages = cpu_times
+                .iter()
+                .zip(&self.cpu_times)
+                .map(|(&(busy, total), &(prev_busy, prev_total))| {
+                    let total = total.saturating_sub(prev_total);
+                    match total {
+                        0 => 0.0,
+                        _ => busy.saturating_sub(prev_busy) as f32 / total as f32 * 100.0,
+                    }
+                })
+                .collect();
+            self.cpu_times = cpu_times;
+        }
+
+        let pids = fs::read_dir("/proc")
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok()?.file_name().to_str()?.parse::<usize>().ok())
+                    .map(Pid::from)
+                    .collect::<Vec<Pid>>()
+            })
+            .unwrap_or_default();
+        // Processes that exited since listing `/proc` are skipped
+        self.processes = pids
+            .into_iter()
+            .filter_map(|pid| Some((pid, self.read_process(pid, elapsed, now_secs)?)))
+            .collect();
+        self.last_refresh = Some(now);
+
+        if self.track_frequency {
+            self.frequency = read_frequency();
+        }
+    }
+
+    fn resolve_users(&mut self) {
+        // Pick up users added since starting
+        self.users = read_users();
+    }
+
+    fn processes(&self) -> Vec<&dyn ProcessInfo> {
+        self.processes
+            .values()
+            .map(|p| p as &dyn ProcessInfo)
+            .collect()
+    }
+
+    fn user_name(&self, pid: Pid) -> Option<&str> {
+        let uid = self.processes.get(&pid)?.uid?;
+        self.users.get(&uid).map(String::as_str)
+    }
+
+    fn global_cpu_usage(&self) -> f32 {
+        self.cpu_usages.first().copied().unwrap_or(0.0)
+    }
+
+    fn cpu_usages(&self) -> Vec<f32> {
+        self.cpu_usages.iter().skip(1).copied().collect()
+    }
+
+    fn core_count(&self) -> usize {
+        self.core_count
+    }
+
+    fn track_frequency(&mut self) {
+        self.track_frequency = true;
+    }
+
+    fn cpu_frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn host_name(&self) -> String {
+        fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,336 @@
+//! Per-process thresholds and actions by name pattern from a TOML rules file, for `rules_file`
+//!
+//! Only the subset of TOML needed for a list of rules is understood:
+//!
+//! ```toml
+//! [[rule]]
+//! pattern = "java*"
+//! cpu = 80
+//!
+//! [[rule]]
+//! pattern = "*miner*"
+//! cpu = 10
+//! action = "kill"
+//! ```
+
+use clap::ValueEnum;
+use std::fs;
+
+use crate::condition::Metric;
+use crate::BreachAction;
+
+const KEYS: [&str; 4] = ["pattern", "cpu", "mem", "action"];
+
+/// Thresholds of the processes whose name matches `pattern`
+#[derive(Clone, Debug)]
+pub struct Rule {
+    /// `*` matches any number of characters, `?` a single one
+    pattern: String,
+    /// CPU usage in percent
+    pub cpu: Option<f32>,
+    /// Resident memory in MB
+    pub mem: Option<f32>,
+    /// Overrides `on_process_breach`
+    pub action: Option<BreachAction>,
+}
+
+impl Rule {
+    /// Whether the metrics given by `value` reach any threshold of the rule
+    pub fn exceeded(&self, value: &dyn Fn(Metric) -> f32) -> bool {
+        self.cpu.is_some_and(|t| value(Metric::Cpu) >= t)
+            || self.mem.is_some_and(|t| value(Metric::Mem) >= t)
+    }
+
+    /// The thresholds like `cpu >= 80.00% or mem >= 500 MB`
+    pub fn describe(&self) -> String {
+        self.cpu
+            .map(|t| format!("cpu >= {:.2}%", t))
+            .into_iter()
+            .chain(self.mem.map(|t| format!("mem >= {} MB", t)))
+            .collect::<Vec<String>>()
+            .join(" or ")
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+}
+
+/// Rules in the order of the file, the first matching one applies
+#[derive(Clone, Debug)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    /// The first rule matching a process name, [None] leaves the process to the global thresholds
+    pub fn find(&self, name: &str) -> Option<&Rule> {
+        self.rules.iter().find(|rule| matches(&rule.pattern, name))
+    }
+
+    /// Whether any rule kills processes
+    pub fn kills(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|rule| rule.action == Some(BreachAction::Kill))
+    }
+}
+
+/// Glob match of the whole name, `*` matches any number of characters and `?` a single one
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+
+    // Backtrack to the last `*` on a mismatch, letting it match one more character
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A `"string"` or a number, followed by nothing but an optional comment
+enum Value {
+    String(String),
+    Number(f32),
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    let (value, rest) = match value.strip_prefix('"') {
+        Some(quoted) => {
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| String::from("missing closing '\"'"))?;
+            (Value::String(quoted[..end].to_string()), &quoted[end + 1..])
+        }
+        None => {
+            let end = value.find('#').unwrap_or(value.len());
+            let number = value[..end].trim();
+            let number = number
+                .parse::<f32>()
+                .ok()
+                .filter(|n| n.is_finite() && *n >= 0.0)
+                .ok_or_else(|| format!("expected a quoted string or a number, got '{}'", number))?;
+            (Value::Number(number), &value[end..])
+        }
+    };
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected '{}' after the value", rest));
+    }
+
+    Ok(value)
+}
+
+/// Check a finished rule and add it
+fn push_rule(rules: &mut Vec<Rule>, rule: Rule, line: usize) -> Result<(), String> {
+    if rule.pattern.is_empty() {
+        return Err(format!("line {}: rule without a `pattern`", line));
+    }
+    if rule.cpu.is_none() && rule.mem.is_none() {
+        return Err(format!(
+            "line {}: rule '{}' needs a `cpu` or `mem` threshold",
+            line, rule.pattern
+        ));
+    }
+    rules.push(rule);
+
+    Ok(())
+}
+
+/// Parse the content of a rules file
+fn parse(content: &str) -> Result<Rules, String> {
+    let mut rules = Vec::new();
+    // The rule being read and the line of its `[[rule]]` header
+    let mut current: Option<(Rule, usize, Vec<&str>)> = None;
+
+    for (i, line) in content.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            let header = line.split('#').next().unwrap().trim();
+            if header != "[[rule]]" {
+                return Err(format!(
+                    "line {}: unknown table '{}', expected '[[rule]]'",
+                    i, header
+                ));
+            }
+            if let Some((rule, start, _)) = current.take() {
+                push_rule(&mut rules, rule, start)?;
+            }
+            current = Some((
+                Rule {
+                    pattern: String::new(),
+                    cpu: None,
+                    mem: None,
+                    action: None,
+                },
+                i,
+                Vec::new(),
+            ));
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {}: expected `key = value` or '[[rule]]'", i))?;
+        let key = key.trim();
+        let Some((rule, _, seen)) = current.as_mut() else {
+            return Err(format!("line {}: '{}' outside of a '[[rule]]'", i, key));
+        };
+        if !KEYS.contains(&key) {
+            return Err(format!(
+                "line {}: unknown key '{}', expected one of: {}",
+                i,
+                key,
+                KEYS.join(", ")
+            ));
+        }
+        if seen.contains(&key) {
+            return Err(format!("line {}: '{}' given twice", i, key));
+        }
+        seen.push(key);
+
+        let value = parse_value(value.trim()).map_err(|e| format!("line {}: {}", i, e))?;
+        match (key, value) {
+            ("pattern", Value::String(pattern)) => rule.pattern = pattern,
+            ("cpu", Value::Number(cpu)) => rule.cpu = Some(cpu),
+            ("mem", Value::Number(mem)) => rule.mem = Some(mem),
+            ("action", Value::String(action)) => {
+                rule.action = Some(BreachAction::from_str(&action, true).map_err(|_| {
+                    format!(
+                        "line {}: unknown action '{}', expected one of: {}",
+                        i,
+                        action,
+                        BreachAction::value_variants()
+                            .iter()
+                            .filter_map(|a| a.to_possible_value())
+                            .map(|v| v.get_name().to_string())
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    )
+                })?)
+            }
+            ("pattern" | "action", Value::Number(_)) => {
+                return Err(format!("line {}: '{}' has to be a quoted string", i, key))
+            }
+            _ => return Err(format!("line {}: '{}' has to be a number", i, key)),
+        }
+    }
+    if let Some((rule, start, _)) = current {
+        push_rule(&mut rules, rule, start)?;
+    }
+    if rules.is_empty() {
+        return Err(String::from("no '[[rule]]' found"));
+    }
+
+    Ok(Rules { rules })
+}
+
+/// Read and check a rules file
+pub fn load(path: &str) -> Result<Rules, String> {
+    let content =
+        fs::read_to_string(path).map_err(|e| format!("failed to read '{}': {}", path, e))?;
+    parse(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stars_match_any_number_of_characters() {
+        assert!(matches("*miner*", "xmrig-miner-2"));
+        assert!(matches("*miner*", "miner"));
+        assert!(!matches("*miner*", "mine"));
+        assert!(matches("java*", "java"));
+        assert!(matches("java*", "javaw"));
+        assert!(!matches("java*", "openjava"));
+    }
+
+    #[test]
+    fn question_marks_match_a_single_character() {
+        assert!(matches("python?", "python3"));
+        assert!(!matches("python?", "python"));
+        assert!(!matches("python?", "python39"));
+    }
+
+    #[test]
+    fn empty_names_only_match_stars() {
+        assert!(matches("", ""));
+        assert!(matches("*", ""));
+        assert!(!matches("?", ""));
+        assert!(!matches("java*", ""));
+    }
+
+    #[test]
+    fn rules_apply_in_the_order_of_the_file() {
+        let rules = parse(
+            "[[rule]]\npattern = \"java*\" # the JVMs\ncpu = 80 # percent\n\n\
+             [[rule]]\npattern = \"*\"\nmem = 500\naction = \"kill\"\n",
+        )
+        .unwrap();
+
+        let java = rules.find("javaw").unwrap();
+        assert_eq!((java.cpu, java.mem, java.action), (Some(80.0), None, None));
+        let other = rules.find("bash").unwrap();
+        assert_eq!(other.pattern(), "*");
+        assert_eq!(other.action, Some(BreachAction::Kill));
+        assert!(rules.kills());
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        let error = parse("[[rule]]\npattern = \"a\"\ncpu = 1\ncpu = 2\n").unwrap_err();
+        assert_eq!(error, "line 4: 'cpu' given twice");
+    }
+
+    #[test]
+    fn unknown_tables_are_rejected() {
+        let error = parse("[rules]\npattern = \"a\"\ncpu = 1\n").unwrap_err();
+        assert_eq!(
+            error,
+            "line 1: unknown table '[rules]', expected '[[rule]]'"
+        );
+    }
+
+    #[test]
+    fn only_comments_may_follow_a_value() {
+        assert_eq!(
+            parse("[[rule]]\npattern = \"a\" b\ncpu = 1\n").unwrap_err(),
+            "line 2: unexpected 'b' after the value"
+        );
+        assert_eq!(
+            parse("[[rule]]\npattern = \"a\"\ncpu = 1 2\n").unwrap_err(),
+            "line 3: expected a quoted string or a number, got '1 2'"
+        );
+    }
+
+    #[test]
+    fn rules_need_a_threshold() {
+        let error =
+            parse("# no limits\n[[rule]]\npattern = \"a\"\naction = \"kill\"\n").unwrap_err();
+        assert_eq!(error, "line 2: rule 'a' needs a `cpu` or `mem` threshold");
+    }
+}
@@ -0,0 +1,145 @@
+//! Windows service integration, the service runs the normal measurement loop under the SCM dispatcher
+
+use clap::{Parser, ValueEnum};
+use std::ffi::OsString;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+use crate::{monitor, Args};
+
+const SERVICE_NAME: &str = "cpu-usage-logger";
+const SERVICE_DISPLAY_NAME: &str = "CPU Usage Logger";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// Actions that can be taken on the Windows service
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServiceAction {
+    /// Register the service with the options of this invocation
+    Install,
+    /// Stop and remove the service
+    Uninstall,
+    /// Run as the service (used by the SCM)
+    Run,
+}
+
+/// Execute a service action
+pub fn handle(action: ServiceAction) -> windows_service::Result<()> {
+    match action {
+        ServiceAction::Install => install(),
+        ServiceAction::Uninstall => uninstall(),
+        ServiceAction::Run => service_dispatcher::start(SERVICE_NAME, ffi_service_main),
+    }
+}
+
+/// Register the service to start automatically, reusing all options except `--service`
+fn install() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+
+    let mut launch_arguments: Vec<OsString> = Vec::new();
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--service" {
+            args.next();
+        } else if !arg.to_string_lossy().starts_with("--service=") {
+            launch_arguments.push(arg);
+        }
+    }
+    launch_arguments.push(OsString::from("--service"));
+    launch_arguments.push(OsString::from("run"));
+
+    let service_info = ServiceInfo {
+        name: OsString::from(SERVICE_NAME),
+        display_name: OsString::from(SERVICE_DISPLAY_NAME),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().expect("failed to get executable path"),
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+
+    let service = manager.create_service(&service_info, ServiceAccess::CHANGE_CONFIG)?;
+    service.set_description(env!("CARGO_PKG_DESCRIPTION"))?;
+
+    println!("Installed service '{}'", SERVICE_NAME);
+    Ok(())
+}
+
+/// Stop the service if it is running and remove it
+fn uninstall() -> windows_service::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+
+    println!("Uninstalled service '{}'", SERVICE_NAME);
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Entry point called by the SCM dispatcher
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(e) = run_service() {
+        eprintln!("service failed: {}", e);
+    }
+}
+
+/// Run the measurement loop until the SCM asks us to stop
+fn run_service() -> windows_service::Result<()> {
+    // The launch arguments are passed to the process, not to `service_main`
+    let mut args = Args::parse();
+    // There is no console to draw to
+    args.cli = false;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let status_handle = {
+        let running = running.clone();
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                running.store(false, Ordering::SeqCst);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?
+    };
+
+    let set_state = |state: ServiceState, controls_accepted: ServiceControlAccept| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    set_state(
+        ServiceState::Running,
+        ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+    )?;
+    monitor(&args, &running);
+    set_state(ServiceState::Stopped, ServiceControlAccept::empty())
+}
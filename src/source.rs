@@ -0,0 +1,396 @@
+//! Sources the measurement loop gets processes and time from, so it can run against fakes instead of the real system
+
+use chrono::{DateTime, Local};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use sysinfo::{
+    CpuExt, CpuRefreshKind, Pid, Process, ProcessExt, ProcessRefreshKind, RefreshKind, Signal,
+    System, SystemExt, UserExt,
+};
+
+/// A single process as seen by the measurement loop
+pub trait ProcessInfo {
+    fn pid(&self) -> Pid;
+    fn parent(&self) -> Option<Pid>;
+    fn name(&self) -> &str;
+    /// Path of the executable, empty if unknown
+    fn exe(&self) -> &Path;
+    fn cmd(&self) -> &[String];
+    /// CPU usage since the last refresh in percent of a single core
+    fn cpu_usage(&self) -> f32;
+    /// Resident memory in bytes
+    fn memory(&self) -> u64;
+    /// Start time in seconds since the epoch
+    fn start_time(&self) -> u64;
+    /// Seconds since the process was started
+    fn run_time(&self) -> u64;
+    /// Send a signal, [None] if the signal isn't supported on this platform
+    fn kill_with(&self, signal: Signal) -> Option<bool>;
+}
+
+/// Provides the processes to measure
+pub trait ProcessSource {
+    /// Refresh all processes, and the global CPU usage if `cpu` is set
+    fn refresh(&mut self, cpu: bool);
+    /// Also resolve the users running processes on refresh
+    fn resolve_users(&mut self);
+    /// Processes as of the last refresh
+    fn processes(&self) -> Vec<&dyn ProcessInfo>;
+    /// Name of the user running a process, if known
+    fn user_name(&self, pid: Pid) -> Option<&str>;
+    /// Global CPU usage as of the last CPU refresh in percent
+    fn global_cpu_usage(&self) -> f32;
+    /// Usage of every logical core as of the last CPU refresh in percent
+    fn cpu_usages(&self) -> Vec<f32>;
+    /// Number of logical cores CPU usage is normalized to
+    fn core_count(&self) -> usize;
+    /// Also refresh the CPU frequency on refresh
+    fn track_frequency(&mut self);
+    /// Mean current frequency of all cores in MHz as of the last refresh
+    fn cpu_frequency(&self) -> u64;
+    fn host_name(&self) -> String;
+}
+
+/// Provides the time, so waiting can be skipped
+pub trait Clock {
+    fn now(&self) -> Instant;
+    /// Wall-clock time for timestamps and calendar boundaries
+    fn local_now(&self) -> DateTime<Local>;
+    fn sleep(&self, duration: Duration);
+}
+
+impl ProcessInfo for Process {
+    fn pid(&self) -> Pid {
+        ProcessExt::pid(self)
+    }
+
+    fn parent(&self) -> Option<Pid> {
+        ProcessExt::parent(self)
+    }
+
+    fn name(&self) -> &str {
+        ProcessExt::name(self)
+    }
+
+    fn exe(&self) -> &Path {
+        ProcessExt::exe(self)
+    }
+
+    fn cmd(&self) -> &[String] {
+        ProcessExt::cmd(self)
+    }
+
+    fn cpu_usage(&self) -> f32 {
+        ProcessExt::cpu_usage(self)
+    }
+
+    fn memory(&self) -> u64 {
+        ProcessExt::memory(self)
+    }
+
+    fn start_time(&self) -> u64 {
+        ProcessExt::start_time(self)
+    }
+
+    fn run_time(&self) -> u64 {
+        ProcessExt::run_time(self)
+    }
+
+    fn kill_with(&self, signal: Signal) -> Option<bool> {
+        ProcessExt::kill_with(self, signal)
+    }
+}
+
+/// The real system, read through sysinfo
+pub struct SysinfoSource {
+    sys: System,
+    refresh_kind: ProcessRefreshKind,
+    track_frequency: bool,
+}
+
+/// Logical cores of a system with its CPUs loaded, process usage is reported in percent of one of them.
+/// Falls back to the physical cores and then to a single core if neither is known
+pub fn logical_core_count(sys: &System) -> usize {
+    match sys.cpus().len() {
+        0 => sys.physical_core_count().unwrap_or(1).max(1),
+        count => count,
+    }
+}
+
+impl SysinfoSource {
+    /// With `minimal` set, nothing but the processes and CPU usage is loaded
+    pub fn new(minimal: bool) -> Self {
+        // Memory is always refreshed alongside processes by sysinfo
+        let refresh_kind = ProcessRefreshKind::new().with_cpu();
+        let sys = match minimal {
+            true => System::new_with_specifics(
+                RefreshKind::new()
+                    .with_cpu(CpuRefreshKind::new())
+                    .with_processes(refresh_kind),
+            ),
+            false => System::new_all(),
+        };
+
+        SysinfoSource {
+            sys,
+            refresh_kind,
+            track_frequency: false,
+        }
+    }
+}
+
+impl ProcessSource for SysinfoSource {
+    fn refresh(&mut self, cpu: bool) {
+        // Processes refresh the global CPU times too, which would skip the per core update within a short interval
+        if cpu {
+            self.sys.refresh_cpu();
+        }
+        self.sys.refresh_processes_specifics(self.refresh_kind);
+        if self.track_frequency {
+            self.sys
+                .refresh_cpu_specifics(CpuRefreshKind::new().with_frequency());
+        }
+    }
+
+    fn resolve_users(&mut self) {
+        self.refresh_kind = self.refresh_kind.with_user();
+        self.sys.refresh_users_list();
+    }
+
+    fn processes(&self) -> Vec<&dyn ProcessInfo> {
+        self.sys
+            .processes()
+            .values()
+            .map(|p| p as &dyn ProcessInfo)
+            .collect()
+    }
+
+    fn user_name(&self, pid: Pid) -> Option<&str> {
+        self.sys
+            .process(pid)?
+            .user_id()
+            .and_then(|uid| self.sys.get_user_by_id(uid))
+            .map(|user| user.name())
+    }
+
+    fn global_cpu_usage(&self) -> f32 {
+        self.sys.global_cpu_info().cpu_usage()
+    }
+
+    fn cpu_usages(&self) -> Vec<f32> {
+        self.sys.cpus().iter().map(|cpu| cpu.cpu_usage()).collect()
+    }
+
+    fn core_count(&self) -> usize {
+        logical_core_count(&self.sys)
+    }
+
+    fn track_frequency(&mut self) {
+        self.track_frequency = true;
+    }
+
+    fn cpu_frequency(&self) -> u64 {
+        let cpus = self.sys.cpus();
+
+        cpus.iter().map(|cpu| cpu.frequency()).sum::<u64>() / cpus.len().max(1) as u64
+    }
+
+    fn host_name(&self) -> String {
+        self.sys.host_name().unwrap_or_default()
+    }
+}
+
+/// The real time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Test doubles replaying scripted processes on a clock that only moves while sleeping
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+    use std::path::PathBuf;
+
+    /// A process with fixed values
+    pub struct FakeProcess {
+        pub pid: Pid,
+        pub name: String,
+        pub exe: PathBuf,
+        pub cmd: Vec<String>,
+        pub cpu_usage: f32,
+        pub memory: u64,
+        pub start_time: u64,
+    }
+
+    impl FakeProcess {
+        pub fn new(pid: usize, name: &str, cpu_usage: f32) -> Self {
+            FakeProcess {
+                pid: Pid::from(pid),
+                name: name.to_string(),
+                exe: PathBuf::new(),
+                cmd: vec![name.to_string()],
+                cpu_usage,
+                memory: 0,
+                start_time: 0,
+            }
+        }
+
+        pub fn started_at(self, start_time: u64) -> Self {
+            FakeProcess { start_time, ..self }
+        }
+
+        pub fn using_memory(self, memory: u64) -> Self {
+            FakeProcess { memory, ..self }
+        }
+    }
+
+    impl ProcessInfo for FakeProcess {
+        fn pid(&self) -> Pid {
+            self.pid
+        }
+
+        fn parent(&self) -> Option<Pid> {
+            None
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn exe(&self) -> &Path {
+            &self.exe
+        }
+
+        fn cmd(&self) -> &[String] {
+            &self.cmd
+        }
+
+        fn cpu_usage(&self) -> f32 {
+            self.cpu_usage
+        }
+
+        fn memory(&self) -> u64 {
+            self.memory
+        }
+
+        fn start_time(&self) -> u64 {
+            self.start_time
+        }
+
+        fn run_time(&self) -> u64 {
+            u64::MAX
+        }
+
+        fn kill_with(&self, _signal: Signal) -> Option<bool> {
+            Some(false)
+        }
+    }
+
+    /// Every refresh moves on to the next scripted snapshot, the last one is kept once they ran out
+    pub struct FakeSource {
+        snapshots: VecDeque<Vec<FakeProcess>>,
+        current: Vec<FakeProcess>,
+        core_count: usize,
+    }
+
+    impl FakeSource {
+        pub fn new(snapshots: Vec<Vec<FakeProcess>>) -> Self {
+            FakeSource {
+                snapshots: snapshots.into(),
+                current: Vec::new(),
+                core_count: 1,
+            }
+        }
+    }
+
+    impl ProcessSource for FakeSource {
+        fn refresh(&mut self, _cpu: bool) {
+            if let Some(snapshot) = self.snapshots.pop_front() {
+                self.current = snapshot;
+            }
+        }
+
+        fn resolve_users(&mut self) {}
+
+        fn processes(&self) -> Vec<&dyn ProcessInfo> {
+            self.current.iter().map(|p| p as &dyn ProcessInfo).collect()
+        }
+
+        fn user_name(&self, _pid: Pid) -> Option<&str> {
+            None
+        }
+
+        fn global_cpu_usage(&self) -> f32 {
+            self.current.iter().map(|p| p.cpu_usage).sum::<f32>() / self.core_count as f32
+        }
+
+        fn cpu_usages(&self) -> Vec<f32> {
+            vec![self.global_cpu_usage(); self.core_count]
+        }
+
+        fn core_count(&self) -> usize {
+            self.core_count
+        }
+
+        fn track_frequency(&mut self) {}
+
+        fn cpu_frequency(&self) -> u64 {
+            0
+        }
+
+        fn host_name(&self) -> String {
+            String::from("fake-host")
+        }
+    }
+
+    /// Starts at the real time, or a given wall-clock time, and only advances by what is slept
+    pub struct FakeClock {
+        now: Cell<Instant>,
+        local_now: Cell<DateTime<Local>>,
+    }
+
+    impl FakeClock {
+        pub fn new() -> Self {
+            FakeClock::starting_at(Local::now())
+        }
+
+        /// Starts at the given wall-clock time
+        pub fn starting_at(local_now: DateTime<Local>) -> Self {
+            FakeClock {
+                now: Cell::new(Instant::now()),
+                local_now: Cell::new(local_now),
+            }
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn local_now(&self) -> DateTime<Local> {
+            self.local_now.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+            self.local_now
+                .set(self.local_now.get() + chrono::Duration::from_std(duration).unwrap());
+        }
+    }
+}